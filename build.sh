@@ -1,10 +1,20 @@
 #!/bin/bash
 
+# One helper for each architecture de can deploy to, see HELPERS in de/src/deploy.rs
+TARGETS="x86_64-unknown-linux-gnu aarch64-unknown-linux-gnu riscv64gc-unknown-linux-gnu"
+
+# binutils' objcopy usually only reads the host's architecture, llvm-objcopy
+# reads them all. rustup component add llvm-tools
+OBJCOPY=$(ls $(rustc +nightly --print sysroot)/lib/rustlib/*/bin/llvm-objcopy)
+
 cd seed
 PREV_RUSTFLAGS=$RUSTFLAGS
-source env_build.sh # set RUSTFLAGS, seed is no_std no_main
-cargo build --release
-objcopy -R .eh_frame -R .got.plt target/release/seed target/release/seed-final
+for target in $TARGETS
+do
+	source env_build.sh $target # set RUSTFLAGS, seed is no_std no_main
+	cargo +nightly build --release -Zbuild-std=core --target $target || exit 1
+	$OBJCOPY -R .eh_frame -R .got.plt target/$target/release/seed target/$target/release/seed-final
+done
 export RUSTFLAGS=$PREV_RUSTFLAGS
 cd ..
 
@@ -18,4 +28,3 @@ else
 	echo "Done: Use target/release/de"
 fi
 cd ..
-
@@ -0,0 +1,185 @@
+//
+// Part of Demeter Deploy
+// https://github.com/grahamking/demeter-deploy/
+//
+// How to authenticate: which methods, in what order, with which key.
+//
+// Secrets come from the environment if set, otherwise we prompt once on the
+// terminal. They are shared between the primary connection and the upload
+// workers, so the user isn't asked again for every connection.
+//
+
+use std::env;
+use std::fmt;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::bail;
+
+use crate::ssh;
+
+pub const DEFAULT_AUTH_ORDER: &str = "agent,key,keyboard-interactive,password";
+
+const ENV_PASSPHRASE: &str = "DE_KEY_PASSPHRASE";
+const ENV_PASSWORD: &str = "DE_PASSWORD";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMethod {
+    // keys held by ssh-agent
+    Agent,
+    // --identity file, or the IdentityFile / default keys in ~/.ssh/
+    Key,
+    // keyboard-interactive, usually a password and/or a one time code
+    KeyboardInteractive,
+    Password,
+}
+
+impl FromStr for AuthMethod {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<AuthMethod> {
+        Ok(match s.trim() {
+            "agent" => AuthMethod::Agent,
+            "key" | "publickey" => AuthMethod::Key,
+            "keyboard-interactive" | "kbdint" => AuthMethod::KeyboardInteractive,
+            "password" => AuthMethod::Password,
+            other => bail!(
+                "Unknown auth method '{other}'. Use agent, key, keyboard-interactive or password"
+            ),
+        })
+    }
+}
+
+impl fmt::Display for AuthMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            AuthMethod::Agent => "agent",
+            AuthMethod::Key => "key",
+            AuthMethod::KeyboardInteractive => "keyboard-interactive",
+            AuthMethod::Password => "password",
+        };
+        write!(f, "{s}")
+    }
+}
+
+// Parse a comma separated list such as "key,password"
+pub fn parse_methods(s: &str) -> anyhow::Result<Vec<AuthMethod>> {
+    let methods = s
+        .split(',')
+        .filter(|m| !m.trim().is_empty())
+        .map(|m| m.parse())
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if methods.is_empty() {
+        bail!("No auth methods given");
+    }
+    Ok(methods)
+}
+
+#[derive(Clone)]
+pub struct Auth {
+    pub methods: Vec<AuthMethod>,
+    // private key file, instead of the ones libssh finds itself
+    pub identity: Option<PathBuf>,
+    secrets: Arc<Mutex<Secrets>>,
+}
+
+#[derive(Default)]
+struct Secrets {
+    passphrase: Option<String>,
+    password: Option<String>,
+    // keyboard-interactive answers that worked, one Vec per round of prompts
+    kbdint_answers: Vec<Vec<String>>,
+}
+
+impl Auth {
+    pub fn new(methods: Vec<AuthMethod>, identity: Option<&str>) -> Auth {
+        let identity = identity.map(|i| match i.strip_prefix("~/") {
+            Some(rest) => PathBuf::from(env::var("HOME").unwrap_or_default()).join(rest),
+            None => PathBuf::from(i),
+        });
+        Auth {
+            methods,
+            identity,
+            secrets: Arc::new(Mutex::new(Secrets {
+                passphrase: env::var(ENV_PASSPHRASE).ok(),
+                password: env::var(ENV_PASSWORD).ok(),
+                kbdint_answers: Vec::new(),
+            })),
+        }
+    }
+
+    // Passphrase for the private key, if we already have one
+    pub fn known_passphrase(&self) -> Option<String> {
+        self.secrets.lock().unwrap().passphrase.clone()
+    }
+
+    pub fn passphrase(&self, key: &str) -> anyhow::Result<String> {
+        let mut secrets = self.secrets.lock().unwrap();
+        if let Some(p) = &secrets.passphrase {
+            return Ok(p.clone());
+        }
+        let p = prompt(&format!("Passphrase for {key}: "), false, ENV_PASSPHRASE)?;
+        secrets.passphrase = Some(p.clone());
+        Ok(p)
+    }
+
+    // The passphrase we have is wrong, forget it so we can ask again
+    pub fn forget_passphrase(&self) {
+        self.secrets.lock().unwrap().passphrase = None;
+    }
+
+    pub fn password(&self, user_host: &str) -> anyhow::Result<String> {
+        let mut secrets = self.secrets.lock().unwrap();
+        if let Some(p) = &secrets.password {
+            return Ok(p.clone());
+        }
+        let p = prompt(&format!("{user_host}'s password: "), false, ENV_PASSWORD)?;
+        secrets.password = Some(p.clone());
+        Ok(p)
+    }
+
+    pub fn forget_password(&self) {
+        self.secrets.lock().unwrap().password = None;
+    }
+
+    // Answer a keyboard-interactive prompt.
+    // round: how many sets of prompts the server sent before this one
+    // idx: which prompt in this set
+    pub fn kbdint_answer(
+        &self,
+        round: usize,
+        idx: usize,
+        text: &str,
+        is_echo: bool,
+    ) -> anyhow::Result<String> {
+        let mut secrets = self.secrets.lock().unwrap();
+        if let Some(a) = secrets.kbdint_answers.get(round).and_then(|r| r.get(idx)) {
+            return Ok(a.clone());
+        }
+        let answer = match &secrets.password {
+            Some(p) if !is_echo && text.to_lowercase().contains("password") => p.clone(),
+            _ => prompt(text, is_echo, ENV_PASSWORD)?,
+        };
+        if secrets.kbdint_answers.len() <= round {
+            secrets.kbdint_answers.resize(round + 1, Vec::new());
+        }
+        secrets.kbdint_answers[round].push(answer.clone());
+        Ok(answer)
+    }
+
+    pub fn forget_kbdint(&self) {
+        self.secrets.lock().unwrap().kbdint_answers.clear();
+    }
+}
+
+// Ask on the terminal. env_name is the variable to suggest if there isn't one.
+fn prompt(text: &str, is_echo: bool, env_name: &str) -> anyhow::Result<String> {
+    if !std::io::stdin().is_terminal() {
+        bail!(
+            "not a terminal, cannot prompt for '{}'. Set ${env_name}",
+            text.trim()
+        );
+    }
+    ssh::getpass(text, is_echo)
+}
@@ -0,0 +1,452 @@
+//
+// Part of Demeter Deploy
+// https://github.com/grahamking/demeter-deploy/
+//
+// Named deploy profiles.
+//
+// Profiles live in ~/.config/de/config.toml and optionally a per-project .de.toml
+// in the current directory. Each [section] is a profile, keys are the long
+// command line flag names:
+//
+//   [prod]
+//   src-dir = "public"
+//   remote = "graham@myhost.com:/var/www/blog/"
+//   workers = 8
+//   hidden = false
+//
+// A value is looked up in this order: command line, DE_* environment variable
+// (DE_HELPER_DST for helper-dst), .de.toml, config.toml, built-in default.
+//
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context};
+use clap::ArgMatches;
+
+use crate::auth::DEFAULT_AUTH_ORDER;
+
+pub const DEFAULT_WORKERS: &str = "4";
+// {uid} is the remote user's, so each user gets their own private directory
+pub const DEFAULT_HELPER_DST: &str = "/tmp/de-{uid}/seed";
+
+const PROJECT_CONFIG: &str = ".de.toml";
+
+// Every setting we know about: (config key, clap arg id, default)
+const KEYS: &[(&str, &str, Option<&str>)] = &[
+    ("src-dir", "src_dir", None),
+    ("remote", "remote", None),
+    ("workers", "workers", Some(DEFAULT_WORKERS)),
+    ("helper-dst", "helper-dst", Some(DEFAULT_HELPER_DST)),
+    ("cleanup-helper", "cleanup-helper", Some("false")),
+    ("no-helper", "no-helper", Some("false")),
+    ("hidden", "hidden", Some("false")),
+    ("dry-run", "dry-run", Some("false")),
+    ("verbose", "verbose", Some("false")),
+    ("include", "include", None),
+    ("exclude", "exclude", None),
+    ("exclude-from", "exclude-from", None),
+    ("identity", "identity", None),
+    ("auth", "auth", Some(DEFAULT_AUTH_ORDER)),
+    ("jump", "jump", None),
+    ("compare", "compare", Some("checksum")),
+    ("hash", "hash", Some("xxh3")),
+    ("links", "links", Some("copy")),
+    ("no-delete", "no-delete", Some("false")),
+    ("delete-excluded", "delete-excluded", Some("false")),
+    ("max-delete", "max-delete", None),
+    ("delete-local", "delete-local", Some("false")),
+    ("atomic", "atomic", Some("false")),
+    ("keep", "keep", Some("5")),
+    ("accept-new-host-key", "accept-new-host-key", Some("false")),
+    ("host-key-fingerprint", "host-key-fingerprint", None),
+];
+
+// Positional args are only on the top level command, not on sub-commands
+const POSITIONAL: &[&str] = &["src_dir", "remote"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+    List(Vec<String>),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Str(s) => write!(f, "{s:?}"),
+            Value::Int(i) => write!(f, "{i}"),
+            Value::Bool(b) => write!(f, "{b}"),
+            Value::List(l) => {
+                let items: Vec<String> = l.iter().map(|s| format!("{s:?}")).collect();
+                write!(f, "[{}]", items.join(", "))
+            }
+        }
+    }
+}
+
+// Where a setting came from, for `de config show`
+#[derive(Debug, Clone)]
+pub enum Source {
+    Cli,
+    Env(String),
+    File(PathBuf),
+    Default,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Cli => write!(f, "command line"),
+            Source::Env(name) => write!(f, "${name}"),
+            Source::File(path) => write!(f, "{}", path.display()),
+            Source::Default => write!(f, "default"),
+        }
+    }
+}
+
+// One named section of a config file, with where each key came from
+pub type Profile = HashMap<String, (Value, PathBuf)>;
+
+#[derive(Default)]
+pub struct Config {
+    profiles: HashMap<String, Profile>,
+}
+
+impl Config {
+    // Load the user config then the project config on top of it.
+    // Missing files are not an error.
+    pub fn load() -> anyhow::Result<Config> {
+        let mut config = Config::default();
+        if let Some(user_path) = user_config_path() {
+            config.merge_file(&user_path)?;
+        }
+        config.merge_file(Path::new(PROJECT_CONFIG))?;
+        Ok(config)
+    }
+
+    pub fn profile(&self, name: &str) -> anyhow::Result<&Profile> {
+        self.profiles.get(name).ok_or_else(|| {
+            let mut names: Vec<&str> = self.profiles.keys().map(|s| s.as_str()).collect();
+            names.sort_unstable();
+            anyhow!(
+                "No profile '{name}' in ~/.config/de/config.toml or {PROJECT_CONFIG}. Known: [{}]",
+                names.join(", ")
+            )
+        })
+    }
+
+    fn merge_file(&mut self, path: &Path) -> anyhow::Result<()> {
+        let contents = match fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err).context(format!("reading {}", path.display())),
+        };
+        let parsed = parse(&contents).with_context(|| format!("parsing {}", path.display()))?;
+        for (name, table) in parsed {
+            let profile = self.profiles.entry(name).or_default();
+            for (key, value) in table {
+                profile.insert(key, (value, path.to_path_buf()));
+            }
+        }
+        Ok(())
+    }
+}
+
+// $XDG_CONFIG_HOME/de/config.toml, usually ~/.config/de/config.toml
+fn user_config_path() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CONFIG_HOME") {
+        Some(xdg) if !xdg.is_empty() => PathBuf::from(xdg),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("de").join("config.toml"))
+}
+
+// The settings for a single run, merged from all the places they can come from.
+pub struct Settings<'a> {
+    top: &'a ArgMatches,
+    sub: Option<&'a ArgMatches>,
+    profile: Option<&'a Profile>,
+}
+
+impl<'a> Settings<'a> {
+    // top: the matches for the whole command line
+    // sub: the sub-command matches, which hold the global flags if given after it
+    // profile: the named profile, if any
+    pub fn new(
+        top: &'a ArgMatches,
+        sub: Option<&'a ArgMatches>,
+        profile: Option<&'a Profile>,
+    ) -> Settings<'a> {
+        Settings { top, sub, profile }
+    }
+
+    pub fn keys() -> impl Iterator<Item = &'static str> {
+        KEYS.iter().map(|(k, _, _)| *k)
+    }
+
+    // Find a setting and where it came from
+    pub fn lookup(&self, key: &str) -> anyhow::Result<Option<(Value, Source)>> {
+        let (_, arg_id, default) = KEYS
+            .iter()
+            .find(|(k, _, _)| *k == key)
+            .ok_or_else(|| anyhow!("Unknown setting '{key}'"))?;
+
+        let matches = match self.sub {
+            Some(sub) if !POSITIONAL.contains(arg_id) => sub,
+            _ => self.top,
+        };
+        if matches.occurrences_of(arg_id) > 0 {
+            let values: Vec<&str> = matches
+                .values_of(arg_id)
+                .map(|v| v.collect())
+                .unwrap_or_default();
+            let value = match values[..] {
+                [] => Value::Bool(true), // a flag
+                [v] => guess(v),
+                _ => Value::List(values.iter().map(|s| s.to_string()).collect()),
+            };
+            return Ok(Some((value, Source::Cli)));
+        }
+
+        let env_name = env_name(key);
+        if let Ok(v) = env::var(&env_name) {
+            return Ok(Some((guess(&v), Source::Env(env_name))));
+        }
+
+        if let Some((value, path)) = self.profile.and_then(|p| p.get(key)) {
+            return Ok(Some((value.clone(), Source::File(path.clone()))));
+        }
+
+        Ok(default.map(|d| (guess(d), Source::Default)))
+    }
+
+    pub fn string(&self, key: &str) -> anyhow::Result<Option<String>> {
+        match self.lookup(key)? {
+            None => Ok(None),
+            Some((Value::Str(s), _)) => Ok(Some(s)),
+            Some((Value::Int(i), _)) => Ok(Some(i.to_string())),
+            Some((Value::Bool(b), _)) => Ok(Some(b.to_string())),
+            Some((v, source)) => bail!("'{key}' from {source} should be a string, got {v}"),
+        }
+    }
+
+    // A string setting that must be present
+    pub fn required(&self, key: &str) -> anyhow::Result<String> {
+        self.string(key)?.ok_or_else(|| {
+            anyhow!(
+                "Missing '{key}'. Pass it on the command line, in ${}, or in a profile",
+                env_name(key)
+            )
+        })
+    }
+
+    // A setting that can be given several times. In the environment separate
+    // them with commas: DE_EXCLUDE="*.map,drafts/"
+    pub fn list(&self, key: &str) -> anyhow::Result<Vec<String>> {
+        match self.lookup(key)? {
+            None => Ok(Vec::new()),
+            Some((Value::List(l), _)) => Ok(l),
+            Some((Value::Str(s), Source::Env(_))) => {
+                Ok(s.split(',').map(|s| s.to_string()).collect())
+            }
+            Some((Value::Str(s), _)) => Ok(vec![s]),
+            // one value that guess took for a number or bool, e.g. --exclude 2024
+            Some((Value::Int(i), _)) => Ok(vec![i.to_string()]),
+            Some((Value::Bool(b), _)) => Ok(vec![b.to_string()]),
+        }
+    }
+
+    pub fn usize(&self, key: &str) -> anyhow::Result<usize> {
+        let s = self.required(key)?;
+        s.parse()
+            .with_context(|| format!("'{key}' should be a number, got '{s}'"))
+    }
+
+    pub fn flag(&self, key: &str) -> anyhow::Result<bool> {
+        match self.lookup(key)? {
+            None => Ok(false),
+            Some((Value::Bool(b), _)) => Ok(b),
+            Some((Value::Int(i), _)) => Ok(i != 0),
+            Some((Value::Str(s), source)) => match s.to_lowercase().as_str() {
+                "1" | "true" | "yes" | "on" => Ok(true),
+                "" | "0" | "false" | "no" | "off" => Ok(false),
+                _ => bail!("'{key}' from {source} should be true or false, got '{s}'"),
+            },
+            Some((v, source)) => bail!("'{key}' from {source} should be true or false, got {v}"),
+        }
+    }
+
+    // Print the merged settings as a config file section, for `de config show`
+    pub fn show(&self, name: &str) -> anyhow::Result<String> {
+        let mut out = format!("[{name}]\n");
+        for key in Settings::keys() {
+            match self.lookup(key)? {
+                Some((value, source)) => {
+                    out += &format!("{key} = {value} # {source}\n");
+                }
+                None => {
+                    out += &format!("# {key} is not set\n");
+                }
+            }
+        }
+        Ok(out)
+    }
+}
+
+// Type a value from the command line or environment, so it displays like a config value
+fn guess(s: &str) -> Value {
+    match s {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        _ => s
+            .parse()
+            .map(Value::Int)
+            .unwrap_or_else(|_| Value::Str(s.to_string())),
+    }
+}
+
+// helper-dst -> DE_HELPER_DST
+fn env_name(key: &str) -> String {
+    format!("DE_{}", key.to_uppercase().replace('-', "_"))
+}
+
+//
+// A small TOML subset parser, enough for profiles.
+// Supports [section] headers, key = value, # comments, "basic" and 'literal'
+// strings, integers, booleans and arrays of strings (which may span lines).
+//
+
+pub(crate) fn parse(contents: &str) -> anyhow::Result<HashMap<String, HashMap<String, Value>>> {
+    let mut out: HashMap<String, HashMap<String, Value>> = HashMap::new();
+    let mut section: Option<String> = None;
+    let mut lines = contents.lines().enumerate();
+
+    while let Some((idx, line)) = lines.next() {
+        let line_num = idx + 1;
+        let line = strip_comment(line);
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(header) = line.strip_prefix('[') {
+            let name = header
+                .strip_suffix(']')
+                .ok_or_else(|| anyhow!("line {line_num}: missing ']' in section header"))?
+                .trim();
+            let name = name.trim_matches('"');
+            if name.is_empty() {
+                bail!("line {line_num}: empty section name");
+            }
+            out.entry(name.to_string()).or_default();
+            section = Some(name.to_string());
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow!("line {line_num}: expected 'key = value'"))?;
+        let key = key.trim().trim_matches('"').replace('_', "-");
+        let mut value = value.trim().to_string();
+
+        // arrays can continue over several lines
+        if value.starts_with('[') {
+            while !value.ends_with(']') {
+                let (_, next) = lines
+                    .next()
+                    .ok_or_else(|| anyhow!("line {line_num}: unterminated array"))?;
+                value.push(' ');
+                value.push_str(strip_comment(next).trim());
+            }
+        }
+
+        let Some(section) = &section else {
+            bail!("line {line_num}: '{key}' must be inside a [profile] section");
+        };
+        // a typo would otherwise quietly fall back to the default
+        if !KEYS.iter().any(|(k, _, _)| *k == key) {
+            bail!("line {line_num}: unknown key '{key}' in profile [{section}]");
+        }
+        let value = parse_value(&value).with_context(|| format!("line {line_num}: '{key}'"))?;
+        out.get_mut(section).unwrap().insert(key, value);
+    }
+    Ok(out)
+}
+
+fn parse_value(s: &str) -> anyhow::Result<Value> {
+    if let Some(inner) = s.strip_prefix('[') {
+        let inner = inner.strip_suffix(']').unwrap_or(inner);
+        let mut items = Vec::new();
+        let mut rest = inner.trim();
+        while !rest.is_empty() {
+            let (item, after) = parse_string(rest)?;
+            items.push(item);
+            rest = after.trim_start();
+            rest = rest.strip_prefix(',').unwrap_or(rest).trim_start();
+        }
+        return Ok(Value::List(items));
+    }
+    if s.starts_with('"') || s.starts_with('\'') {
+        let (v, rest) = parse_string(s)?;
+        if !rest.trim().is_empty() {
+            bail!("unexpected '{rest}' after string");
+        }
+        return Ok(Value::Str(v));
+    }
+    match s {
+        "true" => return Ok(Value::Bool(true)),
+        "false" => return Ok(Value::Bool(false)),
+        _ => {}
+    }
+    s.replace('_', "")
+        .parse()
+        .map(Value::Int)
+        .map_err(|_| anyhow!("can't parse value '{s}'. Strings need quotes."))
+}
+
+// Parse a quoted string from the start of s. Returns it and what follows.
+fn parse_string(s: &str) -> anyhow::Result<(String, &str)> {
+    let mut chars = s.char_indices();
+    let quote = match chars.next() {
+        Some((_, q @ ('"' | '\''))) => q,
+        _ => bail!("expected a quoted string at '{s}'"),
+    };
+    let mut out = String::new();
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            c if c == quote => return Ok((out, &s[idx + 1..])),
+            '\\' if quote == '"' => match chars.next() {
+                Some((_, 'n')) => out.push('\n'),
+                Some((_, 't')) => out.push('\t'),
+                Some((_, '\\')) => out.push('\\'),
+                Some((_, '"')) => out.push('"'),
+                Some((_, other)) => bail!("unknown escape '\\{other}'"),
+                None => break,
+            },
+            c => out.push(c),
+        }
+    }
+    bail!("unterminated string {s}")
+}
+
+// Remove a trailing # comment, ignoring # inside strings
+fn strip_comment(line: &str) -> &str {
+    let mut in_quote = None;
+    let mut prev = ' ';
+    for (idx, c) in line.char_indices() {
+        match (in_quote, c) {
+            (None, '#') => return &line[..idx],
+            (None, '"' | '\'') => in_quote = Some(c),
+            (Some(q), c) if c == q && !(q == '"' && prev == '\\') => in_quote = None,
+            _ => {}
+        }
+        prev = c;
+    }
+    line
+}
@@ -0,0 +1,1305 @@
+//
+// Part of Demeter Deploy
+// https://github.com/grahamking/demeter-deploy/
+//
+// Sync one remote host: connect, list it with the helper, compare with the
+// local files, then upload and delete (push) or download (pull).
+//
+// With several hosts each one runs this on its own thread, sharing the
+// local checksums.
+//
+
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::os::unix::fs::PermissionsExt;
+use std::path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{anyhow, bail};
+use crossbeam_channel::{unbounded, Receiver, Sender};
+
+use crate::auth::{Auth, AuthMethod};
+use crate::filter::Filter;
+use crate::hash::{self, Digest, MAX_DIGEST_LEN};
+use crate::host_key::HostKeyPolicy;
+use crate::jump::{self, JumpHost};
+use crate::output::humanize;
+use crate::progress_message::Progress;
+use crate::protocol::{self, Kind, ProtocolError};
+use crate::release::{self, Releases};
+use crate::remote::{self, shell_quote, Remote};
+use crate::ssh::{self, ConnectOptions};
+use crate::ssh_manager::SSHManager;
+use crate::sums::{self, Sums};
+use crate::target::Target;
+use crate::LocalFiles;
+
+// One helper for each architecture, named the way `uname -m` says it.
+// Use ./build.sh to make sure these exist and are up to date
+static HELPERS: [(&str, &[u8]); 3] = [
+    (
+        "x86_64",
+        include_bytes!("../../seed/target/x86_64-unknown-linux-gnu/release/seed-final"),
+    ),
+    (
+        "aarch64",
+        include_bytes!("../../seed/target/aarch64-unknown-linux-gnu/release/seed-final"),
+    ),
+    (
+        "riscv64",
+        include_bytes!("../../seed/target/riscv64gc-unknown-linux-gnu/release/seed-final"),
+    ),
+];
+
+// The local files, and the filter with the .deignore rules found while
+// walking them. Shared by all the hosts.
+pub type LocalTree = Arc<(LocalFiles, Filter)>;
+
+// Settings that are the same for every host
+pub struct DeployOptions {
+    pub local_dir: String,
+    // may contain {uid}, see helper_path
+    pub helper_dst: String,
+    // remove the helper after listing
+    pub is_cleanup_helper: bool,
+    // list over SFTP, see list_sftp
+    pub is_no_helper: bool,
+    pub num_workers: usize,
+    pub auth_methods: Vec<AuthMethod>,
+    pub identity: Option<String>,
+    pub host_key: HostKeyPolicy,
+    // -J, otherwise each host's ProxyJump from the ssh config
+    pub jump: Option<String>,
+    pub compare: Compare,
+    pub hash: Hash,
+    pub links: Links,
+    pub is_dry_run: bool,
+    // push into a new release dir and switch the `current` symlink, see release.rs
+    pub is_atomic: bool,
+    // with is_atomic, how many releases to keep
+    pub keep: usize,
+    // push only: never delete remote files
+    pub is_no_delete: bool,
+    // push only: also delete remote files that match an exclude rule
+    pub is_delete_excluded: bool,
+    // stop before changing anything if the plan deletes more than this
+    pub max_delete: Option<MaxDelete>,
+    // pull only: delete local files that aren't on the remote
+    pub is_delete_local: bool,
+    pub verbose: bool,
+    // more than one host, so the per-host messages say which host
+    pub is_multi: bool,
+}
+
+// What happened on one host
+#[derive(Debug, Default)]
+pub struct Report {
+    pub num_files: usize,
+    // size of the files that are new to the destination
+    pub bytes_added: u64,
+    // new size of the files that replace ones in the destination
+    pub bytes_changed: u64,
+    pub bytes_removed: u64,
+    // same content, only the permissions changed
+    pub num_chmod: usize,
+    pub num_deleted: usize,
+    pub took: Duration,
+}
+
+impl Report {
+    // One line saying what will change, and how many bytes.
+    // verb: "Upload" or "Download"
+    pub fn plan(&self, verb: &str) -> String {
+        format!(
+            "{verb} {} files ({} new, {} changed), chmod {}, delete {} files ({})",
+            self.num_files,
+            humanize(self.bytes_added),
+            humanize(self.bytes_changed),
+            self.num_chmod,
+            self.num_deleted,
+            humanize(self.bytes_removed),
+        )
+    }
+}
+
+// How to tell whether a file changed, --compare
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compare {
+    // --hash of the contents. Reads every file, on both sides.
+    Checksum,
+    // Modification time (seconds) and size. Only stats the files.
+    MtimeSize,
+}
+
+impl Compare {
+    pub fn parse(s: &str) -> anyhow::Result<Compare> {
+        match s {
+            "checksum" => Ok(Compare::Checksum),
+            "mtime-size" => Ok(Compare::MtimeSize),
+            _ => bail!("--compare should be 'checksum' or 'mtime-size', got '{s}'"),
+        }
+    }
+}
+
+// The content hash, --hash. de and the helper both use seed/src/hash.rs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hash {
+    // 4 bytes, in hardware. Fastest, but only spots accidental changes.
+    Crc32c,
+    // 8 bytes. Nearly as fast, far fewer collisions. The default.
+    Xxh3,
+    // 32 bytes, cryptographic
+    Blake3,
+}
+
+impl Hash {
+    pub fn parse(s: &str) -> anyhow::Result<Hash> {
+        match s {
+            "crc32c" => Ok(Hash::Crc32c),
+            "xxh3" => Ok(Hash::Xxh3),
+            "blake3" => Ok(Hash::Blake3),
+            _ => bail!("--hash should be 'crc32c', 'xxh3' or 'blake3', got '{s}'"),
+        }
+    }
+
+    // How the helper's command line and listing header name it
+    pub fn code(self) -> u8 {
+        match self {
+            Hash::Crc32c => hash::CRC32C,
+            Hash::Xxh3 => hash::XXH3,
+            Hash::Blake3 => hash::BLAKE3,
+        }
+    }
+
+    pub fn digest(self, bytes: &[u8]) -> Digest {
+        unsafe { hash::digest(self.code(), bytes.as_ptr(), bytes.len()) }
+    }
+
+    // The same as digest of everything r has, a buffer at a time
+    pub fn digest_read(self, r: &mut impl Read) -> io::Result<Digest> {
+        let mut hasher = hash::Hasher::new(self.code());
+        let mut buf = vec![0u8; 64 * hash::PIECE_LEN];
+        let mut len = read_full(r, &mut buf)?;
+        // A full buffer might be the end, so keep its last piece back for finish
+        while len == buf.len() {
+            let keep = len - hash::PIECE_LEN;
+            unsafe { hasher.update(buf.as_ptr(), keep) };
+            buf.copy_within(keep.., 0);
+            len = hash::PIECE_LEN + read_full(r, &mut buf[hash::PIECE_LEN..])?;
+        }
+        Ok(unsafe { hasher.finish(buf.as_ptr(), len) })
+    }
+}
+
+// Fill buf unless r ends first, returning how much was read
+fn read_full(r: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match r.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(len)
+}
+
+// What to do with symlinks, --links
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Links {
+    // Deploy what the link points at, as a regular file. The default.
+    Copy,
+    // Make the same link on the other side
+    Preserve,
+    // Leave links alone, on both sides
+    Skip,
+}
+
+impl Links {
+    pub fn parse(s: &str) -> anyhow::Result<Links> {
+        match s {
+            "copy" => Ok(Links::Copy),
+            "preserve" => Ok(Links::Preserve),
+            "skip" => Ok(Links::Skip),
+            _ => bail!("--links should be 'copy', 'preserve' or 'skip', got '{s}'"),
+        }
+    }
+}
+
+// A file in the helper's listing: (name, fingerprint, permission bits, size).
+// A symlink's size is the length of where it points.
+pub type RemoteEntry<'a> = (&'a str, Fingerprint, u32, u64);
+
+// What list_remote found
+pub enum RemoteListing {
+    // The helper's output, for parse_listing
+    Helper(Vec<u8>),
+    // From list_sftp, with files by (mtime, size) whatever --compare is.
+    // With --compare checksum, sums from sha256sum or cksum if a shell can
+    // run them.
+    Sftp {
+        dir: String,
+        files: Vec<(String, Fingerprint, u32, u64)>,
+        sums: Option<Sums>,
+    },
+}
+
+impl RemoteListing {
+    // The files, fingerprinted the way --compare says. Without the helper a
+    // checksum is only worked out for the files the same size as the local
+    // one, the others have changed anyway. With sums the local file is hashed
+    // the same way as the remote tool did, otherwise the remote file is
+    // downloaded and hashed with --hash.
+    pub fn entries<'a>(
+        &'a self,
+        ssh: &dyn Remote,
+        local_dir: &str,
+        local: &LocalFiles,
+        compare: Compare,
+        hash: Hash,
+    ) -> anyhow::Result<Vec<RemoteEntry<'a>>> {
+        let (dir, files, sums) = match self {
+            RemoteListing::Helper(output) => return Ok(parse_listing(output, compare, hash)?),
+            RemoteListing::Sftp { dir, files, sums } => (dir, files, sums),
+        };
+        // Matches nothing
+        let changed = Fingerprint::Hash(Digest {
+            len: 0,
+            bytes: [0; MAX_DIGEST_LEN],
+        });
+        let mut out = Vec::with_capacity(files.len());
+        for (name, fingerprint, mode, size) in files {
+            let fingerprint = match (fingerprint, compare, local.get(name)) {
+                (
+                    Fingerprint::MtimeSize(..),
+                    Compare::Checksum,
+                    Some((l_fingerprint, l_size, _)),
+                ) if l_size == size => match sums {
+                    Some(sums) => {
+                        let contents = fs::read(format!("{local_dir}{name}"))?;
+                        if sums.digests.get(name) == Some(&sums.tool.digest(&contents)) {
+                            *l_fingerprint
+                        } else {
+                            changed
+                        }
+                    }
+                    None => {
+                        let contents = ssh.read_file(&format!("{dir}{name}"))?;
+                        Fingerprint::Hash(hash.digest(&contents))
+                    }
+                },
+                (Fingerprint::MtimeSize(..), Compare::Checksum, _) => changed,
+                _ => *fingerprint,
+            };
+            out.push((name.as_str(), fingerprint, *mode, *size));
+        }
+        Ok(out)
+    }
+}
+
+// What we compare a local and a remote file by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fingerprint {
+    // --hash of the contents
+    Hash(Digest),
+    // (mtime, size)
+    MtimeSize(u64, u64),
+    // A symlink, --hash of where it points
+    Link(Digest),
+}
+
+// --max-delete, a safety net against a wrong source directory
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MaxDelete {
+    Count(usize),
+    // of the files in the destination
+    Percent(f64),
+}
+
+impl MaxDelete {
+    // "N" or "P%"
+    pub fn parse(s: &str) -> anyhow::Result<MaxDelete> {
+        let s = s.trim();
+        if let Some(p) = s.strip_suffix('%') {
+            let p: f64 = p.trim().parse().map_err(|_| {
+                anyhow!("--max-delete should be a number or a percentage, got '{s}'")
+            })?;
+            if !(0.0..=100.0).contains(&p) {
+                bail!("--max-delete percentage should be between 0 and 100, got '{s}'");
+            }
+            return Ok(MaxDelete::Percent(p));
+        }
+        s.parse()
+            .map(MaxDelete::Count)
+            .map_err(|_| anyhow!("--max-delete should be a number or a percentage, got '{s}'"))
+    }
+
+    // Is deleting num_delete of the num_total files in the destination ok?
+    pub fn check(&self, num_delete: usize, num_total: usize) -> anyhow::Result<()> {
+        let is_too_many = match *self {
+            MaxDelete::Count(max) => num_delete > max,
+            MaxDelete::Percent(p) => num_delete as f64 > num_total as f64 * p / 100.0,
+        };
+        if is_too_many {
+            bail!(
+                "Would delete {num_delete} of {num_total} files, more than --max-delete {self}. \
+                 Nothing was changed. Check the source directory, or raise --max-delete."
+            );
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for MaxDelete {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MaxDelete::Count(n) => write!(f, "{n}"),
+            MaxDelete::Percent(p) => write!(f, "{p}%"),
+        }
+    }
+}
+
+// Hosts connect one at a time, so that password and host key prompts don't
+// get mixed up. Also keeps the jump hosts, so hosts behind the same bastion
+// share its session.
+#[derive(Default)]
+pub struct Connector {
+    jumps: Mutex<HashMap<String, JumpHost>>,
+}
+
+impl Connector {
+    fn connect(
+        &self,
+        target: &Target,
+        opts: &DeployOptions,
+        progress: Sender<Progress>,
+    ) -> anyhow::Result<SSHManager> {
+        let mut jumps = self.jumps.lock().unwrap();
+
+        let mut connect_opts = ConnectOptions {
+            target: target.clone(),
+            auth: Auth::new(opts.auth_methods.clone(), opts.identity.as_deref()),
+            host_key: opts.host_key.clone(),
+            log_level: ssh::LogLevel::NOLOG,
+            jump: None,
+        };
+        let jump_spec = match &opts.jump {
+            Some(spec) => Some(spec.clone()),
+            None => jump::config_proxy_jump(&target.host)?,
+        };
+        if let Some(spec) = jump_spec {
+            if opts.verbose {
+                println!("Connecting to {} through jump host {spec}", target.host);
+            }
+            let jump = match jumps.get(&spec) {
+                Some(j) => j.clone(),
+                None => {
+                    let j = JumpHost::new(
+                        &spec,
+                        &connect_opts.auth,
+                        &connect_opts.host_key,
+                        connect_opts.log_level,
+                    )?;
+                    jumps.insert(spec, j.clone());
+                    j
+                }
+            };
+            connect_opts.jump = Some(jump);
+        }
+        SSHManager::new(&connect_opts, opts.num_workers, progress)
+    }
+}
+
+// Upload new and changed files to the target, and delete the ones that
+// aren't local any more.
+pub fn push(
+    target: &Target,
+    opts: &DeployOptions,
+    connector: &Connector,
+    local_tree: Receiver<LocalTree>,
+    progress: Sender<Progress>,
+) -> anyhow::Result<Report> {
+    let mut remote_dir = dir_path(&target.path);
+    let label = label(target, opts);
+    let mut ssh = connector
+        .connect(target, opts, progress.clone())
+        .map_err(|err| anyhow!("Could not ssh to '{}': {err}", target.host_display()))?;
+
+    // In atomic mode compare with the current release, if there is one
+    let releases = if opts.is_atomic {
+        Some(Releases::load(&ssh, &remote_dir)?)
+    } else {
+        None
+    };
+    let list_dir = match &releases {
+        Some(r) => r.current.as_ref().map(|c| r.dir(c)),
+        None => Some(remote_dir.clone()),
+    };
+    let output = list_dir
+        .as_ref()
+        .map(|dir| list_remote(&ssh, target, dir, opts))
+        .transpose()?;
+    let local_tree = local_tree.recv()?;
+    let (local, filter) = &*local_tree;
+    let listing = match &output {
+        Some(output) => output.entries(&ssh, &opts.local_dir, local, opts.compare, opts.hash)?,
+        None => Vec::new(),
+    };
+    let remote = remote_files(&listing, filter, opts.links);
+
+    // compare
+    if opts.verbose {
+        println!("{label}Comparing local and remote files");
+    }
+    let mut report = Report::default();
+    let mut upload = Vec::new();
+    let mut chmod = Vec::new();
+    for (filename, (l_fingerprint, l_size, l_mode)) in local.iter() {
+        match remote.get(filename.as_str()) {
+            None => {
+                upload.push(filename);
+                report.bytes_added += l_size;
+            }
+            // Size first, so that a CRC collision between files of different
+            // sizes isn't missed
+            Some((r_fingerprint, _, r_size))
+                if r_size != l_size || r_fingerprint != l_fingerprint =>
+            {
+                upload.push(filename);
+                report.bytes_changed += l_size;
+            }
+            // The new release's file is a hard link to the old release's, so
+            // a chmod would change both. Upload a new copy instead.
+            Some((_, r_mode, _)) if r_mode != l_mode && releases.is_some() => {
+                upload.push(filename);
+                report.bytes_changed += l_size;
+            }
+            Some((_, r_mode, _)) if r_mode != l_mode => {
+                chmod.push((filename, *l_mode));
+            }
+            _ => {} // they are the same
+        }
+    }
+
+    let mut delete = Vec::new();
+    let mut remote_dirs = HashSet::with_capacity(64);
+    for &filename in remote.keys() {
+        let p = path::PathBuf::from(filename);
+        if let Some(dir) = p.parent() {
+            remote_dirs.insert(dir.to_path_buf());
+        }
+
+        if !local.contains_key(filename) {
+            delete.push(filename);
+        }
+    }
+    let excluded = excluded_remote_files(&listing, filter);
+    let num_remote = remote.len() + excluded.len();
+    if opts.is_delete_excluded {
+        delete.extend(excluded);
+    }
+    if opts.is_no_delete {
+        if opts.verbose && !delete.is_empty() {
+            println!(
+                "{label}Keeping {} remote files that are not local",
+                delete.len()
+            );
+        }
+        delete.clear();
+    }
+    if let Some(max_delete) = &opts.max_delete {
+        max_delete.check(delete.len(), num_remote)?;
+    }
+
+    report.num_files = upload.len();
+    report.num_chmod = chmod.len();
+    report.num_deleted = delete.len();
+    let deleted: HashSet<&str> = delete.iter().copied().collect();
+    report.bytes_removed = listing
+        .iter()
+        .filter(|(name, ..)| deleted.contains(name))
+        .map(|&(.., size)| size)
+        .sum();
+    if opts.verbose {
+        println!("{label}Upload: {:?}", upload);
+        println!("{label}Chmod: {:?}", chmod);
+        println!("{label}Delete: {:?}", delete);
+    }
+    if (opts.verbose || opts.is_dry_run) && !opts.is_multi {
+        println!("{}", report.plan("Upload"));
+    }
+
+    let _ = progress.send(Progress::Planned(
+        upload.len(),
+        Some(report.bytes_added + report.bytes_changed),
+    ));
+    if opts.is_dry_run {
+        ssh = ssh.switch_to_dry_run(&label);
+    }
+
+    // Temp files from uploads that were interrupted last time
+    if let Some(dir) = &list_dir {
+        for filename in leftover_tmp_files(&listing) {
+            if opts.verbose {
+                println!("{label}Delete interrupted upload {filename}");
+            }
+            ssh.delete(&format!("{dir}{filename}"))?;
+        }
+    }
+
+    if upload.is_empty() && chmod.is_empty() && delete.is_empty() {
+        if !opts.is_multi {
+            println!("Directories are already identical");
+        }
+        ssh.stop();
+        return Ok(report);
+    }
+
+    // action
+
+    let t_start = Instant::now();
+    let release_name = release::release_name(SystemTime::now());
+    if let Some(releases) = &releases {
+        remote_dir = releases.create(&ssh, &release_name)?;
+        if opts.verbose {
+            println!("{label}New release {remote_dir}");
+        }
+    }
+    for filename in upload {
+        // Do we need to make the parent dir(s)?
+        let p = path::PathBuf::from(filename);
+        if let Some(dir) = p.parent() {
+            if !dir.as_os_str().is_empty() && !remote_dirs.contains(dir) {
+                if opts.verbose {
+                    println!("{label}mkdir remote: {}", dir.display());
+                }
+                for component in dir
+                    .ancestors()
+                    .collect::<Vec<_>>()
+                    .into_iter()
+                    .rev()
+                    .skip(1)
+                {
+                    let local_mode =
+                        fs::metadata(format!("{}{}", opts.local_dir, component.display()))
+                            .map_or(0o755, |m| m.permissions().mode() & 0o7777);
+                    ssh.mkdir(&format!("{remote_dir}{}", component.display()), local_mode)?;
+                    remote_dirs.insert(component.to_path_buf());
+                }
+            }
+        }
+        let src = format!("{}{filename}", opts.local_dir);
+        let dst = format!("{remote_dir}{filename}");
+        match local.get(filename) {
+            // Made next to it and renamed over it, like an upload
+            Some((Fingerprint::Link(_), _, _)) => {
+                let link_target = fs::read_link(&src)?;
+                let tmp = remote::tmp_path(&dst);
+                ssh.symlink(&link_target.to_string_lossy(), &tmp)?;
+                ssh.rename(&tmp, &dst)?;
+            }
+            _ => ssh.upload(&src, &dst)?,
+        }
+    }
+
+    // Same content, different permissions
+    for (filename, mode) in chmod {
+        ssh.chmod(&format!("{remote_dir}{filename}"), mode)?;
+    }
+
+    if opts.verbose {
+        println!("{label}Delete remote files that are absent locally");
+    }
+    for filename in delete.iter() {
+        ssh.delete(&format!("{remote_dir}{}", filename))?;
+    }
+
+    // Directories the deletes left empty. rmdir fails if there's something
+    // in it the helper doesn't list, such as an empty sub-directory. That's
+    // fine, we leave it.
+    let kept = local.keys().map(|f| f.as_str()).chain(
+        listing
+            .iter()
+            .map(|(name, ..)| *name)
+            .filter(|name| !deleted.contains(name) && !remote::is_tmp_path(name)),
+    );
+    for dir in empty_dirs(&delete, kept) {
+        if let Err(err) = ssh.rmdir(&format!("{remote_dir}{dir}")) {
+            if opts.verbose {
+                println!("{label}Not removing {dir}: {err}");
+            }
+        }
+    }
+
+    ssh.wait();
+    if let Some(releases) = &releases {
+        releases.switch(&ssh, &release_name)?;
+        let pruned = releases.prune(&ssh, &release_name, opts.keep)?;
+        if opts.verbose {
+            println!("{label}Switched to {release_name}, deleted old releases {pruned:?}");
+        }
+    }
+    ssh.stop();
+    report.took = t_start.elapsed();
+    thread::sleep(Duration::from_millis(10)); // make sure Finished is last msg
+    let _ = progress.send(Progress::Finished(report.took));
+
+    Ok(report)
+}
+
+// Download the remote files that are missing or different locally.
+// The opposite of push.
+pub fn pull(
+    target: &Target,
+    opts: &DeployOptions,
+    connector: &Connector,
+    local_tree: Receiver<LocalTree>,
+    progress: Sender<Progress>,
+) -> anyhow::Result<Report> {
+    let remote_dir = dir_path(&target.path);
+    let local_dir = &opts.local_dir;
+    let mut ssh = connector
+        .connect(target, opts, progress.clone())
+        .map_err(|err| anyhow!("Could not ssh to '{}': {err}", target.host_display()))?;
+    let output = list_remote(&ssh, target, &remote_dir, opts)?;
+
+    let local_tree = local_tree.recv()?;
+    let (local, filter) = &*local_tree;
+    let listing = output.entries(&ssh, local_dir, local, opts.compare, opts.hash)?;
+    let mut remote = remote_files(&listing, filter, opts.links);
+    if opts.links == Links::Copy {
+        follow_links(
+            &ssh,
+            &remote_dir,
+            &mut remote,
+            local,
+            opts.compare,
+            opts.hash,
+        )?;
+    }
+
+    if opts.verbose {
+        println!("Comparing remote and local files");
+    }
+    let (download, mut report) = pull_plan(&remote, local);
+    let mut delete: Vec<&str> = local
+        .keys()
+        .filter(|filename| !remote.contains_key(filename.as_str()))
+        .map(|filename| filename.as_str())
+        .collect();
+    delete.sort();
+    if let (true, Some(max_delete)) = (opts.is_delete_local, &opts.max_delete) {
+        max_delete.check(delete.len(), local.len())?;
+    }
+
+    report.num_files = download.len();
+    if opts.is_delete_local {
+        report.num_deleted = delete.len();
+        report.bytes_removed = delete.iter().map(|f| local[*f].1).sum();
+    }
+    if opts.verbose {
+        println!("Download: {:?}", download);
+        println!("Delete local: {:?}", delete);
+    }
+    if opts.verbose || opts.is_dry_run {
+        println!("{}", report.plan("Download"));
+    }
+
+    let _ = progress.send(Progress::Planned(
+        download.len(),
+        Some(report.bytes_added + report.bytes_changed),
+    ));
+    if opts.is_dry_run {
+        ssh = ssh.switch_to_dry_run("");
+    }
+
+    if download.is_empty() && (delete.is_empty() || !opts.is_delete_local) {
+        println!("Directories are already identical");
+        if !delete.is_empty() {
+            println!(
+                "{} local files are not on the remote. Use --delete-local to delete them",
+                delete.len()
+            );
+        }
+        ssh.stop();
+        return Ok(report);
+    }
+
+    let t_start = Instant::now();
+    let mut local_dirs = HashSet::with_capacity(64);
+    for filename in download {
+        if let Some(dir) = path::Path::new(filename).parent() {
+            if !dir.as_os_str().is_empty() && local_dirs.insert(dir.to_path_buf()) {
+                let full_dir = path::Path::new(local_dir).join(dir);
+                if opts.is_dry_run {
+                    if !full_dir.exists() {
+                        println!("would mkdir {}", full_dir.display());
+                    }
+                } else {
+                    fs::create_dir_all(full_dir)?;
+                }
+            }
+        }
+        let src = format!("{remote_dir}{filename}");
+        let dst = format!("{local_dir}{filename}");
+        match remote.get(filename) {
+            Some((Fingerprint::Link(_), ..)) if opts.links == Links::Preserve => {
+                let Some(link_target) = ssh.read_link(&src)? else {
+                    bail!("{src} went away");
+                };
+                if opts.is_dry_run {
+                    println!("would symlink {dst} -> {link_target}");
+                } else {
+                    let _ = fs::remove_file(&dst);
+                    std::os::unix::fs::symlink(link_target, &dst)?;
+                }
+            }
+            _ => ssh.download(&src, &dst)?,
+        }
+    }
+
+    if !delete.is_empty() {
+        if opts.is_delete_local {
+            if opts.verbose {
+                println!("Delete local files that are absent remotely");
+            }
+            for filename in delete {
+                let full_path = format!("{local_dir}{filename}");
+                if opts.is_dry_run {
+                    println!("would delete local {full_path}");
+                } else {
+                    fs::remove_file(full_path)?;
+                }
+            }
+        } else {
+            println!(
+                "{} local files are not on the remote. Use --delete-local to delete them",
+                delete.len()
+            );
+        }
+    }
+
+    ssh.stop();
+    report.took = t_start.elapsed();
+    thread::sleep(Duration::from_millis(10)); // make sure Finished is last msg
+    let _ = progress.send(Progress::Finished(report.took));
+
+    Ok(report)
+}
+
+// Point the target's `current` back at the release before it
+pub fn rollback(
+    target: &Target,
+    opts: &DeployOptions,
+    connector: &Connector,
+) -> anyhow::Result<String> {
+    let base = dir_path(&target.path);
+    let (progress, _) = unbounded();
+    let ssh = connector
+        .connect(target, opts, progress)
+        .map_err(|err| anyhow!("Could not ssh to '{}': {err}", target.host_display()))?;
+    let releases = Releases::load(&ssh, &base)?;
+    let Some(current) = &releases.current else {
+        bail!(
+            "{base}{} is not a symlink to a release",
+            release::CURRENT_LINK
+        );
+    };
+    let Some(previous) = release::previous(&releases.names, current) else {
+        bail!("No release before {current}");
+    };
+    if opts.is_dry_run {
+        println!(
+            "would switch {base}{} from {current} to {previous}",
+            release::CURRENT_LINK
+        );
+    } else {
+        releases.switch(&ssh, previous)?;
+    }
+    ssh.stop();
+    Ok(format!("{current} -> {previous}"))
+}
+
+// List remote_dir with the helper, or over SFTP if it can't run there (a
+// noexec /tmp, an SFTP only account) or --no-helper
+fn list_remote(
+    ssh: &SSHManager,
+    target: &Target,
+    remote_dir: &str,
+    opts: &DeployOptions,
+) -> anyhow::Result<RemoteListing> {
+    let hostname = target.host_display();
+    if !opts.is_multi {
+        println!("Gathering information from {hostname}..");
+    }
+    if !opts.is_no_helper {
+        if let Some(output) = run_helper(ssh, target, remote_dir, opts)? {
+            return Ok(RemoteListing::Helper(output));
+        }
+    }
+    let dir = dir_path(remote_dir);
+    let files = list_sftp(ssh, &dir, opts.hash)?;
+    // Without the helper a shell may still work, and checksum every file on
+    // the server in one go
+    let sums = match opts.compare {
+        Compare::Checksum => match sums::remote_sums(ssh, &dir) {
+            Ok(sums) => {
+                if opts.verbose {
+                    println!("{}Checksums from {}", label(target, opts), sums.tool.name());
+                }
+                Some(sums)
+            }
+            Err(err) => {
+                eprintln!(
+                    "{}No checksum tool ran, reading the files over SFTP instead: {err}",
+                    label(target, opts)
+                );
+                None
+            }
+        },
+        _ => None,
+    };
+    Ok(RemoteListing::Sftp { dir, files, sums })
+}
+
+// Every file under dir, walked over SFTP without running anything on the
+// server. Like the helper it doesn't follow links, and skips other special
+// files. Files are by (mtime, size), see RemoteListing::entries.
+fn list_sftp(
+    ssh: &SSHManager,
+    dir: &str,
+    hash: Hash,
+) -> anyhow::Result<Vec<(String, Fingerprint, u32, u64)>> {
+    let mut out = Vec::with_capacity(64);
+    // relative to dir, with a trailing slash, "" for dir itself
+    let mut dirs = vec![String::new()];
+    while let Some(rel_dir) = dirs.pop() {
+        for entry in ssh.read_dir(&format!("{dir}{rel_dir}"))? {
+            if entry.name == "." || entry.name == ".." {
+                continue;
+            }
+            let rel_path = format!("{rel_dir}{}", entry.name);
+            match entry.mode & S_IFMT {
+                S_IFDIR => dirs.push(format!("{rel_path}/")),
+                S_IFREG => out.push((
+                    rel_path,
+                    Fingerprint::MtimeSize(entry.mtime, entry.size),
+                    entry.mode & 0o7777,
+                    entry.size,
+                )),
+                S_IFLNK => {
+                    let link_target = ssh
+                        .read_link(&format!("{dir}{rel_path}"))?
+                        .unwrap_or_default();
+                    out.push((
+                        rel_path,
+                        Fingerprint::Link(hash.digest(link_target.as_bytes())),
+                        0,
+                        link_target.len() as u64,
+                    ));
+                }
+                _ => {} // not a file, directory or link
+            }
+        }
+    }
+    Ok(out)
+}
+
+// Upload the helper if the server doesn't already have this build of it, and
+// run it on remote_dir.
+// Returns its output, a listing for parse_listing.
+pub(crate) fn run_helper(
+    ssh: &dyn Remote,
+    target: &Target,
+    remote_dir: &str,
+    opts: &DeployOptions,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let hostname = target.host_display();
+    // The server can't run it, no shell or noexec /tmp, so list over SFTP.
+    // Anything that looks unsafe is an error instead, see check_helper_perms.
+    let didnt_run = |err: anyhow::Error| {
+        eprintln!(
+            "{}Helper didn't run, listing over SFTP instead (--no-helper to not try): {err}",
+            label(target, opts)
+        );
+        Ok(None)
+    };
+    let output = match ssh.run_remote_cmd("uname -m; id -u") {
+        Ok((output, 0)) => output,
+        Ok((_, x)) => return didnt_run(anyhow!("{hostname}: 'uname -m; id -u' exit code {x}")),
+        Err(err) => return didnt_run(err),
+    };
+    let mut lines = output.lines();
+    let helper = match helper_for(lines.next().unwrap_or_default()) {
+        Ok(helper) => helper,
+        Err(err) => return didnt_run(anyhow!("{hostname}: {err}")),
+    };
+    let uid: u32 = match lines.next().and_then(|line| line.trim().parse().ok()) {
+        Some(uid) => uid,
+        None => return didnt_run(anyhow!("{hostname}: no user id in '{output}'")),
+    };
+
+    // Nobody else may be able to replace the helper between our checks and
+    // running it, so its directory has to be ours and closed to them.
+    let helper_dst = &helper_path(&opts.helper_dst, uid);
+    let helper_dir = match helper_dst.rsplit_once('/') {
+        Some(("", _)) => "/",
+        Some((dir, _)) => dir,
+        None => ".",
+    };
+    ssh.mkdir(helper_dir, 0o700)?;
+    check_helper_perms(helper_dir, ssh.lstat(helper_dir)?, uid, S_IFDIR)?;
+    // Read back over SFTP and compared with ours, never trusting what a
+    // binary says about itself
+    let state = match ssh.lstat(helper_dst)? {
+        None => HelperState::Missing,
+        Some(stat) => {
+            check_helper_perms(helper_dst, Some(stat), uid, S_IFREG)?;
+            helper_state(&ssh.read_file(helper_dst)?, helper)
+        }
+    };
+    if opts.verbose {
+        println!("{}Helper {helper_dst} {state}", label(target, opts));
+    }
+    if state != HelperState::Current {
+        ssh.upload_bytes(helper, helper_dst)?;
+        check_helper_perms(helper_dst, ssh.lstat(helper_dst)?, uid, S_IFREG)?;
+        if helper_state(&ssh.read_file(helper_dst)?, helper) != HelperState::Current {
+            bail!("{hostname}: {helper_dst} is not the helper we uploaded, not running it");
+        }
+    }
+
+    let hash = opts.hash.code() as char;
+    let helper_arg = shell_quote(helper_dst);
+    let dir_arg = shell_quote(remote_dir);
+    let remote_cmd = &match opts.compare {
+        Compare::Checksum => format!("{helper_arg} {dir_arg} {hash}"),
+        Compare::MtimeSize => format!("{helper_arg} {dir_arg} {hash}m"),
+    };
+    // Only a helper that never started means listing over SFTP. One that ran
+    // and failed, say on a missing directory, has found a real problem.
+    let (listing, is_exec_failed) = match ssh.run_remote_cmd_bytes(remote_cmd) {
+        Ok((output, 0)) => (Ok(output), false),
+        // the shell's "cannot execute", e.g. a noexec mount, and "not found"
+        Ok((_, x @ (126 | 127))) => (
+            Err(anyhow!(
+                "{hostname}: the shell couldn't run {helper_dst}, exit code {x}"
+            )),
+            true,
+        ),
+        Ok((_, x)) if x < 0 => (
+            Err(anyhow!(
+                "run_remote_cmd error: {x}. Try 'ssh {hostname}' and run '{remote_cmd}'"
+            )),
+            false,
+        ),
+        Ok((_, x)) => (
+            Err(anyhow!("{hostname}: remote helper exit code {x}")),
+            false,
+        ),
+        Err(err) => (Err(err), true),
+    };
+    // Whether or not it worked, so a failed run doesn't leave it behind
+    if opts.is_cleanup_helper {
+        let cleanup = ssh.delete(helper_dst).and_then(|_| {
+            // Only the directory we named after the user, others may be shared
+            if opts.helper_dst.contains(UID_VAR) {
+                ssh.rmdir(helper_dir)
+            } else {
+                Ok(())
+            }
+        });
+        // The helper's error says more than one from tidying up after it
+        if listing.is_ok() {
+            cleanup?;
+        }
+    }
+    match listing {
+        Ok(output) => Ok(Some(output)),
+        Err(err) if is_exec_failed => didnt_run(err),
+        Err(err) => Err(err),
+    }
+}
+
+// {uid} in --helper-dst is replaced with the remote user's id
+const UID_VAR: &str = "{uid}";
+
+// File type bits of a mode, see man 7 inode
+const S_IFMT: u32 = 0o170000;
+pub(crate) const S_IFDIR: u32 = 0o040000;
+pub(crate) const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+pub(crate) fn helper_path(helper_dst: &str, uid: u32) -> String {
+    helper_dst.replace(UID_VAR, &uid.to_string())
+}
+
+// `stat` is the path's (owner, mode) from lstat. It has to be the expected
+// type, not a symlink, belong to us or root, and not be writable by the group
+// or others.
+pub(crate) fn check_helper_perms(
+    path: &str,
+    stat: Option<(Option<u32>, u32)>,
+    uid: u32,
+    file_type: u32,
+) -> anyhow::Result<()> {
+    let Some((owner, mode)) = stat else {
+        bail!("helper path {path} does not exist");
+    };
+    let Some(owner) = owner else {
+        bail!("the server didn't say who owns helper path {path}, not running the helper");
+    };
+    if mode & S_IFMT != file_type {
+        let expected = if file_type == S_IFDIR {
+            "a directory"
+        } else {
+            "a regular file"
+        };
+        bail!("helper path {path} is not {expected}, not running the helper");
+    }
+    if owner != uid && owner != 0 {
+        bail!(
+            "helper path {path} belongs to uid {owner}, not to us ({uid}), not running the helper"
+        );
+    }
+    if mode & 0o022 != 0 {
+        bail!(
+            "helper path {path} is writable by others (mode {:o}), not running the helper. Set --helper-dst to a private directory",
+            mode & 0o7777
+        );
+    }
+    Ok(())
+}
+
+// The helper for the architecture `uname -m` printed
+pub(crate) fn helper_for(machine: &str) -> anyhow::Result<&'static [u8]> {
+    let machine = machine.trim();
+    match HELPERS.iter().find(|(name, _)| *name == machine) {
+        Some((_, helper)) => Ok(helper),
+        None => {
+            let names: Vec<&str> = HELPERS.iter().map(|(name, _)| *name).collect();
+            bail!(
+                "no helper for architecture '{machine}', only for {}",
+                names.join(", ")
+            )
+        }
+    }
+}
+
+// The helper already on the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HelperState {
+    Current,
+    Missing,
+    // Another build, or not a helper at all
+    Different,
+}
+
+impl fmt::Display for HelperState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HelperState::Current => write!(f, "is current"),
+            HelperState::Missing => write!(f, "is missing, uploading"),
+            HelperState::Different => write!(f, "is a different build, uploading"),
+        }
+    }
+}
+
+// Is the file on the server, as read over SFTP, byte for byte our helper
+pub(crate) fn helper_state(remote: &[u8], helper: &[u8]) -> HelperState {
+    if remote == helper {
+        HelperState::Current
+    } else {
+        HelperState::Different
+    }
+}
+
+// Every file in the helper's output
+pub(crate) fn parse_listing(
+    output: &[u8],
+    compare: Compare,
+    hash: Hash,
+) -> Result<Vec<RemoteEntry<'_>>, ProtocolError> {
+    let flags = match compare {
+        Compare::Checksum => 0,
+        Compare::MtimeSize => protocol::FLAG_MTIME,
+    };
+    let records = protocol::decode(output, flags, hash.code())?;
+    Ok(records
+        .into_iter()
+        .map(|r| {
+            let fingerprint = match (r.kind, compare) {
+                (Kind::Link, _) => Fingerprint::Link(to_digest(r.digest)),
+                (Kind::File, Compare::Checksum) => Fingerprint::Hash(to_digest(r.digest)),
+                (Kind::File, Compare::MtimeSize) => Fingerprint::MtimeSize(r.mtime, r.size),
+            };
+            (r.name, fingerprint, r.mode, r.size)
+        })
+        .collect())
+}
+
+// decode checked it isn't longer than MAX_DIGEST_LEN
+fn to_digest(bytes: &[u8]) -> Digest {
+    let mut digest = Digest {
+        len: bytes.len() as u8,
+        bytes: [0; MAX_DIGEST_LEN],
+    };
+    digest.bytes[..bytes.len()].copy_from_slice(bytes);
+    digest
+}
+
+// The listing as filename->(fingerprint, mode, size), without the excluded files
+// and upload temp files. With --links skip, without the symlinks too.
+fn remote_files<'a>(
+    listing: &[RemoteEntry<'a>],
+    filter: &Filter,
+    links: Links,
+) -> HashMap<&'a str, (Fingerprint, u32, u64)> {
+    listing
+        .iter()
+        .filter(|(name, ..)| !filter.is_excluded_path(name) && !remote::is_tmp_path(name))
+        .filter(|(_, fingerprint, ..)| {
+            links != Links::Skip || !matches!(fingerprint, Fingerprint::Link(_))
+        })
+        .map(|&(name, fingerprint, mode, size)| (name, (fingerprint, mode, size)))
+        .collect()
+}
+
+// The remote files that are missing or different locally, sorted, and the
+// bytes they add and change
+pub(crate) fn pull_plan<'a>(
+    remote: &HashMap<&'a str, (Fingerprint, u32, u64)>,
+    local: &LocalFiles,
+) -> (Vec<&'a str>, Report) {
+    let mut report = Report::default();
+    let mut download = Vec::new();
+    for (&filename, (r_fingerprint, _, r_size)) in remote.iter() {
+        match local.get(filename) {
+            None => {
+                download.push(filename);
+                report.bytes_added += r_size;
+            }
+            Some((l_fingerprint, l_size, _))
+                if l_size != r_size || l_fingerprint != r_fingerprint =>
+            {
+                download.push(filename);
+                report.bytes_changed += r_size;
+            }
+            _ => {} // they are the same
+        }
+    }
+    download.sort();
+    (download, report)
+}
+
+// With --links copy a pull makes a regular file of what a remote link points
+// at, as checksum_dir reads one locally, so fingerprint the link the same
+// way: by the target's mtime and size, or by its contents if the local file
+// is the same size. Broken links and links to directories are left out.
+pub(crate) fn follow_links(
+    ssh: &dyn Remote,
+    remote_dir: &str,
+    remote: &mut HashMap<&str, (Fingerprint, u32, u64)>,
+    local: &LocalFiles,
+    compare: Compare,
+    hash: Hash,
+) -> anyhow::Result<()> {
+    let links: Vec<&str> = remote
+        .iter()
+        .filter(|(_, (fingerprint, ..))| matches!(fingerprint, Fingerprint::Link(_)))
+        .map(|(&name, _)| name)
+        .collect();
+    for name in links {
+        let path = format!("{remote_dir}{name}");
+        let (mode, size, mtime) = match ssh.stat(&path)? {
+            Some(stat) if stat.0 & S_IFMT == S_IFREG => stat,
+            Some(_) => {
+                eprintln!("Skipping link to a directory {path}");
+                remote.remove(name);
+                continue;
+            }
+            None => {
+                eprintln!("Skipping broken link {path}");
+                remote.remove(name);
+                continue;
+            }
+        };
+        let fingerprint = match compare {
+            Compare::MtimeSize => Fingerprint::MtimeSize(mtime, size),
+            Compare::Checksum => match local.get(name) {
+                Some((_, l_size, _)) if *l_size == size => {
+                    Fingerprint::Hash(hash.digest(&ssh.read_file(&path)?))
+                }
+                // a different size has changed anyway
+                _ => remote[name].0,
+            },
+        };
+        remote.insert(name, (fingerprint, mode & 0o7777, size));
+    }
+    Ok(())
+}
+
+// Directories that held a deleted file and hold nothing that's staying,
+// deepest first so each one is empty by the time we get to it
+pub(crate) fn empty_dirs<'a>(
+    deleted: &[&'a str],
+    kept: impl Iterator<Item = &'a str>,
+) -> Vec<&'a str> {
+    let kept_dirs: HashSet<&str> = kept.flat_map(parent_dirs).collect();
+    let mut dirs: Vec<&str> = deleted
+        .iter()
+        .flat_map(|f| parent_dirs(f))
+        .filter(|d| !kept_dirs.contains(d))
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    dirs.sort_by_key(|d| (Reverse(d.matches('/').count()), *d));
+    dirs
+}
+
+// "a/b/c.html" -> "a", "a/b"
+fn parent_dirs(path: &str) -> impl Iterator<Item = &str> {
+    path.match_indices('/').map(move |(idx, _)| &path[..idx])
+}
+
+// Remote files that match an exclude rule, for --delete-excluded
+fn excluded_remote_files<'a>(listing: &[RemoteEntry<'a>], filter: &Filter) -> Vec<&'a str> {
+    listing
+        .iter()
+        .map(|(name, ..)| *name)
+        .filter(|name| filter.is_rule_excluded_path(name) && !remote::is_tmp_path(name))
+        .collect()
+}
+
+// Upload temp files in the helper's output
+fn leftover_tmp_files<'a>(listing: &[RemoteEntry<'a>]) -> Vec<&'a str> {
+    listing
+        .iter()
+        .map(|(name, ..)| *name)
+        .filter(|name| remote::is_tmp_path(name))
+        .collect()
+}
+
+// Prefix for messages about this host, empty when there's only one
+fn label(target: &Target, opts: &DeployOptions) -> String {
+    if opts.is_multi {
+        format!("{}: ", target.host_display())
+    } else {
+        String::new()
+    }
+}
+
+// With a trailing slash, so file names can be appended
+fn dir_path(dir: &str) -> String {
+    if dir.ends_with('/') {
+        dir.to_string()
+    } else {
+        format!("{dir}/")
+    }
+}
@@ -0,0 +1,277 @@
+//
+// Part of Demeter Deploy
+// https://github.com/grahamking/demeter-deploy/
+//
+// Exclude / include rules, rsync style.
+//
+// Rules come from --include, --exclude, --exclude-from and from .deignore files
+// in the source tree. The same rules are applied to the local walk and to the
+// remote listing, so an excluded remote file is never deleted.
+//
+// Patterns:
+//  - `*` matches anything except '/', `**` matches anything, `?` one character,
+//    `[a-z]` and `[!a-z]` a character class.
+//  - A trailing '/' only matches directories: `drafts/`.
+//  - A leading '/' anchors the pattern to the top of the tree (or to the
+//    directory holding the .deignore file): `/index.xml`.
+//  - A pattern without a '/' is matched against the file name only, otherwise
+//    against the end of the path: `blog/*.map`.
+//  - Excluding a directory excludes everything inside it.
+//
+// The first rule that matches wins. --include rules are checked before --exclude
+// rules, then --exclude-from. Rules in a .deignore file apply to its own directory
+// and below, and are checked before the global rules, deepest directory first.
+//
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context;
+
+// Per-directory rule file. One pattern per line, `!pattern` to include.
+pub const DEIGNORE: &str = ".deignore";
+
+#[derive(Debug)]
+struct Rule {
+    pattern: String,
+    is_include: bool,
+    is_dir_only: bool,
+    is_anchored: bool,
+}
+
+impl Rule {
+    fn new(pattern: &str, is_include: bool) -> Rule {
+        let (pattern, is_dir_only) = match pattern.strip_suffix('/') {
+            Some(p) => (p, true),
+            None => (pattern, false),
+        };
+        let (pattern, is_anchored) = match pattern.strip_prefix('/') {
+            Some(p) => (p, true),
+            None => (pattern, false),
+        };
+        Rule {
+            pattern: pattern.to_string(),
+            is_include,
+            is_dir_only,
+            is_anchored,
+        }
+    }
+
+    // Parse a line from a .deignore or --exclude-from file.
+    // Accepts gitignore style `!pattern` and rsync style `+ pattern` / `- pattern`.
+    fn from_line(line: &str) -> Option<Rule> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        if let Some(p) = line.strip_prefix("+ ") {
+            return Some(Rule::new(p, true));
+        }
+        if let Some(p) = line.strip_prefix("- ") {
+            return Some(Rule::new(p, false));
+        }
+        if let Some(p) = line.strip_prefix('!') {
+            return Some(Rule::new(p, true));
+        }
+        Some(Rule::new(line, false))
+    }
+
+    // path is relative to where the rule applies, without a leading '/'
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.is_dir_only && !is_dir {
+            return false;
+        }
+        if self.is_anchored {
+            return glob_match(&self.pattern, path);
+        }
+        if !self.pattern.contains('/') {
+            let name = path.rsplit('/').next().unwrap_or(path);
+            return glob_match(&self.pattern, name);
+        }
+        // match against the whole path, or any tail of it starting at a directory
+        if glob_match(&self.pattern, path) {
+            return true;
+        }
+        path.match_indices('/')
+            .any(|(idx, _)| glob_match(&self.pattern, &path[idx + 1..]))
+    }
+}
+
+pub struct Filter {
+    is_include_hidden: bool,
+    rules: Vec<Rule>,
+    // rules from .deignore files, key is directory relative to top, "" for top
+    dir_rules: HashMap<String, Vec<Rule>>,
+}
+
+impl Filter {
+    pub fn new(is_include_hidden: bool, includes: &[String], excludes: &[String]) -> Filter {
+        let mut rules: Vec<Rule> = includes.iter().map(|p| Rule::new(p, true)).collect();
+        rules.extend(excludes.iter().map(|p| Rule::new(p, false)));
+        Filter {
+            is_include_hidden,
+            rules,
+            dir_rules: HashMap::new(),
+        }
+    }
+
+    // Add the rules in an --exclude-from file
+    pub fn add_rules_file(&mut self, path: &Path) -> anyhow::Result<()> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("reading exclude file {}", path.display()))?;
+        self.rules
+            .extend(contents.lines().filter_map(Rule::from_line));
+        Ok(())
+    }
+
+    // Load the .deignore in a local directory, if there is one.
+    // rel_dir: dir relative to top, "" for top
+    // full_dir: where it is on disk
+    pub fn load_dir_rules(&mut self, rel_dir: &str, full_dir: &Path) -> anyhow::Result<()> {
+        let contents = match fs::read_to_string(full_dir.join(DEIGNORE)) {
+            Ok(c) => c,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => {
+                return Err(err).context(format!("reading {}", full_dir.join(DEIGNORE).display()))
+            }
+        };
+        let rules: Vec<Rule> = contents.lines().filter_map(Rule::from_line).collect();
+        if !rules.is_empty() {
+            self.dir_rules.insert(rel_dir.to_string(), rules);
+        }
+        Ok(())
+    }
+
+    // Should we skip this file or directory?
+    // path is relative to the top, e.g. "blog/drafts". Assumes the parent
+    // directories have already been checked, as in a tree walk.
+    pub fn is_excluded(&self, path: &str, is_dir: bool) -> bool {
+        self.excluded(path, is_dir, !self.is_include_hidden)
+    }
+
+    // Should we skip this file? Also checks all the directories it is in.
+    // For paths that don't come from a tree walk, such as the remote listing.
+    pub fn is_excluded_path(&self, path: &str) -> bool {
+        path.match_indices('/')
+            .any(|(idx, _)| self.is_excluded(&path[..idx], true))
+            || self.is_excluded(path, false)
+    }
+
+    // Is this file excluded by a rule, rather than just for being hidden?
+    // These are the remote files --delete-excluded deletes. Hidden files
+    // and .deignore files on the remote are left alone.
+    pub fn is_rule_excluded_path(&self, path: &str) -> bool {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        if name == DEIGNORE {
+            return false;
+        }
+        path.match_indices('/')
+            .any(|(idx, _)| self.excluded(&path[..idx], true, false))
+            || self.excluded(path, false, false)
+    }
+
+    fn excluded(&self, path: &str, is_dir: bool, is_hidden_excluded: bool) -> bool {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        if name == DEIGNORE {
+            return true;
+        }
+
+        // .deignore rules, deepest directory first
+        if !self.dir_rules.is_empty() {
+            let mut dir = path;
+            while let Some(idx) = dir.rfind('/') {
+                dir = &dir[..idx];
+                if let Some(v) = self.check(self.dir_rules.get(dir), &path[idx + 1..], is_dir) {
+                    return v;
+                }
+            }
+            if let Some(v) = self.check(self.dir_rules.get(""), path, is_dir) {
+                return v;
+            }
+        }
+
+        if let Some(v) = self.check(Some(&self.rules), path, is_dir) {
+            return v;
+        }
+
+        is_hidden_excluded && name.starts_with('.')
+    }
+
+    // Some(true) if excluded, Some(false) if included, None if no rule matched
+    fn check(&self, rules: Option<&Vec<Rule>>, path: &str, is_dir: bool) -> Option<bool> {
+        rules?
+            .iter()
+            .find(|r| r.matches(path, is_dir))
+            .map(|r| !r.is_include)
+    }
+}
+
+// Match a name against a glob pattern.
+// '*' and '?' don't match a '/', '**' does.
+pub(crate) fn glob_match(pattern: &str, name: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let n: Vec<char> = name.chars().collect();
+    glob(&p, &n)
+}
+
+fn glob(p: &[char], n: &[char]) -> bool {
+    match p.first() {
+        None => n.is_empty(),
+        Some('*') if p.get(1) == Some(&'*') => {
+            let rest = &p[2..];
+            // "a/**/b" also matches "a/b"
+            if rest.first() == Some(&'/') && glob(&rest[1..], n) {
+                return true;
+            }
+            (0..=n.len()).any(|i| glob(rest, &n[i..]))
+        }
+        Some('*') => {
+            let rest = &p[1..];
+            for i in 0..=n.len() {
+                if glob(rest, &n[i..]) {
+                    return true;
+                }
+                if i < n.len() && n[i] == '/' {
+                    break;
+                }
+            }
+            false
+        }
+        Some('?') => matches!(n.first(), Some(c) if *c != '/') && glob(&p[1..], &n[1..]),
+        Some('[') => match char_class(&p[1..], n.first()) {
+            Some((is_match, len)) => is_match && glob(&p[len + 1..], &n[1..]),
+            // no closing ']', treat as a literal
+            None => n.first() == Some(&'[') && glob(&p[1..], &n[1..]),
+        },
+        Some('\\') if p.len() > 1 => n.first() == Some(&p[1]) && glob(&p[2..], &n[1..]),
+        Some(c) => n.first() == Some(c) && glob(&p[1..], &n[1..]),
+    }
+}
+
+// Match a character against a class like "a-z]" (the part after '[').
+// Returns whether it matched and how many pattern chars the class used,
+// or None if the class is not terminated.
+fn char_class(p: &[char], c: Option<&char>) -> Option<(bool, usize)> {
+    let is_negated = matches!(p.first(), Some('!' | '^'));
+    let mut idx = if is_negated { 1 } else { 0 };
+    let mut is_match = false;
+    let mut is_first = true;
+    loop {
+        let start = *p.get(idx)?;
+        if start == ']' && !is_first {
+            break;
+        }
+        is_first = false;
+        if p.get(idx + 1) == Some(&'-') && p.get(idx + 2).is_some_and(|e| *e != ']') {
+            let end = p[idx + 2];
+            is_match |= c.is_some_and(|c| start <= *c && *c <= end);
+            idx += 3;
+        } else {
+            is_match |= c == Some(&start);
+            idx += 1;
+        }
+    }
+    let is_match = c.is_some_and(|c| *c != '/') && (is_match != is_negated);
+    Some((is_match, idx + 1))
+}
@@ -0,0 +1,119 @@
+//
+// Part of Demeter Deploy
+// https://github.com/grahamking/demeter-deploy/
+//
+// What to do when the server's host key isn't already in known_hosts.
+//
+
+use std::io::{self, BufRead, IsTerminal, Write};
+
+use anyhow::bail;
+
+const FINGERPRINT_PREFIX: &str = "SHA256:";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostKeyPolicy {
+    // Trust on first use: show the fingerprint and ask. The default.
+    Ask,
+    // Add unknown keys to known_hosts without asking. Changed keys are still refused.
+    AcceptNew,
+    // Only accept these keys, whatever known_hosts says. For CI.
+    Fingerprint(Vec<String>),
+}
+
+impl HostKeyPolicy {
+    // fingerprints: from --host-key-fingerprint, "SHA256:..." or just the base64 part
+    pub fn new(fingerprints: &[String], is_accept_new: bool) -> anyhow::Result<HostKeyPolicy> {
+        if !fingerprints.is_empty() {
+            if is_accept_new {
+                bail!("--accept-new-host-key and --host-key-fingerprint can't be used together");
+            }
+            let pins = fingerprints
+                .iter()
+                .map(|f| match f.trim() {
+                    f if f.starts_with(FINGERPRINT_PREFIX) => f.to_string(),
+                    f => format!("{FINGERPRINT_PREFIX}{f}"),
+                })
+                .collect();
+            return Ok(HostKeyPolicy::Fingerprint(pins));
+        }
+        if is_accept_new {
+            return Ok(HostKeyPolicy::AcceptNew);
+        }
+        Ok(HostKeyPolicy::Ask)
+    }
+}
+
+// Ask the user whether to trust a host we haven't seen before
+pub fn ask_trust(host: &str, key_type: &str, fingerprint: &str) -> anyhow::Result<bool> {
+    if !io::stdin().is_terminal() {
+        bail!(
+            "Host '{host}' is not in known_hosts. Its {key_type} key is {fingerprint}.\n\
+             Not a terminal so can't ask. Check the fingerprint, then pass \
+             --host-key-fingerprint {fingerprint} or --accept-new-host-key"
+        );
+    }
+    let mut out = io::stderr();
+    write!(
+        out,
+        "The authenticity of host '{host}' can't be established.\n\
+         {key_type} key fingerprint is {fingerprint}.\n\
+         Are you sure you want to continue connecting (yes/no)? "
+    )?;
+    out.flush()?;
+    loop {
+        let mut answer = String::new();
+        if io::stdin().lock().read_line(&mut answer)? == 0 {
+            return Ok(false);
+        }
+        match answer.trim().to_lowercase().as_str() {
+            "yes" => return Ok(true),
+            "no" | "" => return Ok(false),
+            _ => {
+                write!(out, "Please type 'yes' or 'no': ")?;
+                out.flush()?;
+            }
+        }
+    }
+}
+
+// The message for a key that doesn't match known_hosts.
+// known: (type, fingerprint) of the key in known_hosts, if we could read it
+pub fn changed_warning(
+    host: &str,
+    key_type: &str,
+    fingerprint: &str,
+    known: Option<(String, String)>,
+) -> String {
+    let mut msg = "\n\
+        @@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@\n\
+        @    WARNING: REMOTE HOST IDENTIFICATION HAS CHANGED!     @\n\
+        @@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@\n\
+        IT IS POSSIBLE THAT SOMEONE IS DOING SOMETHING NASTY!\n\
+        Someone could be eavesdropping on you right now (man-in-the-middle attack)!\n\
+        It is also possible that the host key has just been changed.\n"
+        .to_string();
+    match known {
+        Some((known_type, known_fp)) if known_type != key_type => {
+            msg += &format!(
+                "known_hosts has a {known_type} key for '{host}': {known_fp}\n\
+                 but the server sent a {key_type} key:     {fingerprint}\n"
+            );
+        }
+        Some((_, known_fp)) => {
+            msg += &format!(
+                "The {key_type} key for '{host}' has changed.\n\
+                 known_hosts has: {known_fp}\n\
+                 server sent:     {fingerprint}\n"
+            );
+        }
+        None => {
+            msg += &format!("The server sent a {key_type} key: {fingerprint}\n");
+        }
+    }
+    msg += &format!(
+        "If you know why it changed, remove the old key with `ssh-keygen -R {host}`, \
+         or pin the new one with --host-key-fingerprint {fingerprint}"
+    );
+    msg
+}
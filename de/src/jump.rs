@@ -0,0 +1,293 @@
+//
+// Part of Demeter Deploy
+// https://github.com/grahamking/demeter-deploy/
+//
+// Connect through a jump host (bastion), like `ssh -J`.
+//
+// We log in to the bastion once. Each connection to the target (the primary
+// and every upload worker) gets its own direct-tcpip channel on that one
+// session. libssh sessions aren't thread safe, so a single thread owns the
+// bastion and copies bytes between each channel and a local socket pair.
+// The other end of the socket pair is handed to libssh as the connection's fd.
+//
+// ProxyCommand in the ssh config is done by libssh itself, one process per
+// connection.
+//
+
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use crossbeam_channel::{bounded, unbounded, Sender, TryRecvError};
+
+use crate::auth::Auth;
+use crate::filter::glob_match;
+use crate::host_key::HostKeyPolicy;
+use crate::ssh::{Bastion, ForwardChannel, LogLevel};
+use crate::target::Target;
+
+const TUNNEL_BUF_SIZE: usize = 64 * 1024;
+
+// How long the pump thread sleeps when no tunnel has anything to do
+const IDLE_SLEEP: Duration = Duration::from_micros(200);
+
+struct TunnelRequest {
+    host: String,
+    port: u16,
+    reply: Sender<anyhow::Result<UnixStream>>,
+}
+
+#[derive(Clone)]
+pub struct JumpHost {
+    requests: Sender<TunnelRequest>,
+}
+
+impl JumpHost {
+    // Log in to the jump host. spec is `[user@]host[:port]`, as for `ssh -J`.
+    //
+    // The bastion uses the same auth methods as the target, but not its
+    // --identity, so the ssh config's IdentityFile for the bastion applies.
+    // It has to be in known_hosts, or be accepted, even if the target's key
+    // is pinned with --host-key-fingerprint.
+    pub fn new(
+        spec: &str,
+        auth: &Auth,
+        host_key: &HostKeyPolicy,
+        log_level: LogLevel,
+    ) -> anyhow::Result<JumpHost> {
+        if spec.contains(',') {
+            bail!("Only one jump host is supported, got '{spec}'");
+        }
+        let jump = Target::parse_host(spec)?;
+        let auth = Auth::new(auth.methods.clone(), None);
+        let host_key = match host_key {
+            HostKeyPolicy::Fingerprint(_) => HostKeyPolicy::Ask,
+            other => other.clone(),
+        };
+
+        let (requests, request_receiver) = unbounded::<TunnelRequest>();
+        let (ready_sender, ready_receiver) = bounded::<anyhow::Result<()>>(1);
+        thread::Builder::new()
+            .name("jump_host".to_string())
+            .spawn(move || {
+                let bastion = match Bastion::new(&jump, &auth, &host_key, log_level) {
+                    Ok(b) => b,
+                    Err(err) => {
+                        let _ = ready_sender.send(Err(err));
+                        return;
+                    }
+                };
+                let _ = ready_sender.send(Ok(()));
+                pump(bastion, request_receiver);
+            })?;
+        ready_receiver
+            .recv()?
+            .map_err(|err| anyhow!("Jump host {spec}: {err}"))?;
+
+        Ok(JumpHost { requests })
+    }
+
+    // A socket connected to host:port through the bastion
+    pub fn open_tunnel(&self, host: &str, port: u16) -> anyhow::Result<UnixStream> {
+        let (reply, reply_receiver) = bounded(1);
+        self.requests.send(TunnelRequest {
+            host: host.to_string(),
+            port,
+            reply,
+        })?;
+        reply_receiver.recv()?
+    }
+}
+
+struct Tunnel {
+    local: UnixStream,
+    channel: ForwardChannel,
+    // read from the channel, not yet written to local
+    to_local: Vec<u8>,
+    is_local_eof: bool,
+    is_remote_eof: bool,
+}
+
+impl Tunnel {
+    fn open(bastion: &Bastion, host: &str, port: u16) -> anyhow::Result<(Tunnel, UnixStream)> {
+        let channel = bastion.open_forward(host, port)?;
+        let (local, theirs) = UnixStream::pair()?;
+        local.set_nonblocking(true)?;
+        let tunnel = Tunnel {
+            local,
+            channel,
+            to_local: Vec::new(),
+            is_local_eof: false,
+            is_remote_eof: false,
+        };
+        Ok((tunnel, theirs))
+    }
+
+    // Move whatever is ready in either direction. Returns true if anything moved.
+    fn pump(&mut self, buf: &mut [u8]) -> anyhow::Result<bool> {
+        let mut is_busy = false;
+
+        if !self.is_local_eof {
+            match self.local.read(buf) {
+                Ok(0) => {
+                    self.is_local_eof = true;
+                    self.channel.send_eof();
+                    is_busy = true;
+                }
+                Ok(n) => {
+                    self.channel.write_all(&buf[..n])?;
+                    is_busy = true;
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        // Only read more once the last lot is written, so a slow reader
+        // doesn't block the other tunnels.
+        if self.to_local.is_empty() && !self.is_remote_eof {
+            match self.channel.read_nonblocking(buf)? {
+                None => {
+                    self.is_remote_eof = true;
+                    let _ = self.local.shutdown(Shutdown::Write);
+                    is_busy = true;
+                }
+                Some(0) => {}
+                Some(n) => {
+                    self.to_local.extend_from_slice(&buf[..n]);
+                    is_busy = true;
+                }
+            }
+        }
+        if !self.to_local.is_empty() {
+            match self.local.write(&self.to_local) {
+                Ok(n) => {
+                    self.to_local.drain(..n);
+                    is_busy = true;
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(is_busy)
+    }
+
+    fn is_done(&self) -> bool {
+        self.is_local_eof && self.is_remote_eof && self.to_local.is_empty()
+    }
+}
+
+// Runs on the jump_host thread until all the JumpHost handles are dropped
+// and the last tunnel is closed.
+fn pump(bastion: Bastion, requests: crossbeam_channel::Receiver<TunnelRequest>) {
+    let mut tunnels: Vec<Tunnel> = Vec::new();
+    let mut buf = vec![0u8; TUNNEL_BUF_SIZE];
+    loop {
+        let mut is_busy = false;
+        match requests.try_recv() {
+            Ok(req) => {
+                is_busy = true;
+                let reply = match Tunnel::open(&bastion, &req.host, req.port) {
+                    Ok((tunnel, theirs)) => {
+                        tunnels.push(tunnel);
+                        Ok(theirs)
+                    }
+                    Err(err) => Err(err),
+                };
+                let _ = req.reply.send(reply);
+            }
+            Err(TryRecvError::Disconnected) if tunnels.is_empty() => return,
+            Err(_) => {}
+        }
+
+        // A tunnel with an error is dropped, closing the local socket. The
+        // connection using it reports the error.
+        tunnels.retain_mut(|t| match t.pump(&mut buf) {
+            Ok(is_moved) => {
+                is_busy |= is_moved;
+                !t.is_done()
+            }
+            Err(_) => false,
+        });
+
+        if !is_busy {
+            thread::sleep(IDLE_SLEEP);
+        }
+    }
+}
+
+// The ProxyJump for this host in ~/.ssh/config or /etc/ssh/ssh_config.
+// As with ssh the first value found wins, and a ProxyCommand found first
+// means no jump, libssh runs the command instead.
+pub fn config_proxy_jump(host: &str) -> anyhow::Result<Option<String>> {
+    let mut files = Vec::new();
+    if let Ok(home) = env::var("HOME") {
+        files.push(PathBuf::from(home).join(".ssh/config"));
+    }
+    files.push(PathBuf::from("/etc/ssh/ssh_config"));
+
+    for path in files {
+        let contents = match fs::read_to_string(&path) {
+            Ok(c) => c,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => bail!("reading {}: {err}", path.display()),
+        };
+        if let Some(value) = find_proxy_jump(&contents, host) {
+            return Ok(if value.eq_ignore_ascii_case("none") {
+                None
+            } else {
+                Some(value)
+            });
+        }
+    }
+    Ok(None)
+}
+
+// Some("none") if ProxyCommand comes first, None if neither is set.
+// Only `Host` blocks and `Match all` are understood, other `Match` blocks
+// are skipped.
+pub(crate) fn find_proxy_jump(contents: &str, host: &str) -> Option<String> {
+    let host = host.to_lowercase();
+    let mut is_match = true; // lines before the first Host apply to all
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (keyword, value) = match line.split_once(|c: char| c.is_whitespace() || c == '=') {
+            Some((k, v)) => (
+                k.to_lowercase(),
+                v.trim_start_matches([' ', '\t', '=']).trim(),
+            ),
+            None => continue,
+        };
+        match keyword.as_str() {
+            "host" => is_match = host_matches(value, &host),
+            "match" => is_match = value.eq_ignore_ascii_case("all"),
+            "proxyjump" if is_match => return Some(value.to_string()),
+            "proxycommand" if is_match => return Some("none".to_string()),
+            _ => {}
+        }
+    }
+    None
+}
+
+// `Host` patterns: any positive pattern matches and no `!pattern` does
+fn host_matches(patterns: &str, host: &str) -> bool {
+    let mut is_match = false;
+    for p in patterns.split_whitespace() {
+        let p = p.to_lowercase();
+        match p.strip_prefix('!') {
+            Some(neg) if glob_match(neg, host) => return false,
+            Some(_) => {}
+            None => is_match |= glob_match(&p, host),
+        }
+    }
+    is_match
+}
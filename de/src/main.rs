@@ -5,168 +5,391 @@
 // This is the cmd-line
 //
 
-use core::arch::x86_64::_mm_crc32_u64;
-use std::collections::{HashMap, HashSet};
-use std::env;
+use std::collections::HashMap;
 use std::fs;
-use std::io::{BufReader, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
 use std::path;
 use std::process;
+use std::sync::Arc;
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Instant, UNIX_EPOCH};
 
+use anyhow::bail;
 use clap::arg;
-use crossbeam_channel::unbounded;
+use crossbeam_channel::{bounded, unbounded};
 
 mod ssh_manager;
-use ssh_manager::SSHManager;
 
 mod ssh;
 use ssh::SSH;
 
 mod remote;
-use remote::Remote;
+
+mod sums;
+
+mod protocol;
+
+// shared with the helper, so that both sides hash the same way
+#[path = "../../seed/src/hash.rs"]
+mod hash;
 
 mod progress_message;
 use progress_message::Progress;
 
 mod output;
-use output::run_output;
+use output::{run_multi_output, run_output, summary_table};
+
+mod deploy;
+
+mod release;
+use deploy::{
+    Compare, Connector, DeployOptions, Fingerprint, Hash, Links, LocalTree, MaxDelete, Report,
+};
+
+mod filter;
+use filter::Filter;
+
+mod auth;
+
+mod host_key;
+use host_key::HostKeyPolicy;
+
+mod jump;
+
+mod target;
+use target::Target;
+
+mod config;
+use config::{Config, Settings};
+
+#[cfg(test)]
+mod test;
 
 const DESC: &str = r#"Demeter Deploy: Fast blog pushes
 Example: de /home/graham/myfiles graham@myhost.com:/var/www/myfiles
-The format is intentionally the same as `scp`."#;
-
-const CRC32: u64 = 0xFFFFFFFF;
-const HELPER_SEP: char = ':';
-const DEFAULT_HELPER_DST: &str = "/tmp/seed";
+The format is intentionally the same as `scp`.
+Named targets can be kept in ~/.config/de/config.toml or ./.de.toml and used as `de deploy <profile>`."#;
 
-// Use ./build.sh to make sure this exists and is up to date
-static HELPER: &'static [u8] = include_bytes!("../../seed/target/release/seed-final");
+const USAGE: &str = "de [OPTIONS] <src_dir> <user@host:remote_dst_dir>...
+    de [OPTIONS] <user@host:remote_src_dir> <dst_dir>
+    de [OPTIONS] deploy <profile>
+    de [OPTIONS] rollback <profile|user@host:/dir/>
+    de config show <profile>";
 
 fn main() -> Result<(), anyhow::Error> {
-    let args = clap::Command::new("de src_dir user@host:remote_dst_dir")
+    let args = clap::Command::new("de")
         .about(DESC)
-        .arg(arg!(--"dry-run" "Show what we would do without doing it").required(false))
-        .arg(arg!(-v --verbose "Debug level output").required(false))
-        .arg(arg!(-H --hidden "Include hidden (dot) files").required(false))
+        .override_usage(USAGE)
+        .args_conflicts_with_subcommands(true)
+        .subcommand_negates_reqs(true)
+        .arg(
+            arg!(--"dry-run" "Show what we would do without doing it")
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            arg!(-v --verbose "Debug level output")
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            arg!(-H --hidden "Include hidden (dot) files")
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            arg!(-w --workers <NUM> "Number of concurrent SSH connections [default: 4]")
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            arg!(--"helper-dst" <PATH> "Full path to upload remote helper binary to, {uid} is the remote user's [default: /tmp/de-{uid}/seed]")
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            arg!(--"cleanup-helper" "Remove the remote helper once it has listed the files")
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            arg!(--"no-helper" "List the remote files over SFTP instead of with the helper. Automatic if the helper can't run")
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            arg!(--include <PATTERN> "Don't exclude files matching PATTERN, checked before --exclude")
+                .required(false)
+                .multiple_occurrences(true)
+                .global(true),
+        )
+        .arg(
+            arg!(--exclude <PATTERN> "Exclude files matching PATTERN, e.g. '*.map' or 'drafts/'")
+                .required(false)
+                .multiple_occurrences(true)
+                .global(true),
+        )
+        .arg(
+            arg!(--"exclude-from" <FILE> "Read exclude patterns from FILE, one per line, '!' to include")
+                .required(false)
+                .multiple_occurrences(true)
+                .global(true),
+        )
+        .arg(
+            arg!(-i --identity <FILE> "Private key file to log in with. Prompts for its passphrase, or set $DE_KEY_PASSPHRASE")
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            arg!(--auth <METHODS> "Auth methods to try, in order. Password comes from $DE_PASSWORD or a prompt [default: agent,key,keyboard-interactive,password]")
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            arg!(-J --jump <HOST> "Connect through this jump host, [user@]host[:port]. Default is the ssh config's ProxyJump")
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            arg!(--"accept-new-host-key" "Add unknown host keys to known_hosts without asking. Changed keys are still refused")
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            arg!(--"host-key-fingerprint" <FINGERPRINT> "Only connect if the host key is this one, e.g. SHA256:abc..., whatever known_hosts says")
+                .required(false)
+                .multiple_occurrences(true)
+                .global(true),
+        )
+        .arg(
+            arg!(--atomic "Upload into a new release dir, then switch the 'current' symlink to it")
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            arg!(--keep <NUM> "With --atomic, how many releases to keep [default: 5]")
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            arg!(--compare <HOW> "How to spot changed files: checksum, or mtime-size to skip reading them [default: checksum]")
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            arg!(--hash <NAME> "Content hash for checksum compare: crc32c, xxh3 or blake3 [default: xxh3]")
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            arg!(--links <HOW> "Symlinks: copy what they point at, preserve them as links, or skip them [default: copy]")
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            arg!(--"no-delete" "Never delete remote files, even if they aren't local")
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            arg!(--"delete-excluded" "Also delete remote files that match an exclude rule")
+                .required(false)
+                .global(true),
+        )
         .arg(
-            arg!(-w --workers "Number of concurrent SSH connections")
+            arg!(--"max-delete" <LIMIT> "Stop without changing anything if more than N, or P%, of the files would be deleted")
                 .required(false)
-                .default_value("4"),
+                .global(true),
         )
         .arg(
-            arg!(--"helper-dst" "Full path to upload remote helper binary to")
+            arg!(--"delete-local" "When pulling, delete local files that aren't on the remote")
                 .required(false)
-                .default_value(DEFAULT_HELPER_DST),
+                .global(true),
+        )
+        .arg(arg!([src_dir] "Directory to copy from, local to push or user@host:/dir/ to pull").required(true))
+        .arg(
+            arg!([remote] "Directory to copy to, user@host:/dir/ to push or local to pull. Push takes several")
+                .required(true)
+                .multiple_values(true),
+        )
+        .subcommand(
+            clap::Command::new("deploy")
+                .about("Deploy a named profile from the config file")
+                .arg(arg!(<profile> "Profile name, a [section] in the config file")),
+        )
+        .subcommand(
+            clap::Command::new("rollback")
+                .about("Point current back at the previous release, after an --atomic deploy")
+                .arg(arg!(<target> "Profile name, or user@host:/dir/")),
+        )
+        .subcommand(
+            clap::Command::new("config")
+                .about("Inspect the config file")
+                .subcommand_required(true)
+                .subcommand(
+                    clap::Command::new("show")
+                        .about("Print a profile merged with environment and command line")
+                        .arg(arg!(<profile> "Profile name")),
+                ),
         )
-        .arg(arg!([src_dir] "Local directory to copy from").required(true))
-        .arg(arg!([remote] "Remote to copy to in format user@host:/dir/").required(true))
         .get_matches();
 
-    let num_workers: usize = args.value_of("workers").unwrap().parse()?;
-    let verbose = args.is_present("verbose");
-    let is_dry_run = args.is_present("dry-run");
-    let is_include_hidden = args.is_present("hidden");
-    let helper_dst = args.value_of("helper-dst").unwrap();
-
-    // clap makes sure these two are populated, we don't need to check
-    let mut src_dir = args.value_of("src_dir").unwrap().to_string();
-    let remote_str = args.value_of("remote").unwrap().to_string();
-    if !src_dir.ends_with('/') {
-        src_dir.push('/');
+    let config = Config::load()?;
+    let settings = match args.subcommand() {
+        Some(("deploy", sub)) => {
+            let profile = config.profile(sub.value_of("profile").unwrap())?;
+            Settings::new(&args, Some(sub), Some(profile))
+        }
+        Some(("rollback", sub)) => {
+            let name = sub.value_of("target").unwrap();
+            if Target::is_remote(name) {
+                let settings = Settings::new(&args, Some(sub), None);
+                return rollback(&settings, &[name.to_string()]);
+            }
+            let settings = Settings::new(&args, Some(sub), Some(config.profile(name)?));
+            return rollback(&settings, &settings.list("remote")?);
+        }
+        Some(("config", sub)) => {
+            // "show" is the only config sub-command
+            let (_, show) = sub.subcommand().unwrap();
+            let name = show.value_of("profile").unwrap();
+            let settings = Settings::new(&args, Some(show), Some(config.profile(name)?));
+            print!("{}", settings.show(name)?);
+            return Ok(());
+        }
+        _ => Settings::new(&args, None, None),
+    };
+
+    let verbose = settings.flag("verbose")?;
+    let is_dry_run = settings.flag("dry-run")?;
+    let is_include_hidden = settings.flag("hidden")?;
+
+    let mut filter = Filter::new(
+        is_include_hidden,
+        &settings.list("include")?,
+        &settings.list("exclude")?,
+    );
+    for exclude_file in settings.list("exclude-from")? {
+        filter.add_rules_file(path::Path::new(&exclude_file))?;
     }
 
-    // split remote string into username, hostname and path
-    let mut from_env = "".to_string();
-    let (username, remote_str) = remote_str.split_once('@').unwrap_or_else(|| {
-        from_env = env::var("USERNAME").expect("missing @ in remote part and $USERNAME not set");
-        (&from_env, &remote_str)
-    });
-    let (hostname, dst_dir) = remote_str
-        .split_once(':')
-        .expect("missing hostname (':' separator) in remote part");
-    let mut dst_dir = dst_dir.to_string();
-    if !dst_dir.ends_with('/') {
-        dst_dir.push('/');
+    // Push is `de local_dir user@host:/dir/ [user@host2:/dir/ ..]`,
+    // pull is `de user@host:/dir/ local_dir`
+    let src_str = settings.required("src-dir")?;
+    let dst_strs = settings.list("remote")?;
+    if dst_strs.is_empty() {
+        bail!("Missing 'remote'. Use --help for usage.");
+    }
+    let (mut local_dir, remote_strs, is_pull) = if !Target::is_remote(&src_str) {
+        (src_str, dst_strs, false)
+    } else if dst_strs.len() == 1 && !Target::is_remote(&dst_strs[0]) {
+        (dst_strs[0].clone(), vec![src_str], true)
+    } else {
+        bail!("Pull copies one remote to one local directory, got {src_str} {dst_strs:?}");
+    };
+    if !local_dir.ends_with('/') {
+        local_dir.push('/');
+    }
+    if is_pull && !path::Path::new(&local_dir).exists() && !is_dry_run {
+        fs::create_dir_all(&local_dir)?;
+    }
+    let targets = remote_strs
+        .iter()
+        .map(|r| Target::parse(r))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let opts = Arc::new(deploy_options(&settings, &local_dir, targets.len() > 1)?);
+    if is_pull && opts.is_atomic {
+        bail!("--atomic is only for pushing. To pull an atomic deploy use its current/ dir");
     }
 
     // start local check in the background
-    let src_dir_for_local = src_dir.clone();
+    let local_dir_for_thread = local_dir.clone();
+    let compare = opts.compare;
+    let hash = opts.hash;
+    let links = opts.links;
     let local_thread = thread::Builder::new()
         .name("local checksum".to_string())
         .spawn(move || {
             let t_start = Instant::now();
-            let out = checksum_dir(src_dir_for_local.into(), is_include_hidden);
+            let out = if is_pull && !path::Path::new(&local_dir_for_thread).exists() {
+                // dry run pull into a new directory
+                Ok((HashMap::new(), filter))
+            } else {
+                checksum_dir(local_dir_for_thread.into(), filter, compare, hash, links)
+            };
             if verbose {
                 println!("Local checksum took {:?}", t_start.elapsed());
             }
             out
         })?;
 
-    // remote
+    // remote, one thread per host
     if verbose {
         println!("Using libssh {}", SSH::version());
     }
-    let (progress_sender, progress_receiver) = unbounded::<Progress>();
-    let mut ssh = match SSHManager::new(
-        hostname,
-        username,
-        ssh::LogLevel::NOLOG,
-        num_workers,
-        progress_sender.clone(),
-    ) {
-        Ok(s) => s,
-        Err(err) => {
-            eprintln!("Could not ssh to '{username}@{hostname}': {err}");
-            process::exit(1);
-        }
-    };
-
-    println!("Gathering information from {hostname}..");
-    ssh.upload_bytes(HELPER, helper_dst)?;
-
-    let remote_cmd = &format!("{helper_dst} {dst_dir}");
-    let (output, exit_status) = ssh.run_remote_cmd(remote_cmd)?;
-    match exit_status {
-        0 => {} // success
-        x if x < 0 => {
-            eprintln!(
-                "run_remote_cmd error: {x}. Try 'ssh {username}@{hostname}' and run '{remote_cmd}'"
-            );
-            process::exit(2);
-        }
-        x if x > 0 => {
-            eprintln!("Remote helper exit code {x}");
-            process::exit(x);
-        }
-        _ => unreachable!(),
-    }
-
-    let first_line = output
-        .lines()
-        .next()
-        .expect("No response from remote or empty directory");
-    if !first_line.contains(HELPER_SEP) {
-        eprintln!("Remote helper error: {output}");
-        process::exit(2);
+    let connector = Arc::new(Connector::default());
+    let mut local_senders = Vec::with_capacity(targets.len());
+    let mut progress_receivers = Vec::with_capacity(targets.len());
+    let mut host_threads = Vec::with_capacity(targets.len());
+    for target in targets.iter() {
+        let (local_sender, local_receiver) = bounded::<LocalTree>(1);
+        let (progress_sender, progress_receiver) = unbounded::<Progress>();
+        local_senders.push(local_sender);
+        progress_receivers.push(progress_receiver);
+
+        let target = target.clone();
+        let opts = opts.clone();
+        let connector = connector.clone();
+        let thread_handle = thread::Builder::new()
+            .name(format!("host {}", target.host))
+            .spawn(move || {
+                let res = if is_pull {
+                    deploy::pull(
+                        &target,
+                        &opts,
+                        &connector,
+                        local_receiver,
+                        progress_sender.clone(),
+                    )
+                } else {
+                    deploy::push(
+                        &target,
+                        &opts,
+                        &connector,
+                        local_receiver,
+                        progress_sender.clone(),
+                    )
+                };
+                if let Err(err) = &res {
+                    let _ = progress_sender.send(Progress::Failed(err.to_string()));
+                }
+                res
+            })?;
+        host_threads.push(thread_handle);
     }
 
-    let remote: HashMap<&str, u32> = output
-        .lines()
-        .map(|l| {
-            l.split_once(HELPER_SEP)
-                .map_or(("", 0), |(k, v)| (k, v.parse().unwrap()))
-        })
-        .filter(|(name, _)| is_include_hidden || !name.starts_with('.'))
-        .collect();
+    let output_thread = if is_dry_run {
+        None
+    } else if targets.len() == 1 {
+        let verb = if is_pull { "Downloaded" } else { "Uploaded" };
+        let progress_receiver = progress_receivers.pop().unwrap();
+        Some(thread::spawn(move || run_output(verb, progress_receiver)))
+    } else {
+        let names = targets.iter().map(|t| t.host_display()).collect();
+        Some(thread::spawn(move || {
+            run_multi_output(names, progress_receivers)
+        }))
+    };
 
     // join local checksumming thread
-    let local = match local_thread.join() {
+    // local thread hands back the filter with the .deignore rules it found
+    let local_tree = match local_thread.join() {
         Ok(checksum_dir_ret) => match checksum_dir_ret {
-            Ok(c) => c,
+            Ok(c) => Arc::new(c),
             Err(err) => {
-                eprintln!("Error on local dir {}: {}", src_dir, err);
+                eprintln!("Error on local dir {}: {}", local_dir, err);
                 process::exit(1);
             }
         },
@@ -175,153 +398,210 @@ fn main() -> Result<(), anyhow::Error> {
             panic!("{:?}", err);
         }
     };
-
-    // compare
-    if verbose {
-        println!("Comparing local and remote files");
+    for local_sender in local_senders {
+        // the host may already have failed
+        let _ = local_sender.send(local_tree.clone());
     }
-    let mut num_upload_bytes = 0;
-    let mut upload = Vec::new();
-    for (filename, (l_crc32, l_size)) in local.iter() {
-        match remote.get(filename.as_str()) {
-            None => {
-                upload.push(filename);
-                num_upload_bytes += l_size;
-            }
-            Some(r_crc32) if r_crc32 != l_crc32 => {
-                upload.push(filename);
-                num_upload_bytes += l_size;
-            }
-            _ => {} // they are the same
-        }
-    }
-
-    let mut delete = Vec::new();
-    let mut remote_dirs = HashSet::with_capacity(64);
-    for (filename, _) in remote {
-        let p = path::PathBuf::from(filename);
-        if let Some(dir) = p.parent() {
-            remote_dirs.insert(dir.to_path_buf());
-        }
 
-        if !local.contains_key(filename) {
-            delete.push(filename);
-        }
+    let results: Vec<anyhow::Result<Report>> = host_threads
+        .into_iter()
+        .map(|h| {
+            h.join()
+                .unwrap_or_else(|err| Err(anyhow::anyhow!("panic: {err:?}")))
+        })
+        .collect();
+    if let Some(output_thread) = output_thread {
+        let _ = output_thread.join();
     }
 
-    if verbose {
-        println!("Upload: {:?}", upload);
-        println!("Delete: {:?}", delete);
+    if targets.len() == 1 {
+        if let Err(err) = &results[0] {
+            eprintln!("{err}");
+            process::exit(1);
+        }
+        return Ok(());
     }
-
-    if is_dry_run {
-        ssh = ssh.switch_to_dry_run();
-    } else {
-        let num_upload_files = upload.len();
-        thread::spawn(move || run_output(num_upload_files, num_upload_bytes, progress_receiver));
+    let rows: Vec<(String, Result<Report, String>)> = targets
+        .iter()
+        .zip(results)
+        .map(|(t, r)| (t.host_display(), r.map_err(|err| err.to_string())))
+        .collect();
+    print!("{}", summary_table(&rows, is_dry_run));
+    if rows.iter().any(|(_, r)| r.is_err()) {
+        process::exit(1);
     }
+    Ok(())
+}
 
-    if upload.is_empty() && delete.is_empty() {
-        println!("Directories are already identical");
-        ssh.stop();
-        return Ok(());
+fn deploy_options(
+    settings: &Settings,
+    local_dir: &str,
+    is_multi: bool,
+) -> anyhow::Result<DeployOptions> {
+    if settings.flag("no-delete")? && settings.flag("delete-excluded")? {
+        bail!("--no-delete and --delete-excluded can't be used together");
     }
+    Ok(DeployOptions {
+        local_dir: local_dir.to_string(),
+        helper_dst: settings.required("helper-dst")?,
+        is_cleanup_helper: settings.flag("cleanup-helper")?,
+        is_no_helper: settings.flag("no-helper")?,
+        num_workers: settings.usize("workers")?,
+        auth_methods: auth::parse_methods(&settings.required("auth")?)?,
+        identity: settings.string("identity")?,
+        host_key: HostKeyPolicy::new(
+            &settings.list("host-key-fingerprint")?,
+            settings.flag("accept-new-host-key")?,
+        )?,
+        jump: settings.string("jump")?,
+        compare: Compare::parse(&settings.required("compare")?)?,
+        hash: Hash::parse(&settings.required("hash")?)?,
+        links: Links::parse(&settings.required("links")?)?,
+        is_dry_run: settings.flag("dry-run")?,
+        is_atomic: settings.flag("atomic")?,
+        keep: settings.usize("keep")?,
+        is_no_delete: settings.flag("no-delete")?,
+        is_delete_excluded: settings.flag("delete-excluded")?,
+        max_delete: settings
+            .string("max-delete")?
+            .map(|s| MaxDelete::parse(&s))
+            .transpose()?,
+        is_delete_local: settings.flag("delete-local")?,
+        verbose: settings.flag("verbose")?,
+        is_multi,
+    })
+}
 
-    // action
-
-    let t_start = Instant::now();
-    for filename in upload {
-        // Do we need to make the parent dir(s)?
-        let p = path::PathBuf::from(filename);
-        if let Some(dir) = p.parent() {
-            if !dir.as_os_str().is_empty() && !remote_dirs.contains(dir) {
-                if verbose {
-                    println!("mkdir remote: {}", dir.display());
-                }
-                for component in dir
-                    .ancestors()
-                    .collect::<Vec<_>>()
-                    .into_iter()
-                    .rev()
-                    .skip(1)
-                {
-                    ssh.mkdir(&format!("{dst_dir}{}", component.display()), 0o755)?;
-                    remote_dirs.insert(component.to_path_buf());
-                }
+// `de rollback`: switch each host's `current` back to the previous release
+fn rollback(settings: &Settings, remotes: &[String]) -> anyhow::Result<()> {
+    let opts = deploy_options(settings, "", remotes.len() > 1)?;
+    let connector = Connector::default();
+    let mut is_ok = true;
+    for remote in remotes {
+        let target = Target::parse(remote)?;
+        match deploy::rollback(&target, &opts, &connector) {
+            Ok(msg) => println!("{}: {msg}", target.host_display()),
+            Err(err) => {
+                eprintln!("{}: {err}", target.host_display());
+                is_ok = false;
             }
         }
-        ssh.upload(
-            &format!("{src_dir}{filename}"),
-            &format!("{dst_dir}{filename}"),
-        )?;
     }
-
-    if verbose {
-        println!("Delete remote files that are absent locally");
-    }
-    for filename in delete {
-        ssh.delete(&format!("{dst_dir}{}", filename))?;
+    if !is_ok {
+        process::exit(1);
     }
-
-    ssh.stop();
-    let took_s = t_start.elapsed();
-    thread::sleep(Duration::from_millis(10)); // make sure Finished is last msg
-    let _ = progress_sender.send(Progress::Finished(took_s));
-
     Ok(())
 }
 
-// returns map of filepath->(checksum, filesize)
+// filepath->(checksum or mtime, filesize, permission bits)
+// A preserved symlink's size is the length of where it points, its permission bits are 0.
+pub type LocalFiles = HashMap<String, (Fingerprint, u64, u32)>;
+
+// returns map of filepath->(checksum or mtime, filesize, mode), and the filter with any
+// .deignore rules added, so that it can be applied to the remote files too.
+// Anything that isn't a regular file, directory or symlink is ignored.
 fn checksum_dir(
     path: path::PathBuf,
-    is_include_hidden: bool,
-) -> Result<HashMap<String, (u32, u64)>, anyhow::Error> {
+    mut filter: Filter,
+    compare: Compare,
+    hash: Hash,
+    links: Links,
+) -> Result<(LocalFiles, Filter), anyhow::Error> {
     let path_len = path.to_string_lossy().len();
     let mut out = HashMap::with_capacity(64);
-    let mut dirs = vec![path];
+    // Each dir with the real paths of it and the dirs above it, so a link back
+    // up the tree isn't followed forever. Only those: a link to a sibling,
+    // latest -> posts, is walked as well as posts itself.
+    let top = vec![fs::canonicalize(&path)?];
+    let mut dirs = vec![(path, top)];
+
+    while let Some((next_dir, ancestors)) = dirs.pop() {
+        // relative to the top, "" for the top itself
+        let rel_dir = next_dir
+            .to_string_lossy()
+            .get(path_len..)
+            .unwrap_or("")
+            .to_string();
+        filter.load_dir_rules(&rel_dir, &next_dir)?;
 
-    while !dirs.is_empty() {
-        let next_dir = dirs.pop().unwrap();
         for entry in fs::read_dir(next_dir)? {
             let file = entry?;
             let filename = file.file_name().to_string_lossy().into_owned();
-            if filename.starts_with('.') && !is_include_hidden {
-                continue;
-            }
-            let file_type = file.file_type()?;
-            if file_type.is_dir() {
-                dirs.push(file.path());
+            let rel_path = if rel_dir.is_empty() {
+                filename
             } else {
-                let f = fs::File::open(file.path())?;
-                let file_size = f.metadata()?.len();
-                let mut f = BufReader::new(f);
-                let mut ubuf = [0; 8];
-
-                let mut checksum = CRC32;
-                loop {
-                    ubuf.fill(0);
-                    match f.read(&mut ubuf) {
-                        Ok(0) => {
-                            break;
-                        }
-                        Ok(_) => {
-                            checksum = unsafe { _mm_crc32_u64(checksum, u64::from_le_bytes(ubuf)) };
+                format!("{rel_dir}/{filename}")
+            };
+            let mut file_type = file.file_type()?;
+            if file_type.is_symlink() {
+                match links {
+                    Links::Skip => continue,
+                    Links::Preserve => {
+                        if !filter.is_excluded(&rel_path, false) {
+                            let link_target = fs::read_link(file.path())?;
+                            let link_target = link_target.as_os_str().as_bytes();
+                            out.insert(
+                                rel_path,
+                                (
+                                    Fingerprint::Link(hash.digest(link_target)),
+                                    link_target.len() as u64,
+                                    0,
+                                ),
+                            );
                         }
+                        continue;
+                    }
+                    Links::Copy => match fs::metadata(file.path()) {
+                        Ok(meta) => file_type = meta.file_type(),
                         Err(err) => {
-                            return Err(err.into());
+                            eprintln!("Skipping broken link {}: {err}", file.path().display());
+                            continue;
                         }
-                    }
+                    },
                 }
+            }
+            if filter.is_excluded(&rel_path, file_type.is_dir()) {
+                continue;
+            }
+            if file_type.is_dir() {
+                let real_path = fs::canonicalize(file.path())?;
+                if ancestors.contains(&real_path) {
+                    eprintln!("Skipping link loop {}", file.path().display());
+                } else {
+                    let mut chain = ancestors.clone();
+                    chain.push(real_path);
+                    dirs.push((file.path(), chain));
+                }
+            } else if !file_type.is_file() {
+                continue; // fifo, socket, device
+            } else if compare == Compare::MtimeSize {
+                let meta = fs::metadata(file.path())?; // follow links, as File::open does
+                let mtime = meta
+                    .modified()?
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs());
+                out.insert(
+                    rel_path,
+                    (
+                        Fingerprint::MtimeSize(mtime, meta.len()),
+                        meta.len(),
+                        meta.permissions().mode() & 0o7777,
+                    ),
+                );
+            } else {
+                let mut f = fs::File::open(file.path())?;
+                let meta = f.metadata()?;
+                let file_size = meta.len();
                 out.insert(
-                    file.path()
-                        .to_string_lossy()
-                        .get(path_len..)
-                        .unwrap()
-                        .to_string(),
-                    ((checksum & CRC32) as u32, file_size),
+                    rel_path,
+                    (
+                        Fingerprint::Hash(hash.digest_read(&mut f)?),
+                        file_size,
+                        meta.permissions().mode() & 0o7777,
+                    ),
                 );
             }
         }
     }
-    Ok(out)
+    Ok((out, filter))
 }
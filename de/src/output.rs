@@ -1,17 +1,29 @@
 use std::cmp::max;
 use std::collections::HashMap;
+use std::fmt;
 use std::io::{stdout, Write};
 use std::time::Duration;
 
+use crate::deploy::Report;
 use crate::progress_message::Progress;
-use crossbeam_channel::Receiver;
+use crossbeam_channel::{Receiver, Select};
 
 const KB: f64 = 1024.0;
 const MB: f64 = KB * 1024.0;
 
 // Display thread
-pub fn run_output(total_files: usize, total_bytes: u64, recv: Receiver<Progress>) {
-    if total_files == 0 || total_bytes == 0 {
+// verb: "Uploaded" or "Downloaded"
+// Waits for the Planned message with the totals. If it doesn't have the
+// total bytes progress is by number of files.
+pub fn run_output(verb: &str, recv: Receiver<Progress>) {
+    let (total_files, total_bytes) = loop {
+        match recv.recv() {
+            Ok(Progress::Planned(files, bytes)) => break (files, bytes),
+            Ok(Progress::Failed(_)) | Err(_) => return,
+            Ok(_) => {}
+        }
+    };
+    if total_files == 0 || total_bytes == Some(0) {
         return;
     }
     use Progress::*;
@@ -28,7 +40,7 @@ pub fn run_output(total_files: usize, total_bytes: u64, recv: Receiver<Progress>
                 let size = in_progress
                     .remove(&filename)
                     .expect("Got Complete for file that didn't have Start");
-                let msg = format!("\rUploaded {filename} ({})", humanize(size));
+                let msg = format!("\r{verb} {filename} ({})", humanize(size));
                 {
                     let mut out = stdout();
                     out.write_all(msg.as_bytes()).unwrap();
@@ -39,7 +51,10 @@ pub fn run_output(total_files: usize, total_bytes: u64, recv: Receiver<Progress>
             }
             Part(bytes) => {
                 bytes_so_far += bytes;
-                let bytes_pct = (bytes_so_far as f64) / (total_bytes as f64) * 100.0;
+                let bytes_pct = match total_bytes {
+                    Some(total) => (bytes_so_far as f64) / (total as f64) * 100.0,
+                    None => (files_so_far as f64) / (total_files as f64) * 100.0,
+                };
                 if (prev_bytes_pct - bytes_pct).abs() < 1.0 {
                     // only update display if > 1% progress
                     continue;
@@ -47,13 +62,22 @@ pub fn run_output(total_files: usize, total_bytes: u64, recv: Receiver<Progress>
                 prev_bytes_pct = bytes_pct;
                 let mut active_files: Vec<&str> = in_progress.keys().map(|s| s.as_ref()).collect();
                 active_files.sort();
-                let msg = format!(
-                    "\rProgress: {} / {} files, {:.0}% of bytes. [{}]",
-                    files_so_far,
-                    total_files,
-                    bytes_pct,
-                    active_files.join(" "),
-                );
+                let msg = match total_bytes {
+                    Some(_) => format!(
+                        "\rProgress: {} / {} files, {:.0}% of bytes. [{}]",
+                        files_so_far,
+                        total_files,
+                        bytes_pct,
+                        active_files.join(" "),
+                    ),
+                    None => format!(
+                        "\rProgress: {} / {} files, {}. [{}]",
+                        files_so_far,
+                        total_files,
+                        humanize(bytes_so_far as u64),
+                        active_files.join(" "),
+                    ),
+                };
                 {
                     let mut out = stdout();
                     out.write_all(msg.as_bytes()).unwrap();
@@ -61,6 +85,7 @@ pub fn run_output(total_files: usize, total_bytes: u64, recv: Receiver<Progress>
                 }
             }
             Finished(elapsed) => {
+                let total_bytes = total_bytes.unwrap_or(bytes_so_far as u64);
                 let msg = format!(
                     "\rFinished {total_files} files, {} at {}.\n",
                     humanize(total_bytes),
@@ -70,11 +95,162 @@ pub fn run_output(total_files: usize, total_bytes: u64, recv: Receiver<Progress>
                 out.write_all(msg.as_bytes()).unwrap();
                 out.flush().unwrap();
             }
+            Planned(..) | Failed(_) => {}
+        }
+    }
+}
+
+// One line of run_multi_output
+struct HostLine {
+    name: String,
+    // None until the host has compared
+    total_files: Option<usize>,
+    total_bytes: Option<u64>,
+    files_so_far: usize,
+    bytes_so_far: u64,
+    prev_pct: u64,
+    finished: Option<Duration>,
+    failed: Option<String>,
+}
+
+impl HostLine {
+    fn pct(&self) -> u64 {
+        match (self.total_files, self.total_bytes) {
+            (_, Some(total)) if total > 0 => self.bytes_so_far * 100 / total,
+            (Some(total), _) if total > 0 => (self.files_so_far * 100 / total) as u64,
+            _ => 0,
+        }
+    }
+}
+
+impl fmt::Display for HostLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = &self.name;
+        if let Some(err) = &self.failed {
+            let first_line = err.lines().next().unwrap_or_default();
+            return write!(f, "{name}: failed: {first_line}");
+        }
+        if let Some(took) = self.finished {
+            return write!(
+                f,
+                "{name}: finished {} files, {} in {:.1}s",
+                self.files_so_far,
+                humanize(self.bytes_so_far),
+                took.as_secs_f64()
+            );
+        }
+        match self.total_files {
+            None => write!(f, "{name}: comparing.."),
+            Some(0) => write!(f, "{name}: already identical"),
+            Some(total) => write!(
+                f,
+                "{name}: {} / {total} files, {}%",
+                self.files_so_far,
+                self.pct()
+            ),
+        }
+    }
+}
+
+// Display thread for several hosts, one line each, redrawn in place.
+// recvs is in the same order as names.
+pub fn run_multi_output(names: Vec<String>, recvs: Vec<Receiver<Progress>>) {
+    use Progress::*;
+    let mut lines: Vec<HostLine> = names
+        .into_iter()
+        .map(|name| HostLine {
+            name,
+            total_files: None,
+            total_bytes: None,
+            files_so_far: 0,
+            bytes_so_far: 0,
+            prev_pct: 0,
+            finished: None,
+            failed: None,
+        })
+        .collect();
+    let mut sel = Select::new();
+    for r in recvs.iter() {
+        sel.recv(r);
+    }
+    let mut num_open = recvs.len();
+    let mut is_drawn = false;
+    while num_open > 0 {
+        let oper = sel.select();
+        let idx = oper.index();
+        let line = &mut lines[idx];
+        match oper.recv(&recvs[idx]) {
+            Ok(Planned(files, bytes)) => {
+                line.total_files = Some(files);
+                line.total_bytes = bytes;
+            }
+            Ok(Start(..)) => continue,
+            Ok(Part(bytes)) => {
+                line.bytes_so_far += bytes as u64;
+                // only update display if > 1% progress
+                if line.pct() == line.prev_pct {
+                    continue;
+                }
+                line.prev_pct = line.pct();
+            }
+            Ok(Complete(_)) => line.files_so_far += 1,
+            Ok(Finished(took)) => line.finished = Some(took),
+            Ok(Failed(err)) => line.failed = Some(err),
+            Err(_) => {
+                sel.remove(idx);
+                num_open -= 1;
+                continue;
+            }
+        }
+
+        let mut out = stdout();
+        if is_drawn {
+            write!(out, "\x1B[{}A", lines.len()).unwrap(); // cursor up
+        }
+        for line in lines.iter() {
+            write!(out, "\r{line}\x1B[K\n").unwrap(); // clear to end of line
+        }
+        out.flush().unwrap();
+        is_drawn = true;
+    }
+}
+
+// Final per-host results, after run_multi_output
+pub fn summary_table(rows: &[(String, Result<Report, String>)], is_dry_run: bool) -> String {
+    let width = rows
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0)
+        .max("HOST".len());
+    let mut out = format!(
+        "{:width$}  {:<7}  {:>6}  {:>5}  {:>7}  {:>9}  {:>9}  {:>9}  {:>6}\n",
+        "HOST", "RESULT", "FILES", "CHMOD", "DELETED", "ADDED", "CHANGED", "REMOVED", "TIME"
+    );
+    for (name, result) in rows {
+        match result {
+            Ok(report) => {
+                out += &format!(
+                    "{name:width$}  {:<7}  {:>6}  {:>5}  {:>7}  {:>9}  {:>9}  {:>9}  {:>5.1}s\n",
+                    if is_dry_run { "dry-run" } else { "ok" },
+                    report.num_files,
+                    report.num_chmod,
+                    report.num_deleted,
+                    humanize(report.bytes_added),
+                    humanize(report.bytes_changed),
+                    humanize(report.bytes_removed),
+                    report.took.as_secs_f64(),
+                );
+            }
+            Err(err) => {
+                out += &format!("{name:width$}  FAILED   {err}\n");
+            }
         }
     }
+    out
 }
 
-fn humanize(size: u64) -> String {
+pub fn humanize(size: u64) -> String {
     let fsize = size as f64;
     if fsize > MB {
         format!("{} MiB", (fsize / MB).round())
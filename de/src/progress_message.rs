@@ -1,12 +1,17 @@
 use std::time::Duration;
 
 pub enum Progress {
-    // Start uploading this filename. u64 if file size in bytes.
+    // Compared, about to transfer this many files and bytes.
+    // Bytes is None if we don't know the sizes yet.
+    Planned(usize, Option<u64>),
+    // Start uploading (or downloading) this filename. u64 if file size in bytes.
     Start(String, u64),
     // Uploaded a file block. value should be bytes we uploaded in this block (e.g. 64).
     Part(usize),
     // Uploaded a whole file.
     Complete(String),
-    // Uploaded all the files. value is how long the whole upload took.
+    // Transferred all the files. value is how long the whole transfer took.
     Finished(Duration),
+    // This host failed, with the error message.
+    Failed(String),
 }
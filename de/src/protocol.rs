@@ -0,0 +1,186 @@
+//
+// Part of Demeter Deploy
+// https://github.com/grahamking/demeter-deploy/
+//
+// Decode the remote helper's listing. Must match seed/src/main.rs.
+// All numbers are little endian.
+//
+//   Header: magic "DMTR", u16 version, u8 flags, u8 hash
+//   Record: u8 kind, u8 digest length, u16 name length, u32 mode, u64 size,
+//           u64 mtime, digest, name
+//
+// hash is the --hash we asked for, as seed/src/hash.rs names them.
+// Kinds are 'F' file, 'L' symlink and 'E' end. A file's digest is the hash of
+// its contents, there is none if flags has FLAG_MTIME. A symlink's size and
+// digest are the length and hash of where it points. The end record's size is
+// the number of records before it, so a listing that was cut short is spotted.
+//
+
+use std::error::Error;
+use std::fmt;
+use std::str;
+
+use crate::hash::MAX_DIGEST_LEN;
+
+const MAGIC: &[u8; 4] = b"DMTR";
+pub const VERSION: u16 = 2;
+pub const FLAG_MTIME: u8 = 1;
+const HEADER_LEN: usize = 8;
+const RECORD_LEN: usize = 24; // without the digest and name
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    Link,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record<'a> {
+    pub kind: Kind,
+    pub name: &'a str,
+    // permission bits, 0 for a link
+    pub mode: u32,
+    pub size: u64,
+    // seconds, 0 for a link
+    pub mtime: u64,
+    // empty for a file in mtime mode
+    pub digest: &'a [u8],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    // Doesn't start with the magic. Holds the start of the output, which is
+    // usually a shell error.
+    NotHelper(String),
+    // The helper's protocol version
+    Version(u16),
+    // The header's flags, which aren't what we asked for
+    Flags(u8),
+    // The header's hash, which isn't the one we asked for
+    Hash(u8),
+    // A digest longer than any hash, at this byte offset
+    DigestLen(u8, usize),
+    // Ended part way through a record, or without the end record
+    Truncated,
+    // An unknown record kind, at this byte offset
+    Kind(u8, usize),
+    // A file name that isn't UTF-8, lossily converted
+    Name(String),
+    // The end record's count, and how many records there were
+    Count(u64, u64),
+    // Bytes after the end record
+    TrailingData,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::NotHelper(start) => {
+                write!(f, "Remote helper output is not a listing: {start}")
+            }
+            ProtocolError::Version(v) => write!(
+                f,
+                "Remote helper speaks protocol version {v}, this de speaks version {VERSION}"
+            ),
+            ProtocolError::Flags(flags) => {
+                write!(f, "Remote helper listed in the wrong mode (flags {flags})")
+            }
+            ProtocolError::Hash(hash) => {
+                write!(f, "Remote helper used the wrong hash ('{}')", *hash as char)
+            }
+            ProtocolError::DigestLen(len, offset) => write!(
+                f,
+                "Digest of {len} bytes at byte {offset} of the listing is too long"
+            ),
+            ProtocolError::Truncated => write!(f, "Remote helper listing was cut short"),
+            ProtocolError::Kind(kind, offset) => {
+                write!(
+                    f,
+                    "Unknown record kind {kind} at byte {offset} of the listing"
+                )
+            }
+            ProtocolError::Name(name) => {
+                write!(f, "Remote file name is not valid UTF-8: {name}")
+            }
+            ProtocolError::Count(expected, got) => write!(
+                f,
+                "Remote helper listed {got} files but said there were {expected}"
+            ),
+            ProtocolError::TrailingData => {
+                write!(f, "Remote helper listing has data after the end")
+            }
+        }
+    }
+}
+
+impl Error for ProtocolError {}
+
+// Every entry in a listing. flags and hash are what we asked the helper for.
+pub fn decode(output: &[u8], flags: u8, hash: u8) -> Result<Vec<Record<'_>>, ProtocolError> {
+    decode_header(output, flags, hash)?;
+
+    let mut records = Vec::new();
+    let mut pos = HEADER_LEN;
+    loop {
+        let Some(fixed) = output.get(pos..pos + RECORD_LEN) else {
+            return Err(ProtocolError::Truncated);
+        };
+        let digest_len = fixed[1] as usize;
+        if digest_len > MAX_DIGEST_LEN {
+            return Err(ProtocolError::DigestLen(fixed[1], pos));
+        }
+        let name_len = u16::from_le_bytes([fixed[2], fixed[3]]) as usize;
+        let mode = u32::from_le_bytes(fixed[4..8].try_into().unwrap());
+        let size = u64::from_le_bytes(fixed[8..16].try_into().unwrap());
+        let mtime = u64::from_le_bytes(fixed[16..24].try_into().unwrap());
+        let digest_start = pos + RECORD_LEN;
+        let name_start = digest_start + digest_len;
+        let Some(name) = output.get(name_start..name_start + name_len) else {
+            return Err(ProtocolError::Truncated);
+        };
+        let digest = &output[digest_start..name_start];
+        let kind = match fixed[0] {
+            b'F' => Kind::File,
+            b'L' => Kind::Link,
+            b'E' => {
+                if size != records.len() as u64 {
+                    return Err(ProtocolError::Count(size, records.len() as u64));
+                }
+                if name_start + name_len != output.len() {
+                    return Err(ProtocolError::TrailingData);
+                }
+                return Ok(records);
+            }
+            other => return Err(ProtocolError::Kind(other, pos)),
+        };
+        let name = str::from_utf8(name)
+            .map_err(|_| ProtocolError::Name(String::from_utf8_lossy(name).into_owned()))?;
+        records.push(Record {
+            kind,
+            name,
+            mode,
+            size,
+            mtime,
+            digest,
+        });
+        pos = name_start + name_len;
+    }
+}
+
+fn decode_header(output: &[u8], flags: u8, hash: u8) -> Result<(), ProtocolError> {
+    if output.len() < HEADER_LEN || &output[..4] != MAGIC {
+        let start = String::from_utf8_lossy(&output[..output.len().min(200)]);
+        return Err(ProtocolError::NotHelper(start.trim().to_string()));
+    }
+    let version = u16::from_le_bytes([output[4], output[5]]);
+    if version != VERSION {
+        return Err(ProtocolError::Version(version));
+    }
+    if output[6] != flags {
+        return Err(ProtocolError::Flags(output[6]));
+    }
+    if output[7] != hash {
+        return Err(ProtocolError::Hash(output[7]));
+    }
+    Ok(())
+}
@@ -0,0 +1,171 @@
+//
+// Part of Demeter Deploy
+// https://github.com/grahamking/demeter-deploy/
+//
+// --atomic: each deploy goes into a new directory under releases/, and the
+// `current` symlink is switched to it once it is complete:
+//
+//   /var/www/blog/current -> releases/20261017-142501
+//   /var/www/blog/releases/20261017-142501/
+//   /var/www/blog/releases/20261016-093012/
+//
+// The new release starts as a hard link copy of the current one (cp -al), so
+// only changed files are uploaded. Uploads are renamed into place, replacing
+// the link, so the old release's copy is never written to.
+//
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::bail;
+
+use crate::remote::{shell_quote, Remote};
+
+pub const RELEASES_DIR: &str = "releases";
+pub const CURRENT_LINK: &str = "current";
+
+pub struct Releases {
+    // the remote dir holding `current` and `releases/`, with a trailing slash
+    base: String,
+    // oldest first
+    pub names: Vec<String>,
+    // the release `current` points at
+    pub current: Option<String>,
+}
+
+impl Releases {
+    pub fn load(ssh: &dyn Remote, base: &str) -> anyhow::Result<Releases> {
+        let current = ssh
+            .read_link(&format!("{base}{CURRENT_LINK}"))?
+            .map(|target| {
+                let target = target.trim_end_matches('/');
+                target.rsplit('/').next().unwrap_or(target).to_string()
+            });
+        let cmd = format!(
+            "ls -1 {} 2>/dev/null",
+            shell_quote(&format!("{base}{RELEASES_DIR}"))
+        );
+        let (output, _) = ssh.run_remote_cmd(&cmd)?;
+        let mut names: Vec<String> = output
+            .lines()
+            .filter(|l| !l.is_empty())
+            .map(|l| l.to_string())
+            .collect();
+        names.sort();
+        Ok(Releases {
+            base: base.to_string(),
+            names,
+            current,
+        })
+    }
+
+    // Full path of a release, with a trailing slash
+    pub fn dir(&self, name: &str) -> String {
+        format!("{}{RELEASES_DIR}/{name}/", self.base)
+    }
+
+    // Make the new release directory, as a hard link copy of the current
+    // release if there is one. Returns its path.
+    pub fn create(&self, ssh: &dyn Remote, name: &str) -> anyhow::Result<String> {
+        if self.names.iter().any(|n| n == name) {
+            bail!("Release {name} already exists, try again in a second");
+        }
+        let dir = self.dir(name);
+        ssh.mkdir(&self.base, 0o755)?;
+        ssh.mkdir(&format!("{}{RELEASES_DIR}", self.base), 0o755)?;
+        let new = shell_quote(&dir);
+        let cmd = match &self.current {
+            // If hard links aren't possible do a full copy. It must start from
+            // empty, never copy over links to the old release.
+            Some(current) => {
+                let cur = shell_quote(&format!("{}.", self.dir(current)));
+                format!(
+                    "mkdir {new} && (cp -al {cur} {new} || (rm -rf {new} && mkdir {new} && cp -a {cur} {new}))"
+                )
+            }
+            None => format!("mkdir {new}"),
+        };
+        let (output, exit_status) = ssh.run_remote_cmd(&cmd)?;
+        if exit_status != 0 {
+            bail!("Creating release {dir} failed ({exit_status}): {output}");
+        }
+        Ok(dir)
+    }
+
+    // Point `current` at this release. A new link is made next to it and
+    // renamed over it, so there is no moment without a `current`.
+    pub fn switch(&self, ssh: &dyn Remote, name: &str) -> anyhow::Result<()> {
+        let link = format!("{}{CURRENT_LINK}", self.base);
+        let tmp_link = format!("{link}.de-new");
+        // left over from a failed switch
+        let _ = ssh.delete(&tmp_link);
+        ssh.symlink(&format!("{RELEASES_DIR}/{name}"), &tmp_link)?;
+        ssh.rename(&tmp_link, &link)
+    }
+
+    // Delete the oldest releases so there are `keep` left, not counting ones
+    // newer than `new`. Returns the ones it deleted.
+    pub fn prune(&self, ssh: &dyn Remote, new: &str, keep: usize) -> anyhow::Result<Vec<String>> {
+        let mut names = self.names.clone();
+        names.push(new.to_string());
+        let old = to_prune(&names, new, keep);
+        for name in old.iter() {
+            let cmd = format!("rm -rf {}", shell_quote(&self.dir(name)));
+            let (output, exit_status) = ssh.run_remote_cmd(&cmd)?;
+            if exit_status != 0 {
+                bail!("Deleting old release {name} failed ({exit_status}): {output}");
+            }
+        }
+        Ok(old)
+    }
+}
+
+// Releases to delete to keep the newest `keep`. Never the current one.
+// names must be sorted, oldest first.
+pub fn to_prune(names: &[String], current: &str, keep: usize) -> Vec<String> {
+    let Some(current_idx) = names.iter().position(|n| n == current) else {
+        return Vec::new();
+    };
+    let older = &names[..current_idx];
+    let num_keep_older = keep.saturating_sub(1);
+    if older.len() <= num_keep_older {
+        return Vec::new();
+    }
+    older[..older.len() - num_keep_older].to_vec()
+}
+
+// The release before current, for rollback
+pub fn previous<'a>(names: &'a [String], current: &str) -> Option<&'a str> {
+    let current_idx = names.iter().position(|n| n == current)?;
+    if current_idx == 0 {
+        return None;
+    }
+    Some(names[current_idx - 1].as_str())
+}
+
+// UTC YYYYMMDD-HHMMSS, so names sort by date
+pub fn release_name(now: SystemTime) -> String {
+    let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{year:04}{month:02}{day:02}-{:02}{:02}{:02}",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+// Days since 1970-01-01 to (year, month, day).
+// Howard Hinnant's algorithm, http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
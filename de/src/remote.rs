@@ -1,7 +1,68 @@
 pub trait Remote {
-    fn run_remote_cmd(&self, cmd: &str) -> anyhow::Result<(String, i32)>;
+    // Run a shell command, returning its stdout and exit status
+    fn run_remote_cmd_bytes(&self, cmd: &str) -> anyhow::Result<(Vec<u8>, i32)>;
+    // run_remote_cmd_bytes for commands that output text
+    fn run_remote_cmd(&self, cmd: &str) -> anyhow::Result<(String, i32)> {
+        let (output, exit_status) = self.run_remote_cmd_bytes(cmd)?;
+        Ok((String::from_utf8_lossy(&output).into_owned(), exit_status))
+    }
     fn mkdir(&self, dir: &str, perms: u32) -> anyhow::Result<()>;
     fn upload(&self, src: &str, dst: &str) -> anyhow::Result<()>;
     fn upload_bytes(&self, src_bytes: &[u8], dst: &str) -> anyhow::Result<()>;
+    fn download(&self, src: &str, dst: &str) -> anyhow::Result<()>;
     fn delete(&self, path: &str) -> anyhow::Result<()>;
+    // Set the permission bits
+    fn chmod(&self, path: &str, mode: u32) -> anyhow::Result<()>;
+    // Remove an empty directory
+    fn rmdir(&self, dir: &str) -> anyhow::Result<()>;
+    // Create symlink `link` pointing at `target`
+    fn symlink(&self, target: &str, link: &str) -> anyhow::Result<()>;
+    // Rename, replacing `to` if it exists
+    fn rename(&self, from: &str, to: &str) -> anyhow::Result<()>;
+    // Where a symlink points, None if it doesn't exist
+    fn read_link(&self, path: &str) -> anyhow::Result<Option<String>>;
+    // (owner uid, mode including the file type bits) of the path itself, not
+    // what it links to. None if it doesn't exist. The uid is None if the
+    // server didn't send it.
+    fn lstat(&self, path: &str) -> anyhow::Result<Option<(Option<u32>, u32)>>;
+    // (mode including the file type bits, size, mtime in seconds) of what the
+    // path links to. None if that doesn't exist.
+    fn stat(&self, path: &str) -> anyhow::Result<Option<(u32, u64, u64)>>;
+    // The whole of a small remote file
+    fn read_file(&self, path: &str) -> anyhow::Result<Vec<u8>>;
+    // The entries of a directory, including . and ..
+    fn read_dir(&self, dir: &str) -> anyhow::Result<Vec<DirEntry>>;
+}
+
+// Single quote for the remote shell, for anything put into a command for
+// run_remote_cmd
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+// One entry from read_dir. Links are not followed.
+pub struct DirEntry {
+    pub name: String,
+    // including the file type bits
+    pub mode: u32,
+    pub size: u64,
+    // seconds
+    pub mtime: u64,
+}
+
+// Uploads are written to this name in the same directory, then renamed
+// into place
+const TMP_PREFIX: &str = ".de-tmp.";
+
+pub fn tmp_path(dst: &str) -> String {
+    match dst.rsplit_once('/') {
+        Some((dir, name)) => format!("{dir}/{TMP_PREFIX}{name}"),
+        None => format!("{TMP_PREFIX}{dst}"),
+    }
+}
+
+// A temp file left behind by an upload that didn't finish
+pub fn is_tmp_path(path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    name.starts_with(TMP_PREFIX)
 }
@@ -2,21 +2,30 @@
 #![allow(non_camel_case_types)]
 #![allow(clippy::upper_case_acronyms)]
 
+use std::env;
 use std::ffi::{CStr, CString};
 use std::fs;
-use std::io::Read;
+use std::io::{Read, Write};
 use std::os::raw::{c_char, c_int, c_uint, c_void};
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::IntoRawFd;
+use std::ptr;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::bail;
 use crossbeam_channel::Sender;
 
+use crate::auth::{Auth, AuthMethod};
+use crate::host_key::{self, HostKeyPolicy};
+use crate::jump::JumpHost;
 use crate::progress_message::Progress;
-use crate::remote::Remote;
+use crate::remote::{self, DirEntry, Remote};
+use crate::target::Target;
 
 // These are in libc crate, but no dependencies is nice
+const O_RDONLY: c_uint = 0;
 const O_WRONLY: c_uint = 1;
 const O_CREAT: c_uint = 0o100;
 const O_TRUNC: c_uint = 0o1000;
@@ -35,6 +44,20 @@ pub struct SSH {
     sftp_session: SFTP, // comes first because must be dropped before 'session'
     session: SSHSessionWrap,
     progress: Sender<Progress>,
+    // "SHA256:..." of the server's host key
+    host_key_fingerprint: String,
+}
+
+// Everything needed to open a session.
+// Shared by the primary connection and the upload workers.
+#[derive(Clone)]
+pub struct ConnectOptions {
+    pub target: Target,
+    pub auth: Auth,
+    pub host_key: HostKeyPolicy,
+    pub log_level: LogLevel,
+    // tunnel through this bastion, from -J or the ssh config's ProxyJump
+    pub jump: Option<JumpHost>,
 }
 
 impl SSH {
@@ -46,53 +69,32 @@ impl SSH {
             .to_string()
     }
 
-    // connect and authenticate
-    pub fn new(
-        host: &str,
-        username: &str,
-        log_level: LogLevel,
-        progress: Sender<Progress>,
-    ) -> anyhow::Result<SSH> {
-        let host = CString::new(host)?;
-        let username = CString::new(username)?;
-
-        let ret = unsafe { ssh_set_log_level(log_level) };
-        if !matches!(ret, SSHResult::OK) {
-            bail!("set_log_level error");
-        }
-
-        let session = unsafe { ssh_new() };
-        if session.is_null() {
-            bail!("ssh_new retuned null");
-        }
-        unsafe {
-            ssh_options_set(session, SSHOption::HOST, host.as_ptr() as *const c_void);
-            ssh_options_set(session, SSHOption::PORT, &22 as *const _ as _);
-        }
-        let connect_ret = unsafe { ssh_connect(session) };
-        if !matches!(connect_ret, SSHResult::OK) {
-            let err_msg = unsafe { CStr::from_ptr(ssh_get_error(session)) };
-            bail!("Connect ERR: {}", err_msg.to_string_lossy());
-        }
+    // connect, check the host key, and authenticate
+    //
+    // target.host can be an alias from ~/.ssh/config. The config's HostName, Port,
+    // User and IdentityFile are used, unless the target gives a user or port.
+    pub fn new(opts: &ConnectOptions, progress: Sender<Progress>) -> anyhow::Result<SSH> {
+        let target = &opts.target;
+        let session = SSHSessionWrap::connect(target, opts.log_level, opts.jump.as_ref())?;
 
-        let is_know = unsafe { ssh_session_is_known_server(session) };
-        if !matches!(is_know, SSHKnownHostsResult::HOSTS_OK) {
-            bail!("Unknown host: {is_know:?}. ssh to it manually first to accept key");
-        }
+        let host_key_fingerprint = session.verify_host_key(&opts.host_key, &target.host)?;
 
-        let auth_ret = unsafe { ssh_userauth_agent(session, username.as_ptr()) };
-        if !matches!(auth_ret, SSHAuthResult::SUCCESS) {
-            bail!("auth err or incomplete: {auth_ret:?}. Is ssh-agent running?");
-        }
+        session.authenticate(&opts.auth, &target.host_display())?;
 
-        let sftp_session = SSH::create_sftp(session)?;
+        let sftp_session = SSH::create_sftp(session.0)?;
         Ok(SSH {
-            session: SSHSessionWrap(session),
+            session,
             sftp_session,
             progress,
+            host_key_fingerprint,
         })
     }
 
+    // The server's host key, as "SHA256:..."
+    pub fn host_key_fingerprint(&self) -> &str {
+        &self.host_key_fingerprint
+    }
+
     fn create_sftp(session: *mut c_void) -> anyhow::Result<SFTP> {
         let sftp_session = unsafe { sftp_new(session) };
         if sftp_session.is_null() {
@@ -106,6 +108,8 @@ impl SSH {
         }
         Ok(SFTP {
             session: sftp_session,
+            is_fsync: sftp_has_extension(sftp_session, "fsync@openssh.com"),
+            is_posix_rename: sftp_has_extension(sftp_session, "posix-rename@openssh.com"),
         })
     }
 
@@ -123,10 +127,106 @@ impl SSH {
             sftp_err_num
         )
     }
+
+    // Copy all of lfile into rfile. Returns the number of bytes written.
+    fn write_from(
+        &self,
+        lfile: &mut fs::File,
+        rfile: &SFTPFile,
+        dst: &str,
+    ) -> anyhow::Result<usize> {
+        let mut buf = [0u8; SFTP_CHUNK_SIZE];
+        let mut total_bytes = 0;
+        loop {
+            let bytes_read = lfile.read(&mut buf)?;
+            if bytes_read == 0 {
+                // done
+                break;
+            }
+            let ret = rfile.write(&buf[0..bytes_read]);
+            if ret < 0 {
+                return Err(self.get_sftp_err(&format!("upload to {}", dst)));
+            }
+            let bytes_written = ret as usize;
+            if bytes_written != bytes_read {
+                bail!("Short write: {bytes_written} / {bytes_read}");
+            }
+            total_bytes += bytes_written;
+            let _ = self.progress.send(Progress::Part(bytes_written));
+        }
+        Ok(total_bytes)
+    }
+
+    // Copy all of rfile into a new local file at path, which should be size
+    // bytes. Returns the local file.
+    fn read_into(
+        &self,
+        rfile: &SFTPFile,
+        path: &str,
+        src: &str,
+        size: u64,
+    ) -> anyhow::Result<fs::File> {
+        let mut lfile = fs::File::create(path)?;
+        let mut buf = [0u8; SFTP_CHUNK_SIZE];
+        let mut total_bytes = 0;
+        loop {
+            let ret = rfile.read(&mut buf);
+            if ret < 0 {
+                return Err(self.get_sftp_err(&format!("download from {}", src)));
+            }
+            if ret == 0 {
+                // done
+                break;
+            }
+            let bytes_read = ret as usize;
+            lfile.write_all(&buf[..bytes_read])?;
+            total_bytes += bytes_read as u64;
+            let _ = self.progress.send(Progress::Part(bytes_read));
+        }
+        if total_bytes != size {
+            bail!("Short read: remote is {size} bytes, downloaded {total_bytes} bytes");
+        }
+        Ok(lfile)
+    }
+
+    // Set the remote file's access and modification times to the local file's
+    fn set_times(&self, path: &str, local: &fs::Metadata) -> anyhow::Result<()> {
+        let c_path = CString::new(path)?;
+        let times = [timeval(local.accessed()?), timeval(local.modified()?)];
+        let ret =
+            unsafe { sftp_utimes(self.sftp_session.session, c_path.as_ptr(), times.as_ptr()) };
+        if ret != SSH_OK {
+            return Err(self.get_sftp_err(&format!("utimes {path}")));
+        }
+        Ok(())
+    }
+
+    // Move a fully written temp file over dst. If writing it failed the temp
+    // file is removed instead, and dst is left as it was.
+    fn put_in_place(
+        &self,
+        rfile: SFTPFile,
+        tmp: &str,
+        dst: &str,
+        written: anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let written = written.and_then(|_| {
+            if self.sftp_session.is_fsync && rfile.fsync() != SSH_OK {
+                return Err(self.get_sftp_err(&format!("fsync {tmp}")));
+            }
+            Ok(())
+        });
+        drop(rfile); // close it
+        if let Err(err) = written.and_then(|_| self.rename(tmp, dst)) {
+            let _ = self.delete(tmp);
+            return Err(err);
+        }
+        Ok(())
+    }
 }
 
 impl Remote for SSH {
-    fn run_remote_cmd(&self, cmd: &str) -> anyhow::Result<(String, i32)> {
+    fn run_remote_cmd_bytes(&self, cmd: &str) -> anyhow::Result<(Vec<u8>, i32)> {
         let channel = unsafe { ssh_channel_new(self.session.0) };
         if channel.is_null() {
             bail!("channel is null");
@@ -145,8 +245,12 @@ impl Remote for SSH {
             let err_msg = unsafe { CStr::from_ptr(ssh_get_error(self.session.0)) };
             bail!("ssh_channel_request_exec err {}", err_msg.to_string_lossy());
         }
+        // Nothing goes to its stdin. Say so now, not after reading, or a
+        // command that reads stdin never finishes, such as the sftp-server an
+        // account with ForceCommand internal-sftp runs in place of ours.
+        unsafe { ssh_channel_send_eof(channel) };
 
-        let mut output = String::new();
+        let mut output = Vec::new();
         let mut buffer = Vec::with_capacity(SSH_CMD_BUF_SIZE);
         // if there is a remote error this read closes the channel
         let mut nbytes =
@@ -158,7 +262,7 @@ impl Remote for SSH {
         }
         while nbytes > 0 {
             unsafe { buffer.set_len(nbytes as usize) };
-            output += &String::from_utf8_lossy(&buffer);
+            output.extend_from_slice(&buffer);
 
             buffer.clear();
             nbytes = unsafe {
@@ -167,7 +271,6 @@ impl Remote for SSH {
         }
 
         let exit_status = unsafe {
-            ssh_channel_send_eof(channel);
             ssh_channel_close(channel);
             // wait for remote to close
             while ssh_channel_is_closed(channel) != 1 {
@@ -187,61 +290,88 @@ impl Remote for SSH {
     //
     // src: local full path of filename to upload
     // dst: remote full path of destination file to create or overwrite
+    //
+    // The file is written to a temp name next to dst and renamed over it once
+    // complete, so dst is never seen half written.
     fn upload(&self, src: &str, dst: &str) -> anyhow::Result<()> {
         let stat = fs::metadata(src)?;
         let _ = self
             .progress
             .send(Progress::Start(src.to_string(), stat.len()));
         let perms = stat.permissions().mode();
-        let mut buf = [0u8; SFTP_CHUNK_SIZE];
+        let mut lfile = fs::File::open(src)?;
+        let tmp = remote::tmp_path(dst);
         let rfile = self
             .sftp_session
-            .open(dst, O_WRONLY | O_CREAT | O_TRUNC, perms)?;
-        let mut lfile = fs::File::open(src)?;
+            .open(&tmp, O_WRONLY | O_CREAT | O_TRUNC, perms)?;
 
-        let mut total_bytes = 0;
-        loop {
-            let bytes_read = lfile.read(&mut buf)?;
-            if bytes_read == 0 {
-                // done
-                break;
-            }
-            let ret = rfile.write(&buf[0..bytes_read]);
-            if ret < 0 {
-                return Err(self.get_sftp_err(&format!("upload to {}", dst)));
-            }
-            let bytes_written = ret as usize;
-            if bytes_written != bytes_read {
-                bail!("Short write: {bytes_written} / {bytes_read}");
-            }
-            total_bytes += bytes_written;
-            let _ = self.progress.send(Progress::Part(bytes_written));
-        }
-        if total_bytes != stat.len() as usize {
-            eprintln!(
-                "ERR uploading {}->{}. Local is {} bytes, uploaded {} bytes.",
-                src,
-                dst,
-                stat.len(),
-                total_bytes
-            );
-        }
+        // A different size means the local file changed while we read it.
+        // dst mustn't get part of it, put_in_place deletes the temp file.
+        let written = self
+            .write_from(&mut lfile, &rfile, dst)
+            .and_then(|total_bytes| {
+                if total_bytes != stat.len() as usize {
+                    bail!(
+                        "Short upload {src}->{dst}: local is {} bytes, uploaded {total_bytes} bytes",
+                        stat.len()
+                    );
+                }
+                Ok(())
+            });
+        // The server's umask applied when it was created, so set the
+        // permissions again. And the same times as the local file, so
+        // Last-Modified and --compare=mtime-size work.
+        let written = written
+            .and_then(|_| self.chmod(&tmp, perms & 0o7777))
+            .and_then(|_| self.set_times(&tmp, &stat));
+        self.put_in_place(rfile, &tmp, dst, written)?;
         let _ = self.progress.send(Progress::Complete(src.to_string()));
         Ok(())
     }
 
+    // Also via a temp file, which means a running copy of the helper can be
+    // replaced
     fn upload_bytes(&self, src_bytes: &[u8], dst: &str) -> anyhow::Result<()> {
+        let tmp = remote::tmp_path(dst);
         let rfile = self
             .sftp_session
-            .open(dst, O_WRONLY | O_CREAT | O_TRUNC, 0o700)?;
-        let ret = rfile.write(src_bytes);
-        if ret < 0 {
-            return Err(self.get_sftp_err(&format!("upload_bytes to {}", dst)));
-        }
-        let bytes_written = ret as usize;
-        if bytes_written != src_bytes.len() {
-            bail!("Short write: {bytes_written} / {}", src_bytes.len());
+            .open(&tmp, O_WRONLY | O_CREAT | O_TRUNC, 0o700)?;
+        let written = match rfile.write(src_bytes) {
+            ret if ret < 0 => Err(self.get_sftp_err(&format!("upload_bytes to {}", dst))),
+            ret if ret as usize != src_bytes.len() => {
+                Err(anyhow::anyhow!("Short write: {ret} / {}", src_bytes.len()))
+            }
+            _ => Ok(()),
+        };
+        self.put_in_place(rfile, &tmp, dst, written)
+    }
+
+    // Download a remote file
+    //
+    // src: remote full path of file to download
+    // dst: local full path of destination file to create or overwrite.
+    //      Its directory must already exist.
+    //
+    // Like upload, written to a temp name next to dst and renamed over it once
+    // complete, so a failed download never leaves dst half written.
+    fn download(&self, src: &str, dst: &str) -> anyhow::Result<()> {
+        let rfile = self.sftp_session.open(src, O_RDONLY, 0)?;
+        let (size, perms, mtime) = rfile
+            .stat()
+            .ok_or_else(|| self.get_sftp_err(&format!("stat {src}")))?;
+        let _ = self.progress.send(Progress::Start(src.to_string(), size));
+
+        let tmp = remote::tmp_path(dst);
+        let written = self.read_into(&rfile, &tmp, src, size).and_then(|lfile| {
+            lfile.set_modified(UNIX_EPOCH + Duration::from_secs(mtime as u64))?;
+            fs::set_permissions(&tmp, fs::Permissions::from_mode(perms & 0o7777))?;
+            Ok(())
+        });
+        if let Err(err) = written.and_then(|_| Ok(fs::rename(&tmp, dst)?)) {
+            let _ = fs::remove_file(&tmp);
+            return Err(err.context(format!("download {src} -> {dst}")));
         }
+        let _ = self.progress.send(Progress::Complete(src.to_string()));
         Ok(())
     }
 
@@ -272,11 +402,634 @@ impl Remote for SSH {
         }
         Ok(())
     }
+
+    fn chmod(&self, path: &str, mode: u32) -> anyhow::Result<()> {
+        let c_path = CString::new(path)?;
+        let ret = unsafe { sftp_chmod(self.sftp_session.session, c_path.as_ptr(), mode) };
+        if ret != SSH_OK {
+            return Err(self.get_sftp_err(&format!("chmod {path}")));
+        }
+        Ok(())
+    }
+
+    fn rmdir(&self, dir: &str) -> anyhow::Result<()> {
+        let c_dir = CString::new(dir)?;
+        let ret = unsafe { sftp_rmdir(self.sftp_session.session, c_dir.as_ptr()) };
+        if ret != SSH_OK {
+            return Err(self.get_sftp_err(&format!("rmdir {dir}")));
+        }
+        Ok(())
+    }
+
+    fn symlink(&self, target: &str, link: &str) -> anyhow::Result<()> {
+        let c_target = CString::new(target)?;
+        let c_link = CString::new(link)?;
+        // libssh swaps the arguments for OpenSSH servers, which have them backwards
+        let ret = unsafe {
+            sftp_symlink(
+                self.sftp_session.session,
+                c_target.as_ptr(),
+                c_link.as_ptr(),
+            )
+        };
+        if ret != SSH_OK {
+            return Err(self.get_sftp_err(&format!("symlink {link} -> {target}")));
+        }
+        Ok(())
+    }
+
+    // libssh uses posix-rename@openssh.com if the server has it, which
+    // replaces `to` atomically. A server without it only has the plain SFTP
+    // rename, which refuses to replace an existing file. Only then, and only
+    // for that refusal, is `to` deleted and the rename tried again.
+    fn rename(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        let c_from = CString::new(from)?;
+        let c_to = CString::new(to)?;
+        let do_rename =
+            || unsafe { sftp_rename(self.sftp_session.session, c_from.as_ptr(), c_to.as_ptr()) };
+        if do_rename() == SSH_OK {
+            return Ok(());
+        }
+        let sftp_err_num = unsafe { sftp_get_error(self.sftp_session.session) };
+        let err = self.get_sftp_err(&format!("rename {from} -> {to}"));
+        // OpenSSH says FAILURE, version 5 and later servers FILE_ALREADY_EXISTS
+        let is_exists = matches!(
+            sftp_err_num,
+            SFTPError::SSH_FX_FAILURE | SFTPError::SSH_FX_FILE_ALREADY_EXISTS
+        );
+        if self.sftp_session.is_posix_rename || !is_exists || !matches!(self.lstat(to), Ok(Some(_)))
+        {
+            return Err(err);
+        }
+        self.delete(to)?;
+        if do_rename() != SSH_OK {
+            return Err(self.get_sftp_err(&format!("rename {from} -> {to}, after deleting {to}")));
+        }
+        Ok(())
+    }
+
+    fn read_link(&self, path: &str) -> anyhow::Result<Option<String>> {
+        let c_path = CString::new(path)?;
+        let target = unsafe { sftp_readlink(self.sftp_session.session, c_path.as_ptr()) };
+        if target.is_null() {
+            let sftp_err_num = unsafe { sftp_get_error(self.sftp_session.session) };
+            if sftp_err_num == SFTPError::SSH_FX_NO_SUCH_FILE {
+                return Ok(None);
+            }
+            return Err(self.get_sftp_err(&format!("readlink {path}")));
+        }
+        let out = unsafe { CStr::from_ptr(target) }
+            .to_string_lossy()
+            .into_owned();
+        unsafe { ssh_string_free_char(target) };
+        Ok(Some(out))
+    }
+
+    fn lstat(&self, path: &str) -> anyhow::Result<Option<(Option<u32>, u32)>> {
+        let c_path = CString::new(path)?;
+        let attrs = unsafe { sftp_lstat(self.sftp_session.session, c_path.as_ptr()) };
+        if attrs.is_null() {
+            let sftp_err_num = unsafe { sftp_get_error(self.sftp_session.session) };
+            if sftp_err_num == SFTPError::SSH_FX_NO_SUCH_FILE {
+                return Ok(None);
+            }
+            return Err(self.get_sftp_err(&format!("lstat {path}")));
+        }
+        let out = unsafe {
+            let is_uid = (*attrs).flags & SSH_FILEXFER_ATTR_UIDGID != 0;
+            (is_uid.then_some((*attrs).uid), (*attrs).permissions)
+        };
+        unsafe { sftp_attributes_free(attrs) };
+        Ok(Some(out))
+    }
+
+    fn stat(&self, path: &str) -> anyhow::Result<Option<(u32, u64, u64)>> {
+        let c_path = CString::new(path)?;
+        let attrs = unsafe { sftp_stat(self.sftp_session.session, c_path.as_ptr()) };
+        if attrs.is_null() {
+            let sftp_err_num = unsafe { sftp_get_error(self.sftp_session.session) };
+            if sftp_err_num == SFTPError::SSH_FX_NO_SUCH_FILE {
+                return Ok(None);
+            }
+            return Err(self.get_sftp_err(&format!("stat {path}")));
+        }
+        let out = unsafe { ((*attrs).permissions, (*attrs).size, (*attrs).mtime as u64) };
+        unsafe { sftp_attributes_free(attrs) };
+        Ok(Some(out))
+    }
+
+    fn read_file(&self, path: &str) -> anyhow::Result<Vec<u8>> {
+        let rfile = self.sftp_session.open(path, O_RDONLY, 0)?;
+        let mut out = Vec::new();
+        let mut buf = [0u8; SFTP_CHUNK_SIZE];
+        loop {
+            let ret = rfile.read(&mut buf);
+            if ret < 0 {
+                return Err(self.get_sftp_err(&format!("read {path}")));
+            }
+            if ret == 0 {
+                break;
+            }
+            out.extend_from_slice(&buf[..ret as usize]);
+        }
+        Ok(out)
+    }
+
+    fn read_dir(&self, dir: &str) -> anyhow::Result<Vec<DirEntry>> {
+        let c_dir = CString::new(dir)?;
+        let handle = unsafe { sftp_opendir(self.sftp_session.session, c_dir.as_ptr()) };
+        if handle.is_null() {
+            return Err(self.get_sftp_err(&format!("opendir {dir}")));
+        }
+        let mut out = Vec::new();
+        loop {
+            let attrs = unsafe { sftp_readdir(self.sftp_session.session, handle) };
+            if attrs.is_null() {
+                break;
+            }
+            unsafe {
+                out.push(DirEntry {
+                    name: CStr::from_ptr((*attrs).name).to_string_lossy().into_owned(),
+                    mode: (*attrs).permissions,
+                    size: (*attrs).size,
+                    mtime: (*attrs).mtime as u64,
+                });
+                sftp_attributes_free(attrs);
+            }
+        }
+        // null is the end of the directory, or an error
+        let is_eof = unsafe { sftp_dir_eof(handle) } == 1;
+        unsafe { sftp_closedir(handle) };
+        if !is_eof {
+            return Err(self.get_sftp_err(&format!("readdir {dir}")));
+        }
+        Ok(out)
+    }
+}
+
+// A logged in session to a jump host, used only to open tunnels.
+// Not thread safe, JumpHost keeps it on one thread.
+pub struct Bastion {
+    session: SSHSessionWrap,
+}
+
+impl Bastion {
+    pub fn new(
+        jump: &Target,
+        auth: &Auth,
+        host_key: &HostKeyPolicy,
+        log_level: LogLevel,
+    ) -> anyhow::Result<Bastion> {
+        let session = SSHSessionWrap::connect(jump, log_level, None)?;
+        session.verify_host_key(host_key, &jump.host)?;
+        session.authenticate(auth, &jump.host_display())?;
+        Ok(Bastion { session })
+    }
+
+    // Ask the bastion to connect to host:port for us (a direct-tcpip channel)
+    pub fn open_forward(&self, host: &str, port: u16) -> anyhow::Result<ForwardChannel> {
+        let channel = unsafe { ssh_channel_new(self.session.0) };
+        if channel.is_null() {
+            bail!("channel is null");
+        }
+        let channel = ForwardChannel(channel);
+        let host_c = CString::new(host)?;
+        let localhost = CString::new("127.0.0.1").unwrap();
+        let ret = unsafe {
+            ssh_channel_open_forward(
+                channel.0,
+                host_c.as_ptr(),
+                port as c_int,
+                localhost.as_ptr(),
+                0,
+            )
+        };
+        if ret != SSH_OK {
+            bail!(
+                "Jump host could not connect to {host}:{port}: {}",
+                self.session.error()
+            );
+        }
+        Ok(channel)
+    }
+}
+
+pub struct ForwardChannel(SSHChannel);
+
+impl ForwardChannel {
+    // Ok(None) at end of file, Ok(Some(0)) if there is nothing to read yet
+    pub fn read_nonblocking(&self, buf: &mut [u8]) -> anyhow::Result<Option<usize>> {
+        let n =
+            unsafe { ssh_channel_read_nonblocking(self.0, buf.as_mut_ptr(), buf.len() as u32, 0) };
+        match n {
+            SSH_EOF => Ok(None),
+            0 if unsafe { ssh_channel_is_eof(self.0) } != 0 => Ok(None),
+            n if n < 0 => bail!("ssh_channel_read_nonblocking error"),
+            n => Ok(Some(n as usize)),
+        }
+    }
+
+    pub fn write_all(&self, data: &[u8]) -> anyhow::Result<()> {
+        let mut done = 0;
+        while done < data.len() {
+            let n = unsafe {
+                ssh_channel_write(self.0, data[done..].as_ptr(), (data.len() - done) as u32)
+            };
+            if n < 0 {
+                bail!("ssh_channel_write error");
+            }
+            done += n as usize;
+        }
+        Ok(())
+    }
+
+    pub fn send_eof(&self) {
+        unsafe { ssh_channel_send_eof(self.0) };
+    }
+}
+
+impl Drop for ForwardChannel {
+    fn drop(&mut self) {
+        unsafe {
+            ssh_channel_close(self.0);
+            ssh_channel_free(self.0);
+        }
+    }
 }
 
 // Wrap the pointer so we can implement Drop
 struct SSHSessionWrap(*mut c_void);
 
+impl SSHSessionWrap {
+    // New session, connected but not yet verified or authenticated.
+    // With a jump host the connection is a tunnel through it, otherwise libssh
+    // connects directly, or with the ssh config's ProxyCommand.
+    fn connect(
+        target: &Target,
+        log_level: LogLevel,
+        jump: Option<&JumpHost>,
+    ) -> anyhow::Result<SSHSessionWrap> {
+        let host = CString::new(target.host.as_str())?;
+        let username = target.user.as_deref().map(CString::new).transpose()?;
+
+        let ret = unsafe { ssh_set_log_level(log_level) };
+        if !matches!(ret, SSHResult::OK) {
+            bail!("set_log_level error");
+        }
+
+        let session = unsafe { ssh_new() };
+        if session.is_null() {
+            bail!("ssh_new retuned null");
+        }
+        // wrap it now so it gets freed if we bail
+        let session = SSHSessionWrap(session);
+        unsafe {
+            ssh_options_set(session.0, SSHOption::HOST, host.as_ptr() as *const c_void);
+        }
+
+        // ~/.ssh/config then /etc/ssh/ssh_config. Needs HOST set first, and it
+        // overwrites options already set, so anything explicit goes after.
+        let parse_ret = unsafe { ssh_options_parse_config(session.0, ptr::null()) };
+        if parse_ret != 0 {
+            bail!("Error reading ssh config: {}", session.error());
+        }
+        if let Some(username) = &username {
+            unsafe {
+                ssh_options_set(
+                    session.0,
+                    SSHOption::USER,
+                    username.as_ptr() as *const c_void,
+                );
+            }
+        }
+        if let Some(port) = target.port {
+            let port = port as c_uint;
+            unsafe {
+                ssh_options_set(session.0, SSHOption::PORT, &port as *const _ as _);
+            }
+        }
+
+        if let Some(jump) = jump {
+            // the config may have changed both, e.g. HostName
+            let mut port: c_uint = 0;
+            unsafe { ssh_options_get_port(session.0, &mut port) };
+            let tunnel = jump.open_tunnel(&session.option(SSHOption::HOST), port as u16)?;
+            // Don't let libssh start its own proxy. ProxyJump is only an
+            // option in newer libssh, older ones ignore it.
+            let none = CString::new("none").unwrap();
+            let fd: c_int = tunnel.into_raw_fd(); // libssh closes it
+            unsafe {
+                ssh_options_set(session.0, SSHOption::PROXYCOMMAND, none.as_ptr() as _);
+                ssh_options_set(session.0, SSHOption::PROXYJUMP, none.as_ptr() as _);
+                ssh_options_set(session.0, SSHOption::FD, &fd as *const _ as _);
+            }
+        }
+
+        let connect_ret = unsafe { ssh_connect(session.0) };
+        if !matches!(connect_ret, SSHResult::OK) {
+            bail!("Connect ERR: {}", session.error());
+        }
+        Ok(session)
+    }
+
+    // A string option as it is after reading the ssh config, "" if not set
+    fn option(&self, opt: SSHOption) -> String {
+        let mut value: *mut c_char = ptr::null_mut();
+        let ret = unsafe { ssh_options_get(self.0, opt, &mut value) };
+        if ret != 0 || value.is_null() {
+            return String::new();
+        }
+        let out = unsafe { CStr::from_ptr(value) }
+            .to_string_lossy()
+            .into_owned();
+        unsafe { ssh_string_free_char(value) };
+        out
+    }
+
+    // The user we log in as, after reading the ssh config
+    fn username(&self) -> String {
+        let username = self.option(SSHOption::USER);
+        if username.is_empty() {
+            return "?".to_string();
+        }
+        username
+    }
+
+    // Check the server's host key against known_hosts, or the pinned fingerprints.
+    // Unknown keys are added to known_hosts if the policy or the user allows.
+    // Returns the key's fingerprint.
+    fn verify_host_key(&self, policy: &HostKeyPolicy, host: &str) -> anyhow::Result<String> {
+        let mut key: SSHKey = ptr::null_mut();
+        if unsafe { ssh_get_server_publickey(self.0, &mut key) } != SSH_OK {
+            bail!("Could not get server host key: {}", self.error());
+        }
+        let key_type = key_type_name(key);
+        let fingerprint = key_fingerprint(key);
+        unsafe { ssh_key_free(key) };
+        let fingerprint = fingerprint?;
+
+        if let HostKeyPolicy::Fingerprint(pins) = policy {
+            if pins.contains(&fingerprint) {
+                return Ok(fingerprint);
+            }
+            bail!(
+                "Host key for '{host}' is {key_type} {fingerprint}, \
+                 which is not one of the --host-key-fingerprint values. Refusing to connect."
+            );
+        }
+
+        match unsafe { ssh_session_is_known_server(self.0) } {
+            SSHKnownHostsResult::HOSTS_OK => {}
+            SSHKnownHostsResult::HOSTS_CHANGED | SSHKnownHostsResult::HOSTS_OTHER => {
+                let msg = host_key::changed_warning(
+                    host,
+                    &key_type,
+                    &fingerprint,
+                    self.known_hosts_entry(),
+                );
+                bail!("{msg}");
+            }
+            SSHKnownHostsResult::HOSTS_UNKNOWN | SSHKnownHostsResult::SSH_KNOWN_HOSTS_NOT_FOUND => {
+                if *policy == HostKeyPolicy::Ask
+                    && !host_key::ask_trust(host, &key_type, &fingerprint)?
+                {
+                    bail!("Host key for '{host}' not accepted");
+                }
+                if unsafe { ssh_session_update_known_hosts(self.0) } != SSH_OK {
+                    bail!("Could not add host key to known_hosts: {}", self.error());
+                }
+                eprintln!("Permanently added '{host}' ({key_type} {fingerprint}) to known_hosts");
+            }
+            SSHKnownHostsResult::HOSTS_ERROR => {
+                bail!("Error checking known_hosts: {}", self.error());
+            }
+        }
+        Ok(fingerprint)
+    }
+
+    // The key type and fingerprint known_hosts has for this server
+    fn known_hosts_entry(&self) -> Option<(String, String)> {
+        let mut entry: *mut KnownHostsEntry = ptr::null_mut();
+        unsafe { ssh_session_get_known_hosts_entry(self.0, &mut entry) };
+        if entry.is_null() {
+            return None;
+        }
+        let key = unsafe { (*entry).publickey };
+        let out = if key.is_null() {
+            None
+        } else {
+            key_fingerprint(key).ok().map(|fp| (key_type_name(key), fp))
+        };
+        unsafe { ssh_knownhosts_entry_free(entry) };
+        out
+    }
+
+    // Try each auth method in order until one succeeds.
+    // PARTIAL means the server wants another method as well (e.g. a key then a
+    // one time code), so we carry on down the list.
+    fn authenticate(&self, auth: &Auth, user_host: &str) -> anyhow::Result<()> {
+        // null username everywhere means the session's user, explicit or from ssh config
+        let none_ret = unsafe { ssh_userauth_none(self.0, ptr::null()) };
+        if matches!(none_ret, SSHAuthResult::SUCCESS) {
+            return Ok(());
+        }
+
+        let mut errors = Vec::new();
+        for method in &auth.methods {
+            // what the server accepts, can change after a partial success
+            let allowed = unsafe { ssh_userauth_list(self.0, ptr::null()) };
+            let needs = match method {
+                AuthMethod::Agent | AuthMethod::Key => SSH_AUTH_METHOD_PUBLICKEY,
+                AuthMethod::KeyboardInteractive => SSH_AUTH_METHOD_INTERACTIVE,
+                AuthMethod::Password => SSH_AUTH_METHOD_PASSWORD,
+            };
+            if allowed & needs == 0 {
+                errors.push(format!("{method}: not offered by server"));
+                continue;
+            }
+            let ret = match method {
+                AuthMethod::Agent => self.auth_agent(),
+                AuthMethod::Key => self.auth_key(auth),
+                AuthMethod::KeyboardInteractive => self.auth_kbdint(auth),
+                AuthMethod::Password => self.auth_password(auth, user_host),
+            };
+            match ret {
+                Ok(SSHAuthResult::SUCCESS) => return Ok(()),
+                Ok(SSHAuthResult::PARTIAL) => {
+                    errors.push(format!("{method}: accepted, but server wants more"));
+                }
+                Ok(other) => errors.push(format!("{method}: {other:?}")),
+                Err(err) => errors.push(format!("{method}: {err}")),
+            }
+        }
+        bail!(
+            "Authentication failed for user '{}':\n  {}",
+            self.username(),
+            errors.join("\n  ")
+        );
+    }
+
+    fn auth_agent(&self) -> anyhow::Result<SSHAuthResult> {
+        if env::var_os("SSH_AUTH_SOCK").is_none() {
+            bail!("ssh-agent is not running, SSH_AUTH_SOCK is not set");
+        }
+        match unsafe { ssh_userauth_agent(self.0, ptr::null()) } {
+            SSHAuthResult::DENIED => bail!("no key in ssh-agent was accepted"),
+            SSHAuthResult::ERROR => bail!("{}", self.error()),
+            ret => Ok(ret),
+        }
+    }
+
+    fn auth_key(&self, auth: &Auth) -> anyhow::Result<SSHAuthResult> {
+        let Some(identity) = &auth.identity else {
+            // keys from IdentityFile in ssh config, or the default ~/.ssh/id_*
+            let passphrase = auth.known_passphrase().map(CString::new).transpose()?;
+            let p_ptr = passphrase.as_ref().map_or(ptr::null(), |p| p.as_ptr());
+            return match unsafe { ssh_userauth_publickey_auto(self.0, ptr::null(), p_ptr) } {
+                SSHAuthResult::DENIED => bail!(
+                    "no key in ~/.ssh/ or IdentityFile was accepted. \
+                     Encrypted keys need --identity or $DE_KEY_PASSPHRASE"
+                ),
+                SSHAuthResult::ERROR => bail!("{}", self.error()),
+                ret => Ok(ret),
+            };
+        };
+
+        let key_name = identity.display().to_string();
+        let c_identity = CString::new(identity.as_os_str().as_bytes())?;
+        let mut passphrase = auth.known_passphrase();
+        let mut key: SSHKey = ptr::null_mut();
+        loop {
+            let c_pass = passphrase.clone().map(CString::new).transpose()?;
+            let p_ptr = c_pass.as_ref().map_or(ptr::null(), |p| p.as_ptr());
+            let ret = unsafe {
+                ssh_pki_import_privkey_file(
+                    c_identity.as_ptr(),
+                    p_ptr,
+                    ptr::null(),
+                    ptr::null_mut(),
+                    &mut key,
+                )
+            };
+            match ret {
+                SSH_OK => break,
+                SSH_EOF => bail!("cannot read key file {key_name}"),
+                // encrypted key, we didn't have a passphrase yet
+                _ if passphrase.is_none() => {
+                    passphrase = Some(auth.passphrase(&key_name)?);
+                }
+                _ => {
+                    auth.forget_passphrase();
+                    bail!("cannot load key {key_name}, wrong passphrase?");
+                }
+            }
+        }
+        let ret = unsafe { ssh_userauth_publickey(self.0, ptr::null(), key) };
+        unsafe { ssh_key_free(key) };
+        match ret {
+            SSHAuthResult::DENIED => bail!("key {key_name} was not accepted"),
+            SSHAuthResult::ERROR => bail!("{}", self.error()),
+            ret => Ok(ret),
+        }
+    }
+
+    fn auth_password(&self, auth: &Auth, user_host: &str) -> anyhow::Result<SSHAuthResult> {
+        let password = CString::new(auth.password(user_host)?)?;
+        match unsafe { ssh_userauth_password(self.0, ptr::null(), password.as_ptr()) } {
+            SSHAuthResult::DENIED => {
+                auth.forget_password();
+                bail!("password was not accepted");
+            }
+            SSHAuthResult::ERROR => bail!("{}", self.error()),
+            ret => Ok(ret),
+        }
+    }
+
+    // The server sends rounds of prompts until it's satisfied.
+    fn auth_kbdint(&self, auth: &Auth) -> anyhow::Result<SSHAuthResult> {
+        let mut round = 0;
+        let mut ret = unsafe { ssh_userauth_kbdint(self.0, ptr::null(), ptr::null()) };
+        while matches!(ret, SSHAuthResult::INFO) {
+            let num_prompts = unsafe { ssh_userauth_kbdint_getnprompts(self.0) };
+            for idx in 0..num_prompts.max(0) as c_uint {
+                let mut echo: c_char = 0;
+                let text = unsafe { ssh_userauth_kbdint_getprompt(self.0, idx, &mut echo) };
+                let text = unsafe { CStr::from_ptr(text) }.to_string_lossy();
+                let answer = auth.kbdint_answer(round, idx as usize, &text, echo != 0)?;
+                let answer = CString::new(answer)?;
+                unsafe { ssh_userauth_kbdint_setanswer(self.0, idx, answer.as_ptr()) };
+            }
+            round += 1;
+            ret = unsafe { ssh_userauth_kbdint(self.0, ptr::null(), ptr::null()) };
+        }
+        match ret {
+            SSHAuthResult::DENIED => {
+                auth.forget_kbdint();
+                bail!("answers were not accepted");
+            }
+            SSHAuthResult::ERROR => bail!("{}", self.error()),
+            ret => Ok(ret),
+        }
+    }
+
+    fn error(&self) -> String {
+        unsafe { CStr::from_ptr(ssh_get_error(self.0)) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+// e.g. "ssh-ed25519"
+fn key_type_name(key: SSHKey) -> String {
+    let name = unsafe { ssh_key_type_to_char(ssh_key_type(key)) };
+    if name.is_null() {
+        return "unknown".to_string();
+    }
+    unsafe { CStr::from_ptr(name) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+// "SHA256:base64", same as `ssh-keygen -l`
+fn key_fingerprint(key: SSHKey) -> anyhow::Result<String> {
+    let mut hash: *mut u8 = ptr::null_mut();
+    let mut hash_len: usize = 0;
+    let ret =
+        unsafe { ssh_get_publickey_hash(key, SSH_PUBLICKEY_HASH_SHA256, &mut hash, &mut hash_len) };
+    if ret != SSH_OK {
+        bail!("Could not hash host key");
+    }
+    let fp = unsafe { ssh_get_fingerprint_hash(SSH_PUBLICKEY_HASH_SHA256, hash, hash_len) };
+    unsafe { ssh_clean_pubkey_hash(&mut hash) };
+    if fp.is_null() {
+        bail!("Could not format host key fingerprint");
+    }
+    let out = unsafe { CStr::from_ptr(fp) }.to_string_lossy().into_owned();
+    unsafe { ssh_string_free_char(fp) };
+    Ok(out)
+}
+
+// Read a line from the terminal, without echo unless is_echo
+pub fn getpass(prompt: &str, is_echo: bool) -> anyhow::Result<String> {
+    let c_prompt = CString::new(prompt)?;
+    let mut buf = [0u8; 1024];
+    let ret = unsafe {
+        ssh_getpass(
+            c_prompt.as_ptr(),
+            buf.as_mut_ptr() as *mut c_char,
+            buf.len(),
+            is_echo as c_int,
+            0,
+        )
+    };
+    if ret != 0 {
+        bail!("could not read from terminal");
+    }
+    Ok(CStr::from_bytes_until_nul(&buf)?
+        .to_string_lossy()
+        .into_owned())
+}
+
 impl Drop for SSHSessionWrap {
     fn drop(&mut self) {
         unsafe {
@@ -288,6 +1041,16 @@ impl Drop for SSHSessionWrap {
 
 pub struct SFTP {
     session: SFTPSession,
+    // fsync@openssh.com
+    is_fsync: bool,
+    // posix-rename@openssh.com, a rename that replaces the destination
+    is_posix_rename: bool,
+}
+
+fn sftp_has_extension(sftp: SFTPSession, name: &str) -> bool {
+    let c_name = CString::new(name).unwrap();
+    let c_version = CString::new("1").unwrap();
+    unsafe { sftp_extension_supported(sftp, c_name.as_ptr(), c_version.as_ptr()) == 1 }
 }
 
 impl SFTP {
@@ -318,6 +1081,27 @@ impl SFTPFile {
         //println!("SFTPFile.write {} bytes", data.len() as u32);
         unsafe { sftp_write(self.handle, data.as_ptr(), data.len() as u32) }
     }
+
+    // Flush to disk on the server. Needs fsync@openssh.com.
+    pub fn fsync(&self) -> c_int {
+        unsafe { sftp_fsync(self.handle) }
+    }
+
+    // Bytes read, 0 at end of file, negative on error
+    pub fn read(&self, buf: &mut [u8]) -> isize {
+        unsafe { sftp_read(self.handle, buf.as_mut_ptr(), buf.len()) }
+    }
+
+    // (size, permissions, mtime)
+    pub fn stat(&self) -> Option<(u64, u32, u32)> {
+        let attrs = unsafe { sftp_fstat(self.handle) };
+        if attrs.is_null() {
+            return None;
+        }
+        let out = unsafe { ((*attrs).size, (*attrs).permissions, (*attrs).mtime) };
+        unsafe { sftp_attributes_free(attrs) };
+        Some(out)
+    }
 }
 
 impl Drop for SFTPFile {
@@ -339,29 +1123,66 @@ pub enum LogLevel {
     FUNCTIONS, // Every function path
 }
 
-pub struct MockSSH {}
+pub struct MockSSH {
+    pub label: String,
+}
 
 impl Remote for MockSSH {
-    fn run_remote_cmd(&self, cmd: &str) -> anyhow::Result<(String, i32)> {
-        println!("would run cmd '{cmd}'");
-        Ok(("".to_string(), 0))
+    fn run_remote_cmd_bytes(&self, cmd: &str) -> anyhow::Result<(Vec<u8>, i32)> {
+        println!("{}would run cmd '{cmd}'", self.label);
+        Ok((Vec::new(), 0))
     }
     fn mkdir(&self, dir: &str, perms: u32) -> anyhow::Result<()> {
-        println!("would mkdir {dir} with perms {perms:o}"); // :o is octal
+        println!("{}would mkdir {dir} with perms {perms:o}", self.label); // :o is octal
         Ok(())
     }
     fn upload(&self, src: &str, dst: &str) -> anyhow::Result<()> {
-        println!("would upload {src} -> {dst}");
+        println!("{}would upload {src} -> {dst}", self.label);
         Ok(())
     }
     fn upload_bytes(&self, _: &[u8], _: &str) -> anyhow::Result<()> {
         // only for helper so don't display it as activity
         Ok(())
     }
+    fn download(&self, src: &str, dst: &str) -> anyhow::Result<()> {
+        println!("{}would download {src} -> {dst}", self.label);
+        Ok(())
+    }
     fn delete(&self, path: &str) -> anyhow::Result<()> {
-        println!("would delete {path}");
+        println!("{}would delete {path}", self.label);
+        Ok(())
+    }
+    fn chmod(&self, path: &str, mode: u32) -> anyhow::Result<()> {
+        println!("{}would chmod {mode:o} {path}", self.label);
+        Ok(())
+    }
+    fn rmdir(&self, dir: &str) -> anyhow::Result<()> {
+        println!("{}would rmdir {dir}", self.label);
+        Ok(())
+    }
+    fn symlink(&self, target: &str, link: &str) -> anyhow::Result<()> {
+        println!("{}would symlink {link} -> {target}", self.label);
         Ok(())
     }
+    fn rename(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        println!("{}would rename {from} -> {to}", self.label);
+        Ok(())
+    }
+    fn read_link(&self, _: &str) -> anyhow::Result<Option<String>> {
+        Ok(None)
+    }
+    fn lstat(&self, _: &str) -> anyhow::Result<Option<(Option<u32>, u32)>> {
+        Ok(None)
+    }
+    fn stat(&self, _: &str) -> anyhow::Result<Option<(u32, u64, u64)>> {
+        Ok(None)
+    }
+    fn read_file(&self, _: &str) -> anyhow::Result<Vec<u8>> {
+        Ok(Vec::new())
+    }
+    fn read_dir(&self, _: &str) -> anyhow::Result<Vec<DirEntry>> {
+        Ok(Vec::new())
+    }
 }
 
 //
@@ -372,7 +1193,19 @@ type SSHSession = *mut c_void;
 type SSHChannel = *mut c_void;
 type SFTPSession = *mut c_void;
 type SFTPFileHandle = *mut c_void;
-//type SSHKey = *const u8;
+type SFTPDirHandle = *mut c_void;
+type SSHKey = *mut c_void;
+
+const SSH_OK: c_int = 0;
+// SFTPAttributes.flags bit, the uid and gid are set
+const SSH_FILEXFER_ATTR_UIDGID: u32 = 0x2;
+const SSH_PUBLICKEY_HASH_SHA256: c_int = 2;
+const SSH_EOF: c_int = -127;
+
+// bitmask from ssh_userauth_list
+const SSH_AUTH_METHOD_PASSWORD: c_int = 0x0002;
+const SSH_AUTH_METHOD_PUBLICKEY: c_int = 0x0004;
+const SSH_AUTH_METHOD_INTERACTIVE: c_int = 0x0010;
 
 const SSH_CMD_BUF_SIZE: usize = 1024;
 
@@ -387,6 +1220,8 @@ enum SSHOption {
     IDENTITY,
     ADD_IDENTITY,
     KNOWNHOSTS,
+    PROXYCOMMAND = 19,
+    PROXYJUMP = 47,
 }
 
 #[repr(i32)]
@@ -421,6 +1256,57 @@ enum SSHKnownHostsResult {
     HOSTS_OTHER = 3,
 }
 
+// libssh's struct sftp_attributes_struct
+#[repr(C)]
+struct SFTPAttributes {
+    name: *mut c_char,
+    longname: *mut c_char,
+    flags: u32,
+    file_type: u8,
+    size: u64,
+    uid: u32,
+    gid: u32,
+    owner: *mut c_char,
+    group: *mut c_char,
+    permissions: u32,
+    atime64: u64,
+    atime: u32,
+    atime_nseconds: u32,
+    createtime: u64,
+    createtime_nseconds: u32,
+    mtime64: u64,
+    mtime: u32,
+    mtime_nseconds: u32,
+    acl: *mut c_void,
+    extended_count: u32,
+    extended_type: *mut c_void,
+    extended_data: *mut c_void,
+}
+
+// struct timeval, for sftp_utimes
+#[repr(C)]
+struct Timeval {
+    tv_sec: i64,
+    tv_usec: i64,
+}
+
+fn timeval(t: SystemTime) -> Timeval {
+    let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    Timeval {
+        tv_sec: d.as_secs() as i64,
+        tv_usec: d.subsec_micros() as i64,
+    }
+}
+
+// libssh's struct ssh_knownhosts_entry
+#[repr(C)]
+struct KnownHostsEntry {
+    hostname: *mut c_char,
+    unparsed: *mut c_char,
+    publickey: SSHKey,
+    comment: *mut c_char,
+}
+
 #[derive(Debug, PartialEq)]
 #[repr(u32)]
 enum SFTPError {
@@ -474,6 +1360,10 @@ extern "C" {
     fn ssh_version(min: c_uint) -> *const c_char;
     fn ssh_set_log_level(level: LogLevel) -> SSHResult;
     fn ssh_options_set(s: SSHSession, opt_type: SSHOption, value: *const c_void) -> c_int;
+    fn ssh_options_get(s: SSHSession, opt_type: SSHOption, value: *mut *mut c_char) -> c_int;
+    fn ssh_options_get_port(s: SSHSession, port: *mut c_uint) -> c_int;
+    fn ssh_options_parse_config(s: SSHSession, filename: *const c_char) -> c_int;
+    fn ssh_string_free_char(s: *mut c_char);
 
     fn ssh_new() -> SSHSession;
     fn ssh_free(s: SSHSession);
@@ -483,14 +1373,88 @@ extern "C" {
 
     fn ssh_get_error(s: SSHSession) -> *const c_char;
     fn ssh_session_is_known_server(s: SSHSession) -> SSHKnownHostsResult;
+    fn ssh_session_update_known_hosts(s: SSHSession) -> c_int;
+    fn ssh_session_get_known_hosts_entry(
+        s: SSHSession,
+        entry: *mut *mut KnownHostsEntry,
+    ) -> SSHKnownHostsResult;
+    fn ssh_knownhosts_entry_free(entry: *mut KnownHostsEntry);
+    fn ssh_get_server_publickey(s: SSHSession, key: *mut SSHKey) -> c_int;
+    fn ssh_get_publickey_hash(
+        key: SSHKey,
+        hash_type: c_int,
+        hash: *mut *mut u8,
+        hlen: *mut usize,
+    ) -> c_int;
+    fn ssh_get_fingerprint_hash(hash_type: c_int, hash: *mut u8, len: usize) -> *mut c_char;
+    fn ssh_clean_pubkey_hash(hash: *mut *mut u8);
+    fn ssh_key_type(key: SSHKey) -> c_int;
+    fn ssh_key_type_to_char(key_type: c_int) -> *const c_char;
+
+    fn ssh_getpass(
+        prompt: *const c_char,
+        buf: *mut c_char,
+        len: usize,
+        echo: c_int,
+        verify: c_int,
+    ) -> c_int;
 
+    fn ssh_userauth_none(s: SSHSession, username: *const c_char) -> SSHAuthResult;
+    fn ssh_userauth_list(s: SSHSession, username: *const c_char) -> c_int;
     fn ssh_userauth_agent(s: SSHSession, username: *const c_char) -> SSHAuthResult;
+    fn ssh_userauth_password(
+        s: SSHSession,
+        username: *const c_char,
+        password: *const c_char,
+    ) -> SSHAuthResult;
+    fn ssh_userauth_publickey(
+        s: SSHSession,
+        username: *const c_char,
+        privkey: SSHKey,
+    ) -> SSHAuthResult;
+    fn ssh_userauth_kbdint(
+        s: SSHSession,
+        user: *const c_char,
+        submethods: *const c_char,
+    ) -> SSHAuthResult;
+    fn ssh_userauth_kbdint_getnprompts(s: SSHSession) -> c_int;
+    fn ssh_userauth_kbdint_getprompt(s: SSHSession, i: c_uint, echo: *mut c_char) -> *const c_char;
+    fn ssh_userauth_kbdint_setanswer(s: SSHSession, i: c_uint, answer: *const c_char) -> c_int;
+
+    fn ssh_pki_import_privkey_file(
+        filename: *const c_char,
+        passphrase: *const c_char,
+        auth_fn: *const c_void,
+        auth_data: *mut c_void,
+        pkey: *mut SSHKey,
+    ) -> c_int;
+    fn ssh_key_free(key: SSHKey);
+    fn ssh_userauth_publickey_auto(
+        s: SSHSession,
+        username: *const c_char,
+        passphrase: *const c_char,
+    ) -> SSHAuthResult;
 
     fn ssh_channel_new(s: SSHSession) -> SSHChannel;
     fn ssh_channel_free(c: SSHChannel);
     fn ssh_channel_open_session(c: SSHChannel) -> SSHResult;
     fn ssh_channel_request_exec(c: SSHChannel, cmd: *const c_char) -> SSHResult;
     fn ssh_channel_read(c: SSHChannel, dest: *mut u8, count: u32, is_stderr: c_uint) -> c_int;
+    fn ssh_channel_read_nonblocking(
+        c: SSHChannel,
+        dest: *mut u8,
+        count: u32,
+        is_stderr: c_uint,
+    ) -> c_int;
+    fn ssh_channel_write(c: SSHChannel, data: *const u8, len: u32) -> c_int;
+    fn ssh_channel_is_eof(c: SSHChannel) -> c_int;
+    fn ssh_channel_open_forward(
+        c: SSHChannel,
+        remote_host: *const c_char,
+        remote_port: c_int,
+        source_host: *const c_char,
+        local_port: c_int,
+    ) -> c_int;
     fn ssh_channel_send_eof(c: SSHChannel);
     fn ssh_channel_close(c: SSHChannel);
     fn ssh_channel_is_closed(c: SSHChannel) -> c_int;
@@ -502,6 +1466,23 @@ extern "C" {
     fn sftp_get_error(sftp: SFTPSession) -> SFTPError;
     fn sftp_mkdir(sftp: SFTPSession, dir: *const c_char, perms: c_uint) -> SSHResult;
     fn sftp_unlink(sftp: SFTPSession, path: *const c_char) -> SSHResult;
+    fn sftp_utimes(sftp: SFTPSession, file: *const c_char, times: *const Timeval) -> c_int;
+    fn sftp_chmod(sftp: SFTPSession, file: *const c_char, mode: u32) -> c_int;
+    fn sftp_rmdir(sftp: SFTPSession, directory: *const c_char) -> c_int;
+    fn sftp_symlink(sftp: SFTPSession, target: *const c_char, dest: *const c_char) -> c_int;
+    fn sftp_extension_supported(
+        sftp: SFTPSession,
+        name: *const c_char,
+        data: *const c_char,
+    ) -> c_int;
+    fn sftp_rename(sftp: SFTPSession, original: *const c_char, newname: *const c_char) -> c_int;
+    fn sftp_readlink(sftp: SFTPSession, path: *const c_char) -> *mut c_char;
+    fn sftp_lstat(sftp: SFTPSession, path: *const c_char) -> *mut SFTPAttributes;
+    fn sftp_stat(sftp: SFTPSession, path: *const c_char) -> *mut SFTPAttributes;
+    fn sftp_opendir(sftp: SFTPSession, path: *const c_char) -> SFTPDirHandle;
+    fn sftp_readdir(sftp: SFTPSession, dir: SFTPDirHandle) -> *mut SFTPAttributes;
+    fn sftp_dir_eof(dir: SFTPDirHandle) -> c_int;
+    fn sftp_closedir(dir: SFTPDirHandle) -> c_int;
 
     fn sftp_open(
         sftp: SFTPSession,
@@ -510,12 +1491,11 @@ extern "C" {
         mode: c_uint,
     ) -> SFTPFileHandle;
     fn sftp_write(sfile: SFTPFileHandle, buf: *const u8, count: c_uint) -> i32;
+    fn sftp_read(sfile: SFTPFileHandle, buf: *mut u8, count: usize) -> isize;
+    fn sftp_fstat(sfile: SFTPFileHandle) -> *mut SFTPAttributes;
+    fn sftp_fsync(sfile: SFTPFileHandle) -> c_int;
+    fn sftp_attributes_free(attrs: *mut SFTPAttributes);
     fn sftp_close(sfile: SFTPFileHandle) -> SSHResult;
 
-    //fn ssh_userauth_publickey(
-    //    s: SSHSession,
-    //    username: *const c_char,
-    //    privkey: SSHKey,
-    //) -> c_int;
     //fn ssh_get_log_level() -> libc::c_int;
 }
@@ -1,82 +1,103 @@
+use crate::host_key::HostKeyPolicy;
 use crate::progress_message::Progress;
-use crate::remote::Remote;
-use crate::ssh::{LogLevel, MockSSH, SSH};
+use crate::remote::{DirEntry, Remote};
+use crate::ssh::{ConnectOptions, MockSSH, SSH};
 use crossbeam_channel::{unbounded, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
+// (src, dst) for a worker to transfer
+enum Job {
+    Upload(String, String),
+    Download(String, String),
+}
+
 pub struct SSHManager {
     primary: Box<dyn Remote>,
-    upload_sender: Option<Sender<(String, String)>>,
-    upload_workers: Vec<thread::JoinHandle<()>>,
+    job_sender: Option<Sender<Job>>,
+    workers: Vec<thread::JoinHandle<()>>,
 }
 
 impl SSHManager {
     pub fn new(
-        host: &str,
-        username: &str,
-        log_level: LogLevel,
+        opts: &ConnectOptions,
         num_workers: usize,
         progress_sender: Sender<Progress>,
     ) -> anyhow::Result<SSHManager> {
-        let primary = SSH::new(host, username, log_level, progress_sender.clone())?;
-        let (upload_sender, upload_receiver) = unbounded::<(String, String)>();
-        let mut upload_workers = Vec::with_capacity(num_workers);
+        let primary = SSH::new(opts, progress_sender.clone())?;
+
+        // Workers must see the same host key as the primary connection. That
+        // also stops them asking again if it wasn't saved to known_hosts.
+        let mut worker_opts = opts.clone();
+        worker_opts.host_key =
+            HostKeyPolicy::Fingerprint(vec![primary.host_key_fingerprint().to_string()]);
+
+        let (job_sender, job_receiver) = unbounded::<Job>();
+        let mut workers = Vec::with_capacity(num_workers);
 
         // one ssh at a time
         let ssh_lock = Arc::new(Mutex::new(()));
 
-        // start upload workers
-        let host = host.to_string();
-        let username = username.to_string();
+        // start upload / download workers
         for tid in 1..=num_workers {
-            let host = host.clone();
-            let username = username.clone();
-            let upload_receiver = upload_receiver.clone();
+            let opts = worker_opts.clone();
+            let job_receiver = job_receiver.clone();
             let progress_sender = progress_sender.clone();
             let ssh_lock = ssh_lock.clone();
             let thread_handle = thread::Builder::new()
-                .name(format!("upload_worker_{tid}"))
+                .name(format!("transfer_worker_{tid}"))
                 .spawn(move || {
                     let guard = ssh_lock.lock();
-                    let ssh = SSH::new(&host, &username, log_level, progress_sender).unwrap();
+                    let ssh = SSH::new(&opts, progress_sender).unwrap();
                     drop(guard);
-                    for (src, dst) in upload_receiver {
-                        ssh.upload(&src, &dst).unwrap();
+                    for job in job_receiver {
+                        match job {
+                            Job::Upload(src, dst) => ssh.upload(&src, &dst).unwrap(),
+                            Job::Download(src, dst) => ssh.download(&src, &dst).unwrap(),
+                        }
                     }
                 })?;
-            upload_workers.push(thread_handle);
+            workers.push(thread_handle);
         }
 
         Ok(SSHManager {
             primary: Box::new(primary),
-            upload_sender: Some(upload_sender),
-            upload_workers,
+            job_sender: Some(job_sender),
+            workers,
         })
     }
 
     // Replaces self with a mocked SSH connection, so we can report what would really happen
-    pub fn switch_to_dry_run(self) -> Self {
+    // label: prefix for the messages, to say which host
+    pub fn switch_to_dry_run(self, label: &str) -> Self {
         self.stop();
         SSHManager {
-            primary: Box::new(MockSSH {}),
-            upload_sender: None,
-            upload_workers: Vec::new(),
+            primary: Box::new(MockSSH {
+                label: label.to_string(),
+            }),
+            job_sender: None,
+            workers: Vec::new(),
         }
     }
 
-    // wait until all upload workers are done
+    // wait until all workers are done
     pub fn stop(mut self) {
-        drop(self.upload_sender.take());
-        for thread_handle in self.upload_workers {
+        self.wait();
+    }
+
+    // Wait for the queued transfers to finish. After this the primary
+    // connection is still usable, and transfers run on it.
+    pub fn wait(&mut self) {
+        drop(self.job_sender.take());
+        for thread_handle in self.workers.drain(..) {
             thread_handle.join().unwrap();
         }
     }
 }
 
 impl Remote for SSHManager {
-    fn run_remote_cmd(&self, cmd: &str) -> anyhow::Result<(String, i32)> {
-        self.primary.run_remote_cmd(cmd)
+    fn run_remote_cmd_bytes(&self, cmd: &str) -> anyhow::Result<(Vec<u8>, i32)> {
+        self.primary.run_remote_cmd_bytes(cmd)
     }
 
     fn mkdir(&self, dir: &str, perms: u32) -> anyhow::Result<()> {
@@ -87,6 +108,42 @@ impl Remote for SSHManager {
         self.primary.delete(path)
     }
 
+    fn chmod(&self, path: &str, mode: u32) -> anyhow::Result<()> {
+        self.primary.chmod(path, mode)
+    }
+
+    fn rmdir(&self, dir: &str) -> anyhow::Result<()> {
+        self.primary.rmdir(dir)
+    }
+
+    fn symlink(&self, target: &str, link: &str) -> anyhow::Result<()> {
+        self.primary.symlink(target, link)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        self.primary.rename(from, to)
+    }
+
+    fn read_link(&self, path: &str) -> anyhow::Result<Option<String>> {
+        self.primary.read_link(path)
+    }
+
+    fn lstat(&self, path: &str) -> anyhow::Result<Option<(Option<u32>, u32)>> {
+        self.primary.lstat(path)
+    }
+
+    fn stat(&self, path: &str) -> anyhow::Result<Option<(u32, u64, u64)>> {
+        self.primary.stat(path)
+    }
+
+    fn read_file(&self, path: &str) -> anyhow::Result<Vec<u8>> {
+        self.primary.read_file(path)
+    }
+
+    fn read_dir(&self, dir: &str) -> anyhow::Result<Vec<DirEntry>> {
+        self.primary.read_dir(dir)
+    }
+
     // Special function to upload the helper binary.
     // Takes bytes instead of a src filename and
     // uploads using the primary connection, not the thread pool.
@@ -99,14 +156,25 @@ impl Remote for SSHManager {
     // queues for upload and returns immediately.
     // call stop() once all the uploads are queued to wait for completion.
     fn upload(&self, src: &str, dst: &str) -> anyhow::Result<()> {
-        match &self.upload_sender {
+        match &self.job_sender {
             // normal multi-threaded mode
             Some(sender) => {
-                sender.send((src.to_string(), dst.to_string()))?;
+                sender.send(Job::Upload(src.to_string(), dst.to_string()))?;
                 Ok(())
             }
             // dry-run mode
             None => self.primary.upload(src, dst),
         }
     }
+
+    // download a file from remote. Same as upload, queues it for the workers.
+    fn download(&self, src: &str, dst: &str) -> anyhow::Result<()> {
+        match &self.job_sender {
+            Some(sender) => {
+                sender.send(Job::Download(src.to_string(), dst.to_string()))?;
+                Ok(())
+            }
+            None => self.primary.download(src, dst),
+        }
+    }
 }
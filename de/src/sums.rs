@@ -0,0 +1,259 @@
+//
+// Part of Demeter Deploy
+// https://github.com/grahamking/demeter-deploy/
+//
+// Checksums from the standard tools, for a server where the helper can't run
+// but a shell can: sha256sum (coreutils, busybox), or else POSIX cksum. The
+// local files are hashed the same way to compare with them, see
+// RemoteListing::entries.
+//
+
+use std::collections::HashMap;
+
+use anyhow::bail;
+
+use crate::hash::{Digest, MAX_DIGEST_LEN};
+use crate::remote::{shell_quote, Remote};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SumTool {
+    Sha256sum,
+    // POSIX CRC-32 and size. Only 32 bits, but always there.
+    Cksum,
+}
+
+impl SumTool {
+    pub fn name(self) -> &'static str {
+        match self {
+            SumTool::Sha256sum => "sha256sum",
+            SumTool::Cksum => "cksum",
+        }
+    }
+
+    // What the tool would print for these bytes
+    pub fn digest(self, bytes: &[u8]) -> Digest {
+        match self {
+            SumTool::Sha256sum => to_digest(&sha256(bytes)),
+            SumTool::Cksum => to_digest(&cksum(bytes).to_be_bytes()),
+        }
+    }
+
+    // path->digest from the tool's output, paths relative to where it ran.
+    // Lines we can't read are skipped, so those files count as changed.
+    pub fn parse(self, output: &str) -> HashMap<String, Digest> {
+        output
+            .lines()
+            .filter_map(|line| match self {
+                SumTool::Sha256sum => parse_sha256sum(line),
+                SumTool::Cksum => parse_cksum(line),
+            })
+            .map(|(name, digest)| (name.strip_prefix("./").unwrap_or(&name).to_string(), digest))
+            .collect()
+    }
+}
+
+// Checksums of the files under a remote directory
+pub struct Sums {
+    pub tool: SumTool,
+    pub digests: HashMap<String, Digest>,
+}
+
+// Find which tool the server has and run it on every file under dir, in one
+// round trip. The first line of output is the tool's name.
+pub fn remote_sums(ssh: &dyn Remote, dir: &str) -> anyhow::Result<Sums> {
+    let dir = shell_quote(dir);
+    let mut cmd = format!("cd {dir} || exit 1; ");
+    for tool in [SumTool::Sha256sum, SumTool::Cksum] {
+        let name = tool.name();
+        cmd += &format!(
+            "if command -v {name} >/dev/null; then echo {name}; find . -type f -exec {name} {{}} +; exit; fi; "
+        );
+    }
+    cmd += "exit 127";
+
+    let (output, exit_status) = ssh.run_remote_cmd(&cmd)?;
+    if exit_status != 0 {
+        bail!("checksum command exit code {exit_status}");
+    }
+    let (name, sums) = output.split_once('\n').unwrap_or((&output, ""));
+    let tool = match name {
+        "sha256sum" => SumTool::Sha256sum,
+        "cksum" => SumTool::Cksum,
+        _ => bail!("checksum command printed '{name}'"),
+    };
+    Ok(Sums {
+        tool,
+        digests: tool.parse(sums),
+    })
+}
+
+// "<64 hex>  ./name". GNU starts the line with a backslash if the name has
+// a backslash or newline in it, and escapes those.
+fn parse_sha256sum(line: &str) -> Option<(String, Digest)> {
+    let (is_escaped, line) = match line.strip_prefix('\\') {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+    let hex = line.get(..64)?;
+    // two spaces, or space star in binary mode
+    let name = line.get(64..)?.strip_prefix(' ')?.get(1..)?;
+    let mut bytes = [0u8; 32];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    let name = if is_escaped {
+        unescape(name)
+    } else {
+        name.to_string()
+    };
+    Some((name, to_digest(&bytes)))
+}
+
+// Undo sha256sum's escaping of '\\', newlines and (newer coreutils) carriage
+// returns. Left to right, so an escaped backslash followed by 'n' stays a
+// backslash and an 'n'.
+fn unescape(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some(c) => out.push(c),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+// "<crc> <size> ./name"
+fn parse_cksum(line: &str) -> Option<(String, Digest)> {
+    let mut parts = line.splitn(3, ' ');
+    let crc: u32 = parts.next()?.parse().ok()?;
+    parts.next()?.parse::<u64>().ok()?;
+    let name = parts.next()?;
+    Some((name.to_string(), to_digest(&crc.to_be_bytes())))
+}
+
+fn to_digest(bytes: &[u8]) -> Digest {
+    let mut digest = Digest {
+        len: bytes.len() as u8,
+        bytes: [0; MAX_DIGEST_LEN],
+    };
+    digest.bytes[..bytes.len()].copy_from_slice(bytes);
+    digest
+}
+
+// POSIX cksum: CRC-32 with polynomial 0x04C11DB7, not reflected, over the
+// bytes and then the length, least significant byte first.
+pub fn cksum(bytes: &[u8]) -> u32 {
+    let step = |crc: u32, b: u8| (crc << 8) ^ CKSUM_TABLE[((crc >> 24) as u8 ^ b) as usize];
+    let mut crc = bytes.iter().fold(0, |crc, &b| step(crc, b));
+    let mut len = bytes.len();
+    while len > 0 {
+        crc = step(crc, len as u8);
+        len >>= 8;
+    }
+    !crc
+}
+
+const CKSUM_TABLE: [u32; 256] = cksum_table();
+
+const fn cksum_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04C1_1DB7
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+// FIPS 180-4
+pub fn sha256(bytes: &[u8]) -> [u8; 32] {
+    let mut h = SHA256_H;
+    // the message, a 1 bit, zeros, and its length in bits, to a multiple of 64 bytes
+    let mut tail = [0u8; 128];
+    let rest = bytes.chunks_exact(64).remainder();
+    tail[..rest.len()].copy_from_slice(rest);
+    tail[rest.len()] = 0x80;
+    let tail_len = if rest.len() < 56 { 64 } else { 128 };
+    tail[tail_len - 8..tail_len].copy_from_slice(&((bytes.len() as u64) * 8).to_be_bytes());
+
+    for block in bytes
+        .chunks_exact(64)
+        .chain(tail[..tail_len].chunks_exact(64))
+    {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = hh
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(SHA256_K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            hh = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (x, y) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+            *x = x.wrapping_add(y);
+        }
+    }
+
+    let mut out = [0u8; 32];
+    for (chunk, x) in out.chunks_exact_mut(4).zip(h) {
+        chunk.copy_from_slice(&x.to_be_bytes());
+    }
+    out
+}
+
+const SHA256_H: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
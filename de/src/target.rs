@@ -0,0 +1,207 @@
+//
+// Part of Demeter Deploy
+// https://github.com/grahamking/demeter-deploy/
+//
+// Parse the remote argument into user, host, port and path.
+//
+// Accepted forms:
+//   host:/path/                      scp style
+//   user@host:/path/
+//   user@host:2222:/path/            port between host and path
+//   user@[2001:db8::1]:/path/        IPv6 literal in brackets
+//   ssh://user@host:2222/path/       URL style, path is absolute
+//   ssh://user@[2001:db8::1]:2222/path/
+//
+
+use std::fmt;
+
+use anyhow::{anyhow, bail};
+
+const SSH_SCHEME: &str = "ssh://";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    // None to use ~/.ssh/config's User, or the local user
+    pub user: Option<String>,
+    // Host name, ~/.ssh/config alias, or IP address. IPv6 without the brackets.
+    pub host: String,
+    // None to use ~/.ssh/config's Port, or 22
+    pub port: Option<u16>,
+    pub path: String,
+}
+
+impl Target {
+    pub fn parse(s: &str) -> anyhow::Result<Target> {
+        match s.strip_prefix(SSH_SCHEME) {
+            Some(rest) => Target::parse_url(rest),
+            None => Target::parse_scp(s),
+        }
+        .map_err(|err| anyhow!("Invalid remote '{s}': {err}"))
+    }
+
+    // [user@]host:[port:]path
+    fn parse_scp(s: &str) -> anyhow::Result<Target> {
+        let (user, rest) = split_user(s);
+        let (host, rest) = split_host(rest)?;
+        let rest = rest
+            .strip_prefix(':')
+            .ok_or_else(|| anyhow!("missing ':' between host and path"))?;
+
+        // host:2222:/path, the port is all digits followed by another ':'
+        let (port, path) = match rest.split_once(':') {
+            Some((port, path)) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => {
+                (Some(parse_port(port)?), path)
+            }
+            _ => (None, rest),
+        };
+        Target::new(user, host, port, path)
+    }
+
+    // [user@]host[:port]/path, after the ssh://
+    fn parse_url(s: &str) -> anyhow::Result<Target> {
+        let (user, rest) = split_user(s);
+        let (host, rest) = split_host(rest)?;
+        let (port, path) = match rest.strip_prefix(':') {
+            Some(rest) => {
+                let end = rest.find('/').unwrap_or(rest.len());
+                (Some(parse_port(&rest[..end])?), &rest[end..])
+            }
+            None => (None, rest),
+        };
+        if !path.is_empty() && !path.starts_with('/') {
+            bail!("expected '/' after host");
+        }
+        Target::new(user, host, port, path)
+    }
+
+    // Does this look like a remote rather than a local path?
+    // Like scp, a ':' before any '/' means remote, so use ./a:b for a local a:b
+    pub fn is_remote(s: &str) -> bool {
+        if s.starts_with(SSH_SCHEME) {
+            return true;
+        }
+        match s.find(':') {
+            Some(colon) => !s[..colon].contains('/'),
+            None => false,
+        }
+    }
+
+    // A host without a path, as for -J: [user@]host[:port] or ssh://[user@]host[:port]
+    // The path is left empty.
+    pub fn parse_host(s: &str) -> anyhow::Result<Target> {
+        let inner = || -> anyhow::Result<Target> {
+            let rest = s.strip_prefix(SSH_SCHEME).unwrap_or(s);
+            let (user, rest) = split_user(rest);
+            let (host, rest) = split_host(rest)?;
+            let port = match rest.strip_prefix(':') {
+                Some(port) => Some(parse_port(port)?),
+                None if rest.is_empty() || rest == "/" => None,
+                None => bail!("unexpected '{rest}' after host"),
+            };
+            check_host(user, host)?;
+            Ok(Target {
+                user: user.map(|u| u.to_string()),
+                host: host.to_string(),
+                port,
+                path: String::new(),
+            })
+        };
+        inner().map_err(|err| anyhow!("Invalid host '{s}': {err}"))
+    }
+
+    fn new(
+        user: Option<&str>,
+        host: &str,
+        port: Option<u16>,
+        path: &str,
+    ) -> anyhow::Result<Target> {
+        check_host(user, host)?;
+        // An empty path would be the remote home dir, or with the trailing
+        // slash we add, the root. Both are too surprising for a tool that deletes.
+        if path.is_empty() {
+            bail!("missing remote directory");
+        }
+        Ok(Target {
+            user: user.map(|u| u.to_string()),
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    // The host part for messages and ssh: user@host, or user@host:port
+    pub fn host_display(&self) -> String {
+        let mut out = String::new();
+        if let Some(user) = &self.user {
+            out.push_str(user);
+            out.push('@');
+        }
+        if self.host.contains(':') {
+            out += &format!("[{}]", self.host);
+        } else {
+            out.push_str(&self.host);
+        }
+        if let Some(port) = self.port {
+            out += &format!(":{port}");
+        }
+        out
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.port.is_some() {
+            write!(f, "{SSH_SCHEME}{}{}", self.host_display(), self.path)
+        } else {
+            write!(f, "{}:{}", self.host_display(), self.path)
+        }
+    }
+}
+
+fn check_host(user: Option<&str>, host: &str) -> anyhow::Result<()> {
+    if user == Some("") {
+        bail!("empty user name before '@'");
+    }
+    if host.is_empty() {
+        bail!("missing host");
+    }
+    if host.contains(|c: char| c.is_whitespace() || c == '/' || c == '@') {
+        bail!("invalid host name '{host}'");
+    }
+    Ok(())
+}
+
+// The user is whatever is before the last '@' that comes before the host.
+// The host starts after that '@' and runs to the first ':' or '[', so an '@'
+// in the path is not mistaken for the user separator.
+fn split_user(s: &str) -> (Option<&str>, &str) {
+    let host_end = s.find([':', '[', '/']).unwrap_or(s.len());
+    match s[..host_end].rfind('@') {
+        Some(at) => (Some(&s[..at]), &s[at + 1..]),
+        None => (None, s),
+    }
+}
+
+// Split off the host, which may be an IPv6 literal in brackets.
+// Returns the host (without brackets) and what follows it.
+fn split_host(s: &str) -> anyhow::Result<(&str, &str)> {
+    if let Some(rest) = s.strip_prefix('[') {
+        let end = rest
+            .find(']')
+            .ok_or_else(|| anyhow!("missing ']' after IPv6 address"))?;
+        let host = &rest[..end];
+        if !host.contains(':') {
+            bail!("'[{host}]' is not an IPv6 address");
+        }
+        return Ok((host, &rest[end + 1..]));
+    }
+    let end = s.find([':', '/']).unwrap_or(s.len());
+    Ok((&s[..end], &s[end..]))
+}
+
+fn parse_port(s: &str) -> anyhow::Result<u16> {
+    match s.parse::<u16>() {
+        Ok(0) | Err(_) => bail!("invalid port '{s}'"),
+        Ok(port) => Ok(port),
+    }
+}
@@ -0,0 +1,1014 @@
+use std::collections::HashMap;
+use std::fs;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::auth::{parse_methods, AuthMethod};
+use crate::config::{parse, Settings, Value};
+use crate::deploy::{
+    check_helper_perms, empty_dirs, follow_links, helper_for, helper_path, helper_state,
+    parse_listing, pull_plan, run_helper, Compare, DeployOptions, Fingerprint, Hash, HelperState,
+    Links, MaxDelete, RemoteListing, Report, S_IFDIR, S_IFREG,
+};
+use crate::filter::{glob_match, Filter};
+use crate::hash::{self, Digest};
+use crate::host_key::HostKeyPolicy;
+use crate::jump::find_proxy_jump;
+use crate::protocol::{decode, ProtocolError, FLAG_MTIME};
+use crate::release::{previous, release_name, to_prune};
+use crate::remote::{is_tmp_path, shell_quote, tmp_path, DirEntry, Remote};
+use crate::ssh::MockSSH;
+use crate::sums::{cksum, sha256, SumTool};
+use crate::target::Target;
+use crate::{checksum_dir, LocalFiles};
+
+#[test]
+fn test_config_parse() {
+    let contents = r#"
+# my sites
+[prod]
+src-dir = "public"   # hugo output
+remote = 'graham@myhost.com:/var/www/#blog/'
+workers = 8
+hidden = true
+helper_dst = "/opt/\"seed\""
+
+["staging"]
+exclude = [
+    "*.map", # source maps
+    'drafts/',
+]
+"#;
+    let parsed = parse(contents).unwrap();
+    let prod = &parsed["prod"];
+    assert_eq!(prod["src-dir"], Value::Str("public".to_string()));
+    assert_eq!(
+        prod["remote"],
+        Value::Str("graham@myhost.com:/var/www/#blog/".to_string())
+    );
+    assert_eq!(prod["workers"], Value::Int(8));
+    assert_eq!(prod["hidden"], Value::Bool(true));
+    assert_eq!(prod["helper-dst"], Value::Str("/opt/\"seed\"".to_string()));
+    assert_eq!(
+        parsed["staging"]["exclude"],
+        Value::List(vec!["*.map".to_string(), "drafts/".to_string()])
+    );
+}
+
+#[test]
+fn test_config_parse_errors() {
+    assert!(parse("workers = 4").is_err()); // outside a section
+    assert!(parse("[prod]\nremote = host:/dir/").is_err()); // unquoted string
+    assert!(parse("[prod\n").is_err());
+    assert!(parse("[prod]\nremote = \"host").is_err());
+    assert!(parse("[prod]\nexclude = [\"a\"").is_err());
+    let err = parse("[prod]\nworkers = 4\nwokers = 8").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "line 3: unknown key 'wokers' in profile [prod]"
+    );
+}
+
+#[test]
+fn test_glob_match() {
+    assert!(glob_match("*.map", "app.js.map"));
+    assert!(!glob_match("*.map", "js/app.js.map"));
+    assert!(glob_match("**/*.map", "js/app.js.map"));
+    assert!(glob_match("a/**/b", "a/b"));
+    assert!(glob_match("a/**/b", "a/x/y/b"));
+    assert!(glob_match("file?.txt", "file1.txt"));
+    assert!(!glob_match("file?.txt", "file10.txt"));
+    assert!(glob_match("[a-c]*", "blog"));
+    assert!(!glob_match("[!a-c]*", "blog"));
+    assert!(glob_match("[]]", "]"));
+    assert!(glob_match("\\*", "*"));
+    assert!(!glob_match("\\*", "x"));
+    assert!(glob_match("[unclosed", "[unclosed"));
+}
+
+#[test]
+fn test_filter() {
+    let f = Filter::new(
+        false,
+        &["/.well-known/".to_string()],
+        &[
+            "*.map".to_string(),
+            "drafts/".to_string(),
+            "/index.xml".to_string(),
+            "tags/*/old".to_string(),
+        ],
+    );
+    assert!(f.is_excluded_path("js/app.js.map"));
+    assert!(f.is_excluded_path("drafts/post.html"));
+    assert!(f.is_excluded_path("blog/drafts/post.html"));
+    assert!(!f.is_excluded_path("drafts")); // a file, not the directory
+    assert!(f.is_excluded_path("index.xml"));
+    assert!(!f.is_excluded_path("blog/index.xml"));
+    assert!(f.is_excluded_path("blog/tags/rust/old"));
+    assert!(!f.is_excluded_path("blog/tags/rust/new"));
+
+    // hidden files are excluded unless included by a rule
+    assert!(f.is_excluded_path(".htaccess"));
+    assert!(f.is_excluded_path("blog/.git/config"));
+    assert!(!f.is_excluded_path(".well-known/security.txt"));
+    assert!(f.is_excluded_path("blog/.well-known/security.txt"));
+
+    let with_hidden = Filter::new(true, &[], &[]);
+    assert!(!with_hidden.is_excluded_path(".htaccess"));
+    assert!(with_hidden.is_excluded_path("blog/.deignore"));
+
+    // --delete-excluded only deletes what a rule excludes
+    assert!(f.is_rule_excluded_path("js/app.js.map"));
+    assert!(f.is_rule_excluded_path("blog/drafts/post.html"));
+    assert!(!f.is_rule_excluded_path(".htaccess"));
+    assert!(!f.is_rule_excluded_path("blog/.deignore"));
+    assert!(!f.is_rule_excluded_path("blog/index.xml"));
+}
+
+#[test]
+fn test_filter_deignore() {
+    let top = std::env::temp_dir().join(format!("de_test_deignore_{}", std::process::id()));
+    fs::create_dir_all(top.join("blog")).unwrap();
+    fs::write(top.join(".deignore"), "# top\n*.tmp\n").unwrap();
+    fs::write(top.join("blog/.deignore"), "/cache/\n!keep.tmp\n").unwrap();
+
+    let mut f = Filter::new(false, &[], &[]);
+    f.load_dir_rules("", &top).unwrap();
+    f.load_dir_rules("blog", &top.join("blog")).unwrap();
+    fs::remove_dir_all(&top).unwrap();
+
+    assert!(f.is_excluded_path("x.tmp"));
+    assert!(f.is_excluded_path("blog/x.tmp"));
+    assert!(!f.is_excluded_path("blog/keep.tmp"));
+    assert!(f.is_excluded_path("keep.tmp"));
+    assert!(f.is_excluded_path("blog/cache/page.html"));
+    assert!(!f.is_excluded_path("cache/page.html"));
+    assert!(!f.is_excluded_path("blog/sub/cache/page.html"));
+}
+
+#[test]
+fn test_checksum_dir_links() {
+    let top = std::env::temp_dir().join(format!("de_test_links_{}", std::process::id()));
+    fs::create_dir_all(top.join("posts")).unwrap();
+    fs::write(top.join("posts/a.html"), "a").unwrap();
+    std::os::unix::fs::symlink("posts", top.join("latest")).unwrap();
+    std::os::unix::fs::symlink("..", top.join("posts/up")).unwrap();
+
+    let walked = checksum_dir(
+        format!("{}/", top.display()).into(),
+        Filter::new(false, &[], &[]),
+        Compare::Checksum,
+        Hash::Xxh3,
+        Links::Copy,
+    );
+    fs::remove_dir_all(&top).unwrap();
+
+    let mut names: Vec<_> = walked.unwrap().0.into_keys().collect();
+    names.sort();
+    // up is a loop, so neither posts/up nor latest/up is walked
+    assert_eq!(names, ["latest/a.html", "posts/a.html"]);
+}
+
+fn target(user: Option<&str>, host: &str, port: Option<u16>, path: &str) -> Target {
+    Target {
+        user: user.map(|u| u.to_string()),
+        host: host.to_string(),
+        port,
+        path: path.to_string(),
+    }
+}
+
+// The defaults from the command line, for the functions that take them
+fn deploy_options() -> DeployOptions {
+    DeployOptions {
+        local_dir: "public/".to_string(),
+        helper_dst: "/tmp/de-{uid}/seed".to_string(),
+        is_cleanup_helper: false,
+        is_no_helper: false,
+        num_workers: 1,
+        auth_methods: Vec::new(),
+        identity: None,
+        host_key: HostKeyPolicy::Ask,
+        jump: None,
+        compare: Compare::Checksum,
+        hash: Hash::Xxh3,
+        links: Links::Copy,
+        is_dry_run: false,
+        is_atomic: false,
+        keep: 5,
+        is_no_delete: false,
+        is_delete_excluded: false,
+        max_delete: None,
+        is_delete_local: false,
+        verbose: false,
+        is_multi: false,
+    }
+}
+
+#[test]
+fn test_target_scp_style() {
+    let cases = [
+        ("host:/srv/", target(None, "host", None, "/srv/")),
+        (
+            "user@host:/srv/",
+            target(Some("user"), "host", None, "/srv/"),
+        ),
+        ("user@host:www", target(Some("user"), "host", None, "www")),
+        ("host:2222:/path", target(None, "host", Some(2222), "/path")),
+        (
+            "user@host:2222:/path/",
+            target(Some("user"), "host", Some(2222), "/path/"),
+        ),
+        // port needs a second ':', otherwise it's the path
+        ("host:2222", target(None, "host", None, "2222")),
+        // '@' in the path is not the user
+        ("host:/srv/a@b/", target(None, "host", None, "/srv/a@b/")),
+        (
+            "user@host:/srv/a@b/",
+            target(Some("user"), "host", None, "/srv/a@b/"),
+        ),
+        // '@' in the user name
+        (
+            "me@example.com@host:/srv/",
+            target(Some("me@example.com"), "host", None, "/srv/"),
+        ),
+        // ':' in the path after the port
+        ("host:22:/a:b/", target(None, "host", Some(22), "/a:b/")),
+        ("host:/a:b/", target(None, "host", None, "/a:b/")),
+        ("10.0.0.1:/srv/", target(None, "10.0.0.1", None, "/srv/")),
+    ];
+    for (input, expected) in cases {
+        assert_eq!(Target::parse(input).unwrap(), expected, "{input}");
+    }
+}
+
+#[test]
+fn test_target_ipv6() {
+    let cases = [
+        (
+            "user@[2001:db8::1]:/srv/",
+            target(Some("user"), "2001:db8::1", None, "/srv/"),
+        ),
+        ("[::1]:/srv/", target(None, "::1", None, "/srv/")),
+        (
+            "[2001:db8::1]:2222:/srv/",
+            target(None, "2001:db8::1", Some(2222), "/srv/"),
+        ),
+        (
+            "ssh://user@[2001:db8::1]:2222/srv/www/",
+            target(Some("user"), "2001:db8::1", Some(2222), "/srv/www/"),
+        ),
+        (
+            "ssh://[fe80::1%eth0]/srv/",
+            target(None, "fe80::1%eth0", None, "/srv/"),
+        ),
+    ];
+    for (input, expected) in cases {
+        assert_eq!(Target::parse(input).unwrap(), expected, "{input}");
+    }
+}
+
+#[test]
+fn test_target_url() {
+    let cases = [
+        (
+            "ssh://user@host:2222/srv/www/",
+            target(Some("user"), "host", Some(2222), "/srv/www/"),
+        ),
+        ("ssh://host/srv/", target(None, "host", None, "/srv/")),
+        (
+            "ssh://user@host/srv/",
+            target(Some("user"), "host", None, "/srv/"),
+        ),
+        (
+            "ssh://host:22/a@b/",
+            target(None, "host", Some(22), "/a@b/"),
+        ),
+    ];
+    for (input, expected) in cases {
+        assert_eq!(Target::parse(input).unwrap(), expected, "{input}");
+    }
+}
+
+#[test]
+fn test_target_errors() {
+    let bad = [
+        "host",                    // no path
+        "host:",                   // empty path
+        "user@host",               // no path
+        "@host:/srv/",             // empty user
+        ":/srv/",                  // no host
+        "host:0:/srv/",            // port 0
+        "host:70000:/srv/",        // port too big
+        "[2001:db8::1/srv/",       // unclosed bracket
+        "[2001:db8::1]/srv/",      // no ':' after bracket
+        "[host]:/srv/",            // brackets but not IPv6
+        "ssh://host",              // no path
+        "ssh://host:/srv/",        // empty port
+        "ssh://host:abc/srv/",     // bad port
+        "ssh://host:22srv/",       // bad port
+        "ssh://user@/srv/",        // no host
+        "ssh://[2001:db8::1]srv/", // missing '/'
+        "my host:/srv/",           // space in host
+    ];
+    for input in bad {
+        assert!(Target::parse(input).is_err(), "{input} should be an error");
+    }
+}
+
+#[test]
+fn test_target_display() {
+    for input in [
+        "user@host:/srv/",
+        "host:/srv/",
+        "user@[2001:db8::1]:/srv/",
+        "ssh://user@host:2222/srv/",
+        "ssh://[::1]:2222/srv/",
+    ] {
+        let t = Target::parse(input).unwrap();
+        assert_eq!(t.to_string(), input);
+        assert_eq!(Target::parse(&t.to_string()).unwrap(), t);
+    }
+}
+
+#[test]
+fn test_parse_auth_methods() {
+    assert_eq!(
+        parse_methods("key, kbdint,password").unwrap(),
+        vec![
+            AuthMethod::Key,
+            AuthMethod::KeyboardInteractive,
+            AuthMethod::Password
+        ]
+    );
+    assert_eq!(parse_methods("agent").unwrap(), vec![AuthMethod::Agent]);
+    assert!(parse_methods("").is_err());
+    assert!(parse_methods("agent,hostbased").is_err());
+}
+
+#[test]
+fn test_host_key_policy() {
+    assert_eq!(HostKeyPolicy::new(&[], false).unwrap(), HostKeyPolicy::Ask);
+    assert_eq!(
+        HostKeyPolicy::new(&[], true).unwrap(),
+        HostKeyPolicy::AcceptNew
+    );
+    assert_eq!(
+        HostKeyPolicy::new(&["SHA256:abc".to_string(), " def".to_string()], false).unwrap(),
+        HostKeyPolicy::Fingerprint(vec!["SHA256:abc".to_string(), "SHA256:def".to_string()])
+    );
+    assert!(HostKeyPolicy::new(&["SHA256:abc".to_string()], true).is_err());
+}
+
+#[test]
+fn test_target_parse_host() {
+    let t = Target::parse_host("ops@bastion.example.com:2200").unwrap();
+    assert_eq!(t.user.as_deref(), Some("ops"));
+    assert_eq!(t.host, "bastion.example.com");
+    assert_eq!(t.port, Some(2200));
+    assert_eq!(t.path, "");
+    let t = Target::parse_host("[2001:db8::1]").unwrap();
+    assert_eq!(t.host, "2001:db8::1");
+    assert_eq!(t.port, None);
+    assert!(Target::parse_host("bastion:/srv/").is_err());
+    assert!(Target::parse_host("@bastion").is_err());
+}
+
+#[test]
+fn test_find_proxy_jump() {
+    let config = "
+ServerAliveInterval 30
+
+Host db* !db-public
+    ProxyCommand nc -x proxy:1080 %h %p
+
+Host *.internal web?
+  ProxyJump=ops@bastion:2200
+
+Host *
+    ProxyJump none
+";
+    assert_eq!(
+        find_proxy_jump(config, "app.internal").as_deref(),
+        Some("ops@bastion:2200")
+    );
+    assert_eq!(
+        find_proxy_jump(config, "WEB1").as_deref(),
+        Some("ops@bastion:2200")
+    );
+    // ProxyCommand first, libssh runs it
+    assert_eq!(find_proxy_jump(config, "db1").as_deref(), Some("none"));
+    assert_eq!(
+        find_proxy_jump(config, "db-public").as_deref(),
+        Some("none")
+    );
+    assert_eq!(find_proxy_jump("Host a\n ProxyJump b\n", "c"), None);
+}
+
+#[test]
+fn test_target_is_remote() {
+    for remote in [
+        "host:/srv/",
+        "user@host:dir",
+        "[::1]:/srv/",
+        "ssh://host/srv/",
+    ] {
+        assert!(Target::is_remote(remote), "{remote}");
+    }
+    for local in ["public/", "/home/graham/site", "./a:b", "dir/with:colon"] {
+        assert!(!Target::is_remote(local), "{local}");
+    }
+}
+
+#[test]
+fn test_release_name() {
+    let t = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    assert_eq!(release_name(t), "20231114-221320");
+    assert_eq!(release_name(UNIX_EPOCH), "19700101-000000");
+    // leap day
+    let t = UNIX_EPOCH + Duration::from_secs(951_782_400 + 3600 + 61);
+    assert_eq!(release_name(t), "20000229-010101");
+}
+
+#[test]
+fn test_releases_to_prune() {
+    let names: Vec<String> = ["a", "b", "c", "d", "e"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    assert_eq!(to_prune(&names, "e", 3), vec!["a", "b"]);
+    assert_eq!(to_prune(&names, "e", 5), Vec::<String>::new());
+    // newer than current (after a rollback) are not counted or deleted
+    assert_eq!(to_prune(&names, "c", 2), vec!["a"]);
+    // current is always kept
+    assert_eq!(to_prune(&names, "e", 0), vec!["a", "b", "c", "d"]);
+    assert_eq!(to_prune(&names, "x", 1), Vec::<String>::new());
+
+    assert_eq!(previous(&names, "c"), Some("b"));
+    assert_eq!(previous(&names, "a"), None);
+    assert_eq!(previous(&names, "x"), None);
+}
+
+#[test]
+fn test_shell_quote() {
+    assert_eq!(shell_quote("/var/www/"), "'/var/www/'");
+    assert_eq!(shell_quote("it's"), r"'it'\''s'");
+}
+
+#[test]
+fn test_settings_from_cli() {
+    let args = clap::Command::new("de")
+        .arg(clap::arg!(--"dry-run" "").required(false))
+        .arg(
+            clap::arg!(--exclude <PATTERN> "")
+                .required(false)
+                .multiple_occurrences(true),
+        )
+        .arg(clap::arg!(--workers <NUM> "").required(false))
+        .get_matches_from([
+            "de",
+            "--dry-run",
+            "--exclude",
+            "a",
+            "--exclude",
+            "b",
+            "--workers",
+            "2",
+        ]);
+    let settings = Settings::new(&args, None, None);
+    assert!(settings.flag("dry-run").unwrap());
+    assert_eq!(settings.list("exclude").unwrap(), vec!["a", "b"]);
+    assert_eq!(settings.usize("workers").unwrap(), 2);
+
+    // a single value that looks like a number or bool is still a list
+    let args = clap::Command::new("de")
+        .arg(clap::arg!(--exclude <PATTERN> "").required(false))
+        .arg(clap::arg!(--include <PATTERN> "").required(false))
+        .get_matches_from(["de", "--exclude", "2024", "--include", "true"]);
+    let settings = Settings::new(&args, None, None);
+    assert_eq!(settings.list("exclude").unwrap(), vec!["2024"]);
+    assert_eq!(settings.list("include").unwrap(), vec!["true"]);
+}
+
+#[test]
+fn test_tmp_path() {
+    assert_eq!(
+        tmp_path("/var/www/index.html"),
+        "/var/www/.de-tmp.index.html"
+    );
+    assert_eq!(tmp_path("seed"), ".de-tmp.seed");
+    assert!(is_tmp_path("/var/www/.de-tmp.index.html"));
+    assert!(is_tmp_path("css/.de-tmp.site.css"));
+    assert!(!is_tmp_path("css/site.css"));
+    assert!(!is_tmp_path(".de-tmp/site.css"));
+}
+
+#[test]
+fn test_max_delete() {
+    assert_eq!(MaxDelete::parse("10").unwrap(), MaxDelete::Count(10));
+    assert_eq!(MaxDelete::parse("25%").unwrap(), MaxDelete::Percent(25.0));
+    assert_eq!(MaxDelete::parse(" 0.5 %").unwrap(), MaxDelete::Percent(0.5));
+    for bad in ["", "ten", "-1", "150%", "%"] {
+        assert!(MaxDelete::parse(bad).is_err(), "{bad}");
+    }
+
+    assert!(MaxDelete::Count(10).check(10, 20).is_ok());
+    assert!(MaxDelete::Count(10).check(11, 1000).is_err());
+    assert!(MaxDelete::Percent(50.0).check(50, 100).is_ok());
+    assert!(MaxDelete::Percent(50.0).check(51, 100).is_err());
+    assert!(MaxDelete::Percent(0.0).check(0, 0).is_ok());
+}
+
+#[test]
+fn test_empty_dirs() {
+    let deleted = [
+        "tags/old/index.html",
+        "tags/old/page/2/index.html",
+        "tags/rust/index.html",
+        "posts/a.html",
+        "top.html",
+    ];
+    let kept = ["tags/rust/feed.xml", "posts/b.html", "index.html"];
+    assert_eq!(
+        empty_dirs(&deleted, kept.into_iter()),
+        vec!["tags/old/page/2", "tags/old/page", "tags/old"]
+    );
+    assert_eq!(empty_dirs(&["a/b.html"], std::iter::empty()), vec!["a"]);
+}
+
+// (kind, name, mode, size, mtime, digest)
+type HelperRecord<'a> = (u8, &'a str, u32, u64, u64, Option<Digest>);
+
+// A listing as the helper sends it, using XXH3
+fn helper_listing(flags: u8, records: &[HelperRecord]) -> Vec<u8> {
+    let mut out = b"DMTR".to_vec();
+    out.extend_from_slice(&2u16.to_le_bytes());
+    out.extend_from_slice(&[flags, hash::XXH3]);
+    let end = (b'E', "", 0, records.len() as u64, 0, None);
+    for &(kind, name, mode, size, mtime, digest) in records.iter().chain([&end]) {
+        let digest = digest
+            .as_ref()
+            .map_or(&[][..], |d| &d.bytes[..d.len as usize]);
+        out.extend_from_slice(&[kind, digest.len() as u8]);
+        out.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        out.extend_from_slice(&mode.to_le_bytes());
+        out.extend_from_slice(&size.to_le_bytes());
+        out.extend_from_slice(&mtime.to_le_bytes());
+        out.extend_from_slice(digest);
+        out.extend_from_slice(name.as_bytes());
+    }
+    out
+}
+
+#[test]
+fn test_parse_listing() {
+    let index = Hash::Xxh3.digest(b"<html></html>");
+    let notes = Hash::Xxh3.digest(b"hi\n");
+    let output = helper_listing(
+        0,
+        &[
+            (b'F', "index.html", 0o644, 13, 1792210067, Some(index)),
+            (b'F', "notes:2024\nold", 0o640, 3, 1792210068, Some(notes)),
+        ],
+    );
+    assert_eq!(
+        parse_listing(&output, Compare::Checksum, Hash::Xxh3).unwrap(),
+        vec![
+            ("index.html", Fingerprint::Hash(index), 0o644, 13),
+            ("notes:2024\nold", Fingerprint::Hash(notes), 0o640, 3),
+        ]
+    );
+
+    let target = Hash::Xxh3.digest(b"y");
+    let output = helper_listing(
+        FLAG_MTIME,
+        &[
+            (b'F', "a/x", 0o755, 3, 1792210067, None),
+            (b'L', "lnk", 0, 1, 0, Some(target)),
+        ],
+    );
+    assert_eq!(
+        parse_listing(&output, Compare::MtimeSize, Hash::Xxh3).unwrap(),
+        vec![
+            ("a/x", Fingerprint::MtimeSize(1792210067, 3), 0o755, 3),
+            ("lnk", Fingerprint::Link(target), 0, 1),
+        ]
+    );
+    assert_eq!(
+        parse_listing(&helper_listing(0, &[]), Compare::Checksum, Hash::Xxh3).unwrap(),
+        vec![]
+    );
+    assert!(Compare::parse("mtime").is_err());
+}
+
+// MockSSH reads every remote file as empty
+#[test]
+fn test_sftp_listing_entries() {
+    let target = Hash::Xxh3.digest(b"y");
+    let listing = RemoteListing::Sftp {
+        dir: "/var/www/".to_string(),
+        files: vec![
+            ("same".to_string(), Fingerprint::MtimeSize(1, 0), 0o644, 0),
+            ("bigger".to_string(), Fingerprint::MtimeSize(2, 9), 0o644, 9),
+            ("lnk".to_string(), Fingerprint::Link(target), 0, 1),
+        ],
+        sums: None,
+    };
+    let mut local = LocalFiles::new();
+    local.insert("same".to_string(), (Fingerprint::MtimeSize(1, 0), 0, 0o644));
+    local.insert(
+        "bigger".to_string(),
+        (Fingerprint::MtimeSize(2, 3), 3, 0o644),
+    );
+    let ssh = MockSSH {
+        label: String::new(),
+    };
+
+    let entries = listing
+        .entries(&ssh, "", &local, Compare::MtimeSize, Hash::Xxh3)
+        .unwrap();
+    assert_eq!(entries[0], ("same", Fingerprint::MtimeSize(1, 0), 0o644, 0));
+    assert_eq!(entries[2], ("lnk", Fingerprint::Link(target), 0, 1));
+
+    let entries = listing
+        .entries(&ssh, "", &local, Compare::Checksum, Hash::Xxh3)
+        .unwrap();
+    // only read when the sizes match
+    assert_eq!(entries[0].1, Fingerprint::Hash(Hash::Xxh3.digest(b"")));
+    assert!(matches!(entries[1].1, Fingerprint::Hash(d) if d.len == 0));
+    assert_eq!(entries[2].1, Fingerprint::Link(target));
+}
+
+// A server with only these regular files on it, for the functions that read
+// from a Remote. Changing anything isn't expected.
+struct FakeRemote {
+    files: HashMap<String, Vec<u8>>,
+}
+
+impl Remote for FakeRemote {
+    fn run_remote_cmd_bytes(&self, cmd: &str) -> anyhow::Result<(Vec<u8>, i32)> {
+        unimplemented!("run {cmd}")
+    }
+    fn mkdir(&self, dir: &str, _: u32) -> anyhow::Result<()> {
+        unimplemented!("mkdir {dir}")
+    }
+    fn upload(&self, _: &str, dst: &str) -> anyhow::Result<()> {
+        unimplemented!("upload {dst}")
+    }
+    fn upload_bytes(&self, _: &[u8], dst: &str) -> anyhow::Result<()> {
+        unimplemented!("upload {dst}")
+    }
+    fn download(&self, src: &str, _: &str) -> anyhow::Result<()> {
+        unimplemented!("download {src}")
+    }
+    fn delete(&self, path: &str) -> anyhow::Result<()> {
+        unimplemented!("delete {path}")
+    }
+    fn chmod(&self, path: &str, _: u32) -> anyhow::Result<()> {
+        unimplemented!("chmod {path}")
+    }
+    fn rmdir(&self, dir: &str) -> anyhow::Result<()> {
+        unimplemented!("rmdir {dir}")
+    }
+    fn symlink(&self, _: &str, link: &str) -> anyhow::Result<()> {
+        unimplemented!("symlink {link}")
+    }
+    fn rename(&self, from: &str, _: &str) -> anyhow::Result<()> {
+        unimplemented!("rename {from}")
+    }
+    fn read_link(&self, path: &str) -> anyhow::Result<Option<String>> {
+        unimplemented!("readlink {path}")
+    }
+    fn lstat(&self, path: &str) -> anyhow::Result<Option<(Option<u32>, u32)>> {
+        unimplemented!("lstat {path}")
+    }
+    fn stat(&self, path: &str) -> anyhow::Result<Option<(u32, u64, u64)>> {
+        Ok(self
+            .files
+            .get(path)
+            .map(|bytes| (S_IFREG | 0o644, bytes.len() as u64, 0)))
+    }
+    fn read_file(&self, path: &str) -> anyhow::Result<Vec<u8>> {
+        Ok(self.files[path].clone())
+    }
+    fn read_dir(&self, dir: &str) -> anyhow::Result<Vec<DirEntry>> {
+        unimplemented!("readdir {dir}")
+    }
+}
+
+// With --links copy a remote link is pulled as a regular file, and the next
+// pull must see that file as the same as the link
+#[test]
+fn test_pull_links_copy() {
+    let ssh = FakeRemote {
+        files: HashMap::from([("/var/www/latest.html".to_string(), b"post".to_vec())]),
+    };
+    let link = Fingerprint::Link(Hash::Xxh3.digest(b"posts/1.html"));
+    let plan = |local: &LocalFiles, compare| {
+        let mut remote = HashMap::from([("latest.html", (link, 0, 12))]);
+        follow_links(&ssh, "/var/www/", &mut remote, local, compare, Hash::Xxh3).unwrap();
+        pull_plan(&remote, local).0
+    };
+    for (compare, pulled) in [
+        (
+            Compare::Checksum,
+            Fingerprint::Hash(Hash::Xxh3.digest(b"post")),
+        ),
+        (Compare::MtimeSize, Fingerprint::MtimeSize(0, 4)),
+    ] {
+        let mut local = LocalFiles::new();
+        assert_eq!(plan(&local, compare), ["latest.html"]);
+        // the downloaded file, as checksum_dir sees it next time
+        local.insert("latest.html".to_string(), (pulled, 4, 0o644));
+        assert!(plan(&local, compare).is_empty(), "{compare:?}");
+    }
+}
+
+// Known answers from coreutils
+#[test]
+fn test_sums() {
+    assert_eq!(cksum(b""), 4294967295);
+    assert_eq!(cksum(b"hello\n"), 3015617425);
+    assert_eq!(cksum(&[b'a'; 1000]), 145108180);
+    let hex = |bytes: [u8; 32]| bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+    assert_eq!(
+        hex(sha256(b"hello\n")),
+        "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03"
+    );
+    assert_eq!(
+        hex(sha256(&[b'a'; 1000])),
+        "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3"
+    );
+
+    let output = "2d711642b726b04401627ca9fbac32f5c8530fb1903cc4db02258717921a4881  ./a b\n\
+        \\a1fce4363854ff888cff4b8e7875d600c2682390412a8cf79b37d0b11148b0fa  ./back\\\\slash\n\
+        \\594e519ae499312b29433b7dd8a97ff068defcba9755b6d5d00e84c524d67b06  ./back\\\\nslash\\nline\n\
+        not a sum\n";
+    let sums = SumTool::Sha256sum.parse(output);
+    assert_eq!(sums.len(), 3);
+    assert_eq!(sums["a b"], SumTool::Sha256sum.digest(b"x"));
+    assert_eq!(sums["back\\slash"], SumTool::Sha256sum.digest(b"y"));
+    assert_eq!(sums["back\\nslash\nline"], SumTool::Sha256sum.digest(b"z"));
+
+    let sums = SumTool::Cksum.parse("12738659 1 ./a b\n");
+    assert_eq!(sums["a b"], SumTool::Cksum.digest(b"x"));
+}
+
+#[test]
+fn test_report_plan() {
+    let report = Report {
+        num_files: 3,
+        bytes_added: 2048,
+        bytes_changed: 10,
+        num_deleted: 1,
+        bytes_removed: 3 * 1024 * 1024,
+        ..Default::default()
+    };
+    assert_eq!(
+        report.plan("Upload"),
+        "Upload 3 files (2 KiB new, 10 bytes changed), chmod 0, delete 1 files (3 MiB)"
+    );
+}
+
+#[test]
+fn test_protocol_errors() {
+    let digest = Some(Hash::Xxh3.digest(b"<html></html>"));
+    let output = helper_listing(0, &[(b'F', "index.html", 0o644, 13, 0, digest)]);
+    let x = hash::XXH3;
+    assert_eq!(
+        decode(&output, FLAG_MTIME, x).unwrap_err(),
+        ProtocolError::Flags(0)
+    );
+    assert_eq!(
+        decode(&output, 0, hash::BLAKE3).unwrap_err(),
+        ProtocolError::Hash(x)
+    );
+    for len in [3, 8, 20, 33, output.len() - 1] {
+        assert!(decode(&output[..len], 0, x).is_err(), "{len}");
+    }
+    assert_eq!(
+        decode(&output[..output.len() - 24], 0, x).unwrap_err(),
+        ProtocolError::Truncated
+    );
+
+    let mut old = output.clone();
+    old[4] = 1;
+    assert_eq!(decode(&old, 0, x).unwrap_err(), ProtocolError::Version(1));
+
+    let mut bad_count = output.clone();
+    let count_pos = output.len() - 16;
+    bad_count[count_pos] = 2;
+    assert_eq!(
+        decode(&bad_count, 0, x).unwrap_err(),
+        ProtocolError::Count(2, 1)
+    );
+
+    let mut extra = output.clone();
+    extra.push(0);
+    assert_eq!(
+        decode(&extra, 0, x).unwrap_err(),
+        ProtocolError::TrailingData
+    );
+
+    let mut long_digest = output.clone();
+    long_digest[9] = 33;
+    assert_eq!(
+        decode(&long_digest, 0, x).unwrap_err(),
+        ProtocolError::DigestLen(33, 8)
+    );
+
+    let mut bad_kind = output;
+    bad_kind[8] = b'X';
+    assert_eq!(
+        decode(&bad_kind, 0, x).unwrap_err(),
+        ProtocolError::Kind(b'X', 8)
+    );
+
+    assert_eq!(
+        decode(b"sh: /tmp/seed: not found\n", 0, x).unwrap_err(),
+        ProtocolError::NotHelper("sh: /tmp/seed: not found".to_string())
+    );
+}
+
+#[test]
+fn test_links() {
+    assert_eq!(Links::parse("preserve").unwrap(), Links::Preserve);
+    assert!(Links::parse("follow").is_err());
+}
+
+#[test]
+fn test_helper_for() {
+    for machine in ["x86_64\n", "aarch64\n", "riscv64"] {
+        assert!(helper_for(machine).is_ok(), "{machine}");
+    }
+    assert_eq!(
+        helper_for("armv7l\n").unwrap_err().to_string(),
+        "no helper for architecture 'armv7l', only for x86_64, aarch64, riscv64"
+    );
+    assert!(helper_for("").is_err());
+}
+
+#[test]
+fn test_helper_state() {
+    let helper = b"\x7fELF pretend helper";
+    assert_eq!(helper_state(helper, helper), HelperState::Current);
+    assert_eq!(
+        helper_state(b"\x7fELF another build", helper),
+        HelperState::Different
+    );
+    assert_eq!(helper_state(b"", helper), HelperState::Different);
+}
+
+#[test]
+fn test_check_helper_perms() {
+    assert_eq!(helper_path("/tmp/de-{uid}/seed", 1000), "/tmp/de-1000/seed");
+    assert_eq!(helper_path("/opt/seed", 1000), "/opt/seed");
+
+    let ok = |(owner, mode), file_type| {
+        check_helper_perms("p", Some((Some(owner), mode)), 1000, file_type).is_ok()
+    };
+    assert!(ok((1000, S_IFDIR | 0o700), S_IFDIR));
+    assert!(ok((0, S_IFDIR | 0o755), S_IFDIR)); // e.g. /opt
+    assert!(ok((1000, S_IFREG | 0o700), S_IFREG));
+    assert!(!ok((0, S_IFDIR | 0o1777), S_IFDIR)); // /tmp itself
+    assert!(!ok((1001, S_IFDIR | 0o700), S_IFDIR));
+    assert!(!ok((1000, S_IFREG | 0o720), S_IFREG));
+    assert!(!ok((1000, 0o120000 | 0o777), S_IFREG)); // symlink
+    assert!(!ok((1000, S_IFDIR | 0o700), S_IFREG));
+    assert!(check_helper_perms("p", None, 1000, S_IFDIR).is_err());
+    // the server didn't send the owner
+    assert!(check_helper_perms("p", Some((None, S_IFDIR | 0o700)), 1000, S_IFDIR).is_err());
+}
+
+// An SFTP only account runs sftp-server whatever the command, which prints
+// nothing and exits once stdin is closed. That's not a shell, so list over SFTP.
+#[test]
+fn test_run_helper_no_shell() {
+    let ssh = MockSSH {
+        label: String::new(),
+    };
+    let target = target(None, "example.com", None, "/var/www/");
+    let opts = deploy_options();
+    assert!(run_helper(&ssh, &target, "/var/www/", &opts)
+        .unwrap()
+        .is_none());
+}
+
+// Known answers from the reference implementations, for bytes i % 251.
+// Lengths either side of each code path, most not a multiple of 8.
+#[test]
+fn test_hash() {
+    let cases: [(usize, u32, u64, &str); 10] = [
+        (
+            0,
+            0,
+            0x2d06800538d394c2,
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+        ),
+        (
+            1,
+            0x527d5351,
+            0xc44bdff4074eecdb,
+            "2d3adedff11b61f14c886e35afa036736dcd87a74d27b5c1510225d0f592e213",
+        ),
+        (
+            3,
+            0x92fd4bfa,
+            0x5f4299fc161c9cbb,
+            "e1be4d7a8ab5560aa4199eea339849ba8e293d55ca0a81006726d184519e647f",
+        ),
+        (
+            7,
+            0xa359ed4c,
+            0x0cd2084a62406b69,
+            "3f8770f387faad08faa9d8414e9f449ac68e6ff0417f673f602a646a891419fe",
+        ),
+        (
+            9,
+            0x7144c5a8,
+            0xe9612598145bb9dc,
+            "a0fc27e5d7318b723207637bdeeba4f7dcb22f7f9ec3e8b6f3588ddcd4fdf861",
+        ),
+        (
+            17,
+            0x38435e17,
+            0x9ef341a99de37328,
+            "8462aa7be93b09fda7b93cf9f9cddb703f6dd2cc0c8edd5f9eee092edf8abf0c",
+        ),
+        (
+            129,
+            0xf514629f,
+            0xec7642b431ba3e5a,
+            "683aaae9f3c5ba37eaaf072aed0f9e30bac0865137bae68b1fde4ca2aebdcb12",
+        ),
+        (
+            241,
+            0x54fe7516,
+            0x02e8cd95421c6d02,
+            "749b36ae651c22e8567db692a6876e0ca4fd3daeb7aa8fa3ab2f642ccc69a8f6",
+        ),
+        (
+            1025,
+            0xc8d03add,
+            0xe95c42288f28186e,
+            "d00278ae47eb27b34faecf67b4fe263f82d5412916c1ffd97c8cb7fb814b8444",
+        ),
+        (
+            5000,
+            0x83f68e3a,
+            0xb418500fc42320ee,
+            "ee78d92070de3df1c57c37002abf0a6b1a6589acdeef4d8ffac7cf3d9e8f2836",
+        ),
+    ];
+    let data: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+    for (len, crc32c, xxh3, blake3) in cases {
+        let bytes = &data[..len];
+        let d = Hash::Crc32c.digest(bytes);
+        assert_eq!(d.len, 4);
+        assert_eq!(&d.bytes[..4], &crc32c.to_le_bytes(), "crc32c {len}");
+        let d = Hash::Xxh3.digest(bytes);
+        assert_eq!(d.len, 8);
+        assert_eq!(&d.bytes[..8], &xxh3.to_le_bytes(), "xxh3 {len}");
+        let d = Hash::Blake3.digest(bytes);
+        let hex: String = d.bytes.iter().map(|b| format!("{b:02x}")).collect();
+        assert_eq!((d.len, hex.as_str()), (32, blake3), "blake3 {len}");
+    }
+
+    // Read a buffer at a time, either side of where the buffer fills
+    let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+    for len in [
+        0,
+        1,
+        1025,
+        65535,
+        65536,
+        65537,
+        65536 + 1024,
+        66000,
+        200_000,
+    ] {
+        for hash in [Hash::Crc32c, Hash::Xxh3, Hash::Blake3] {
+            let bytes = &data[..len];
+            let streamed = hash.digest_read(&mut &bytes[..]).unwrap();
+            assert_eq!(streamed, hash.digest(bytes), "{hash:?} {len}");
+        }
+    }
+
+    // the standard check value, in hardware and without it
+    assert_eq!(
+        &Hash::Crc32c.digest(b"123456789").bytes[..4],
+        &0xE3069283u32.to_le_bytes()
+    );
+    assert_eq!(
+        unsafe { hash::crc32c_sw(0, data.as_ptr(), 5000) },
+        0x83f68e3a
+    );
+    // only the given bytes count, not whatever follows them
+    assert_eq!(
+        Hash::Crc32c.digest(&b"abc\0"[..3]),
+        Hash::Crc32c.digest(&b"abcX"[..3])
+    );
+    assert_ne!(Hash::Crc32c.digest(b"abc"), Hash::Crc32c.digest(b"abc\0"));
+
+    assert_eq!(Hash::parse("blake3").unwrap(), Hash::Blake3);
+    assert!(Hash::parse("crc32").is_err());
+}
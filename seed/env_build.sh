@@ -1,10 +1,22 @@
 #!/bin/bash
 #
-# Source this for 'cargo build'
+# Source this for 'cargo build', with the target triple to build for, e.g.
+#   source env_build.sh aarch64-unknown-linux-gnu
 # Do not source this for 'cargo test'
 #
-# Adjust target-cpu to match you server. Find it like this:
-# `gcc -march=native -Q --help=target | grep march`
+# rust-lld links the helper for every architecture, so no cross compiler is
+# needed. core is built with -Zbuild-std, which needs
+# `rustup component add rust-src` on the nightly toolchain. See ../build.sh.
+#
+# target-cpu is the baseline so that the helper runs on any server, it checks for
+# SSE4.2 (x86-64) or the CRC32 extension (aarch64) itself. If all your servers are
+# newer you can raise it, find theirs like this: `gcc -march=native -Q --help=target | grep march`
+
+TARGET=${1:-x86_64-unknown-linux-gnu}
+case $TARGET in
+	x86_64-*) CPU="-Ctarget-cpu=x86-64" ;;
+	aarch64-*) CPU="-Ctarget-cpu=generic" ;;
+	*) CPU="" ;; # riscv64gc
+esac
 
-# try adding --no-pie in the comma separated link-args
-export RUSTFLAGS="-Ctarget-cpu=core-avx2 -Clink-args=-nostartfiles -Crelocation-model=static -Clink-args=-Wl,-n,-N,--no-dynamic-linker,--build-id=none,--no-eh-frame-hdr"
+export RUSTFLAGS="$CPU -Clinker=rust-lld -Crelocation-model=static -Clink-args=-n -Clink-args=-N -Clink-args=--no-dynamic-linker -Clink-args=--build-id=none -Clink-args=--no-eh-frame-hdr"
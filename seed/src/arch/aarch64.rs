@@ -0,0 +1,157 @@
+//
+// Part of Demeter Deploy.
+//
+// aarch64 (arm64): entry point and syscall calling convention. The syscall
+// numbers and struct stat are the generic ones, see generic.rs.
+//
+
+pub use crate::generic::*;
+
+use crate::hash;
+use core::arch::asm;
+#[cfg(not(test))]
+use core::arch::global_asm;
+use core::ffi::c_char;
+
+// Not the generic one, that's O_DIRECT here. /usr/include/asm/fcntl.h
+pub const O_DIRECTORY: i32 = 0o40000;
+
+// /usr/include/linux/auxvec.h and asm/hwcap.h
+const AT_NULL: usize = 0;
+const AT_HWCAP: usize = 16;
+const HWCAP_CRC32: usize = 1 << 7;
+
+// sp is the argc, then the params
+#[cfg(not(test))]
+global_asm!(
+    ".global _start",
+    "_start:",
+    "  ldr x0, [sp]",      // argc
+    "  ldr x1, [sp, #16]", // addr of param 0, skipping the program name
+    "  ldr x2, [sp, #24]", // addr of param 1, null if there isn't one
+    "  mov x3, sp",
+    "  add x3, x3, x0, lsl #3",
+    "  add x3, x3, #16", // environment, after the params and their null
+    "  bl enter",
+    "  brk #0",
+);
+
+// The auxiliary vector follows the environment. Its AT_HWCAP says whether the
+// CPU has the CRC32 instructions, which are optional before ARMv8.1.
+pub unsafe fn init(envp: *const *const c_char) {
+    let mut env = envp;
+    while !(*env).is_null() {
+        env = env.add(1);
+    }
+    let mut aux = env.add(1) as *const usize;
+    while *aux != AT_NULL {
+        if *aux == AT_HWCAP {
+            hash::set_has_crc32(*aux.add(1) & HWCAP_CRC32 != 0);
+            return;
+        }
+        aux = aux.add(2);
+    }
+}
+
+#[inline(always)]
+pub unsafe fn syscall1(n: usize, a1: usize) -> isize {
+    let ret: isize;
+    asm!("svc 0",
+        in("x8") n,
+        inlateout("x0") a1 as isize => ret,
+        options(nostack),
+    );
+    ret
+}
+
+#[inline(always)]
+pub unsafe fn syscall2(n: usize, a1: usize, a2: usize) -> isize {
+    let ret: isize;
+    asm!("svc 0",
+        in("x8") n,
+        inlateout("x0") a1 as isize => ret,
+        in("x1") a2,
+        options(nostack),
+    );
+    ret
+}
+
+#[inline(always)]
+pub unsafe fn syscall3(n: usize, a1: usize, a2: usize, a3: usize) -> isize {
+    let ret: isize;
+    asm!("svc 0",
+        in("x8") n,
+        inlateout("x0") a1 as isize => ret,
+        in("x1") a2,
+        in("x2") a3,
+        options(nostack),
+    );
+    ret
+}
+
+#[inline(always)]
+pub unsafe fn syscall4(n: usize, a1: usize, a2: usize, a3: usize, a4: usize) -> isize {
+    let ret: isize;
+    asm!("svc 0",
+        in("x8") n,
+        inlateout("x0") a1 as isize => ret,
+        in("x1") a2,
+        in("x2") a3,
+        in("x3") a4,
+        options(nostack),
+    );
+    ret
+}
+
+#[inline(always)]
+pub unsafe fn syscall6(
+    n: usize,
+    a1: usize,
+    a2: usize,
+    a3: usize,
+    a4: usize,
+    a5: usize,
+    a6: usize,
+) -> isize {
+    let ret: isize;
+    asm!("svc 0",
+        in("x8") n,
+        inlateout("x0") a1 as isize => ret,
+        in("x1") a2,
+        in("x2") a3,
+        in("x3") a4,
+        in("x4") a5,
+        in("x5") a6,
+        options(nostack),
+    );
+    ret
+}
+
+pub unsafe fn exit(exit_code: i32) -> ! {
+    asm!("svc 0",
+        in("x8") SYS_EXIT,
+        in("x0") exit_code as isize,
+        options(nostack, nomem, noreturn)
+    )
+}
+
+// Volatile or LLVM spots that these are memcpy and memset, and calls them
+#[no_mangle]
+unsafe extern "C" fn memcpy(dest: *mut u8, src: *const u8, n: usize) -> *mut u8 {
+    let mut i = 0;
+    while i < n {
+        dest.add(i).write_volatile(src.add(i).read_volatile());
+        i += 1;
+    }
+    dest
+}
+
+#[no_mangle]
+unsafe extern "C" fn memset(dest: *mut u8, c: i32, count: usize) -> *mut u8 {
+    let mut i = 0;
+    while i < count {
+        dest.add(i).write_volatile(c as u8);
+        i += 1;
+    }
+    dest
+}
@@ -0,0 +1,42 @@
+//
+// Part of Demeter Deploy.
+//
+// Syscall numbers and struct stat for the architectures that use the kernel's
+// generic ABI, aarch64 and riscv64. It has no open, stat or readlink, only
+// the *at versions, which is why seed uses those everywhere.
+//
+
+// /usr/include/asm-generic/unistd.h
+pub const SYS_GETDENTS64: usize = 61;
+pub const SYS_CHDIR: usize = 49;
+pub const SYS_OPENAT: usize = 56;
+pub const SYS_CLOSE: usize = 57;
+pub const SYS_WRITE: usize = 64;
+pub const SYS_READLINKAT: usize = 78;
+pub const SYS_NEWFSTATAT: usize = 79;
+pub const SYS_FSTAT: usize = 80;
+pub const SYS_EXIT: usize = 93;
+pub const SYS_MUNMAP: usize = 215;
+pub const SYS_MMAP: usize = 222;
+
+// /usr/include/asm-generic/stat.h
+// 128 bytes
+#[repr(C)]
+pub struct Stat {
+    st_dev: u64,       /* Device.  */
+    st_ino: u64,       /* File serial number.  */
+    pub st_mode: u32,  /* File mode.  */
+    st_nlink: u32,     /* Link count.  */
+    st_uid: u32,       /* User ID of the file's owner.  */
+    st_gid: u32,       /* Group ID of the file's group.  */
+    st_rdev: u64,      /* Device number, if device.  */
+    _pad1: u64,        /* __pad1 */
+    pub st_size: u64,  /* Size of file, in bytes.  */
+    st_blksize: u32,   /* Optimal block size for I/O.  */
+    _pad2: u32,        /* __pad2 */
+    st_blocks: u64,    /* Number 512-byte blocks allocated. */
+    _atim: [u8; 16],   /* Time of last access. */
+    pub st_mtime: u64, /* Time of last modification, seconds. */
+    _mtime_nsec: u64,
+    _pad3: [u8; 24], /* st_ctim and __unused4/5 that we don't use */
+}
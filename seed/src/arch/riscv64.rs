@@ -0,0 +1,142 @@
+//
+// Part of Demeter Deploy.
+//
+// riscv64: entry point and syscall calling convention. The syscall numbers
+// and struct stat are the generic ones, see generic.rs.
+//
+
+pub use crate::generic::*;
+
+use core::arch::asm;
+#[cfg(not(test))]
+use core::arch::global_asm;
+use core::ffi::c_char;
+
+// /usr/include/asm-generic/fcntl.h
+pub const O_DIRECTORY: i32 = 0o200000;
+
+// sp is the argc, then the params. gp has to be set before anything the
+// linker relaxed to use it.
+#[cfg(not(test))]
+global_asm!(
+    ".global _start",
+    "_start:",
+    "  .option push",
+    "  .option norelax",
+    "  la gp, __global_pointer$",
+    "  .option pop",
+    "  ld a0, 0(sp)",  // argc
+    "  ld a1, 16(sp)", // addr of param 0, skipping the program name
+    "  ld a2, 24(sp)", // addr of param 1, null if there isn't one
+    "  slli a3, a0, 3",
+    "  add a3, a3, sp",
+    "  addi a3, a3, 16", // environment, after the params and their null
+    "  call enter",
+    "  unimp",
+);
+
+// There's no CRC instruction to look for
+pub unsafe fn init(_envp: *const *const c_char) {}
+
+#[inline(always)]
+pub unsafe fn syscall1(n: usize, a1: usize) -> isize {
+    let ret: isize;
+    asm!("ecall",
+        in("a7") n,
+        inlateout("a0") a1 as isize => ret,
+        options(nostack),
+    );
+    ret
+}
+
+#[inline(always)]
+pub unsafe fn syscall2(n: usize, a1: usize, a2: usize) -> isize {
+    let ret: isize;
+    asm!("ecall",
+        in("a7") n,
+        inlateout("a0") a1 as isize => ret,
+        in("a1") a2,
+        options(nostack),
+    );
+    ret
+}
+
+#[inline(always)]
+pub unsafe fn syscall3(n: usize, a1: usize, a2: usize, a3: usize) -> isize {
+    let ret: isize;
+    asm!("ecall",
+        in("a7") n,
+        inlateout("a0") a1 as isize => ret,
+        in("a1") a2,
+        in("a2") a3,
+        options(nostack),
+    );
+    ret
+}
+
+#[inline(always)]
+pub unsafe fn syscall4(n: usize, a1: usize, a2: usize, a3: usize, a4: usize) -> isize {
+    let ret: isize;
+    asm!("ecall",
+        in("a7") n,
+        inlateout("a0") a1 as isize => ret,
+        in("a1") a2,
+        in("a2") a3,
+        in("a3") a4,
+        options(nostack),
+    );
+    ret
+}
+
+#[inline(always)]
+pub unsafe fn syscall6(
+    n: usize,
+    a1: usize,
+    a2: usize,
+    a3: usize,
+    a4: usize,
+    a5: usize,
+    a6: usize,
+) -> isize {
+    let ret: isize;
+    asm!("ecall",
+        in("a7") n,
+        inlateout("a0") a1 as isize => ret,
+        in("a1") a2,
+        in("a2") a3,
+        in("a3") a4,
+        in("a4") a5,
+        in("a5") a6,
+        options(nostack),
+    );
+    ret
+}
+
+pub unsafe fn exit(exit_code: i32) -> ! {
+    asm!("ecall",
+        in("a7") SYS_EXIT,
+        in("a0") exit_code as isize,
+        options(nostack, nomem, noreturn)
+    )
+}
+
+// Volatile or LLVM spots that these are memcpy and memset, and calls them
+#[no_mangle]
+unsafe extern "C" fn memcpy(dest: *mut u8, src: *const u8, n: usize) -> *mut u8 {
+    let mut i = 0;
+    while i < n {
+        dest.add(i).write_volatile(src.add(i).read_volatile());
+        i += 1;
+    }
+    dest
+}
+
+#[no_mangle]
+unsafe extern "C" fn memset(dest: *mut u8, c: i32, count: usize) -> *mut u8 {
+    let mut i = 0;
+    while i < count {
+        dest.add(i).write_volatile(c as u8);
+        i += 1;
+    }
+    dest
+}
@@ -0,0 +1,203 @@
+//
+// Part of Demeter Deploy.
+//
+// x86-64: entry point, syscall numbers and calling convention, struct stat,
+// and the string functions that have a faster path here.
+//
+
+use core::arch::asm;
+#[cfg(not(test))]
+use core::arch::global_asm;
+use core::arch::x86_64::{
+    _mm_cmpistri, _mm_loadu_si128, _SIDD_CMP_RANGES, _SIDD_NEGATIVE_POLARITY,
+};
+use core::ffi::c_char;
+use core::simd::u64x2;
+
+// /usr/include/asm/unistd_64.h
+pub const SYS_WRITE: usize = 1;
+pub const SYS_CLOSE: usize = 3;
+pub const SYS_FSTAT: usize = 5;
+pub const SYS_MMAP: usize = 9;
+pub const SYS_MUNMAP: usize = 11;
+pub const SYS_EXIT: usize = 60;
+pub const SYS_CHDIR: usize = 80;
+pub const SYS_GETDENTS64: usize = 217;
+pub const SYS_OPENAT: usize = 257;
+pub const SYS_NEWFSTATAT: usize = 262;
+pub const SYS_READLINKAT: usize = 267;
+
+// /usr/include/asm-generic/fcntl.h
+pub const O_DIRECTORY: i32 = 0o200000;
+
+// /usr/include/bits/struct_stat.h
+// 144 bytes
+#[repr(C)]
+pub struct Stat {
+    st_dev: u64,       /* Device.  */
+    st_ino: u64,       /* file serial number.	*/
+    st_nlink: u64,     /* Link count.  */
+    pub st_mode: u32,  /* File mode.  */
+    st_uid: u32,       /* User ID of the file's owner.  */
+    st_gid: u32,       /* Group ID of the file's group.  */
+    _pad0: u32,        /* switch back to u64 padding */
+    st_rdev: u64,      /* Device number, if device.  */
+    pub st_size: u64,  /* Size of file, in bytes.  */
+    st_blksize: u64,   /* Optimal block size for I/O.  */
+    st_blocks: u64,    /* Number 512-byte blocks allocated. */
+    _atim: [u8; 16],   /* Time of last access. */
+    pub st_mtime: u64, /* Time of last modification, seconds. */
+    _mtime_nsec: u64,
+    _pad1: [u8; 40], /* st_ctim and __glibc_reserved that we don't use */
+}
+
+// Rust asm! macro messes with the stack so start here
+#[cfg(not(test))]
+global_asm!(
+    ".global _start",
+    "_start:",
+    "  pop rdi",              // argc
+    "  add rsp, 8",           // skip param 0, program name
+    "  mov rsi, [rsp]",       // addr of param 0
+    "  mov rdx, [rsp+8]",     // addr of param 1, null if there isn't one
+    "  lea rcx, [rsp+rdi*8]", // environment, after the params and their null
+    "  call enter",
+    "  ud2",
+);
+
+// Nothing to ask the kernel, cpuid says what the CPU has
+pub unsafe fn init(_envp: *const *const c_char) {}
+
+#[inline(always)]
+pub unsafe fn syscall1(n: usize, a1: usize) -> isize {
+    let ret: isize;
+    asm!("syscall",
+        inlateout("rax") n as isize => ret,
+        in("rdi") a1,
+        out("rcx") _, // clobbered by syscall
+        out("r11") _,
+        options(nostack),
+    );
+    ret
+}
+
+#[inline(always)]
+pub unsafe fn syscall2(n: usize, a1: usize, a2: usize) -> isize {
+    let ret: isize;
+    asm!("syscall",
+        inlateout("rax") n as isize => ret,
+        in("rdi") a1,
+        in("rsi") a2,
+        out("rcx") _, // clobbered by syscall
+        out("r11") _,
+        options(nostack),
+    );
+    ret
+}
+
+#[inline(always)]
+pub unsafe fn syscall3(n: usize, a1: usize, a2: usize, a3: usize) -> isize {
+    let ret: isize;
+    asm!("syscall",
+        inlateout("rax") n as isize => ret,
+        in("rdi") a1,
+        in("rsi") a2,
+        in("rdx") a3,
+        out("rcx") _, // clobbered by syscall
+        out("r11") _,
+        options(nostack),
+    );
+    ret
+}
+
+#[inline(always)]
+pub unsafe fn syscall4(n: usize, a1: usize, a2: usize, a3: usize, a4: usize) -> isize {
+    let ret: isize;
+    asm!("syscall",
+        inlateout("rax") n as isize => ret,
+        in("rdi") a1,
+        in("rsi") a2,
+        in("rdx") a3,
+        in("r10") a4,
+        out("rcx") _, // clobbered by syscall
+        out("r11") _,
+        options(nostack),
+    );
+    ret
+}
+
+#[inline(always)]
+pub unsafe fn syscall6(
+    n: usize,
+    a1: usize,
+    a2: usize,
+    a3: usize,
+    a4: usize,
+    a5: usize,
+    a6: usize,
+) -> isize {
+    let ret: isize;
+    asm!("syscall",
+        inlateout("rax") n as isize => ret,
+        in("rdi") a1,
+        in("rsi") a2,
+        in("rdx") a3,
+        in("r10") a4,
+        in("r8") a5,
+        in("r9") a6,
+        out("rcx") _, // clobbered by syscall
+        out("r11") _,
+        options(nostack),
+    );
+    ret
+}
+
+pub unsafe fn exit(exit_code: i32) -> ! {
+    asm!("syscall",
+        in("rax") SYS_EXIT,
+        in("edi") exit_code,
+        options(nostack, nomem, noreturn)
+    )
+}
+
+// Reads 16 bytes at a time, so can read up to 15 bytes past the \0
+#[target_feature(enable = "sse4.2")]
+pub unsafe fn strlen_sse42(s: *const c_char) -> usize {
+    let mut haystack = s as u64;
+    let mut idx = 16; // pcmpistri instruction uses 16 for 'not found'
+    let mut offset: i32 = -16;
+    while idx == 16 {
+        offset += 16;
+        idx = _mm_cmpistri(
+            u64x2::from_array([0xFF01, 0]).into(),
+            _mm_loadu_si128(haystack as *const _),
+            _SIDD_CMP_RANGES | _SIDD_NEGATIVE_POLARITY,
+        );
+        haystack += 16;
+    }
+    (offset + idx) as usize
+}
+
+#[no_mangle]
+unsafe extern "C" fn memcpy(dest: *mut u8, src: *const u8, n: usize) -> *mut u8 {
+    asm!(
+        "rep movsb",
+        inout("rcx") n => _,
+        inout("rdi") dest => _,
+        inout("rsi") src => _,
+        options(nostack, preserves_flags)
+    );
+    dest
+}
+
+#[no_mangle]
+unsafe extern "C" fn memset(dest: *mut u8, c: i32, count: usize) -> *mut u8 {
+    asm!(
+        "rep stosb",
+        inout("rcx") count => _,
+        inout("rdi") dest => _,
+        inout("al") c as u8 => _,
+        options(nostack, preserves_flags)
+    );
+    dest
+}
@@ -0,0 +1,730 @@
+//
+// Part of Demeter Deploy.
+//
+// Content hashes. Shared by seed and de (which includes this file with
+// #[path]), so that both sides hash a file exactly the same way.
+// Pointers rather than slices, see the size notes in main.rs.
+//
+// - CRC32C: the Castagnoli CRC, as used by iSCSI and ext4. Reflected, initial
+//   value and final xor 0xFFFFFFFF, so "123456789" is 0xE3069283. 4 bytes.
+//   In hardware if the CPU has SSE4.2 (x86-64) or the CRC32 extension (aarch64),
+//   otherwise from a table.
+// - XXH3: XXH3_64bits, seed 0 and the default secret. 8 bytes.
+// - BLAKE3: the default unkeyed hash. 32 bytes.
+//
+// Digests are the hash's bytes, little endian for CRC32C and XXH3.
+//
+
+#[cfg(target_arch = "aarch64")]
+use core::arch::aarch64::{__crc32cb, __crc32cd};
+#[cfg(target_arch = "x86_64")]
+use core::arch::asm;
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::{_mm_crc32_u64, _mm_crc32_u8};
+#[cfg(target_arch = "aarch64")]
+use core::sync::atomic::AtomicBool;
+#[cfg(target_arch = "x86_64")]
+use core::sync::atomic::AtomicU8;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+use core::sync::atomic::Ordering;
+
+// Which hash, as passed to seed on the command line and sent back in its header
+pub const CRC32C: u8 = b'c';
+pub const XXH3: u8 = b'x';
+pub const BLAKE3: u8 = b'b';
+
+pub const MAX_DIGEST_LEN: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Digest {
+    pub len: u8,
+    pub bytes: [u8; MAX_DIGEST_LEN],
+}
+
+// Hash the len bytes at ptr. Only those bytes are read, ptr can be anything
+// non-null if len is 0. An unknown algo gives an empty digest.
+pub unsafe fn digest(algo: u8, ptr: *const u8, len: usize) -> Digest {
+    Hasher::new(algo).finish(ptr, len)
+}
+
+// Hasher::update takes a whole number of these: a block of XXH3, a chunk of BLAKE3
+pub const PIECE_LEN: usize = 1024;
+const _: () = assert!(PIECE_LEN == BLOCK_LEN && PIECE_LEN == CHUNK_LEN);
+
+// A hash of bytes given a piece at a time, so that de can hash a file as it
+// reads it. digest is the same with all the bytes at once.
+pub struct Hasher {
+    algo: u8,
+    // bytes given to update
+    len: u64,
+    crc: u32,
+    acc: [u64; 8],
+    // the end of the bytes so far, for XXH3's last stripe
+    tail: [u8; STRIPE_LEN],
+    // BLAKE3's completed subtrees
+    cvs: [[u32; 8]; MAX_DEPTH],
+    cvs_len: usize,
+}
+
+impl Hasher {
+    pub fn new(algo: u8) -> Hasher {
+        Hasher {
+            algo,
+            len: 0,
+            crc: 0,
+            acc: [
+                PRIME32_3, PRIME64_1, PRIME64_2, PRIME64_3, PRIME64_4, PRIME32_2, PRIME64_5,
+                PRIME32_1,
+            ],
+            tail: [0; STRIPE_LEN],
+            cvs: [[0; 8]; MAX_DEPTH],
+            cvs_len: 0,
+        }
+    }
+
+    // len is a multiple of PIECE_LEN, and more bytes follow, at least one
+    pub unsafe fn update(&mut self, p: *const u8, len: usize) {
+        match self.algo {
+            CRC32C => self.crc = crc32c(self.crc, p, len),
+            XXH3 => {
+                let mut pos = 0;
+                while pos < len {
+                    xxh3_stripes(&mut self.acc, p.add(pos), STRIPES_PER_BLOCK);
+                    scramble(&mut self.acc, SECRET.as_ptr().add(SECRET_LEN - STRIPE_LEN));
+                    pos += BLOCK_LEN;
+                }
+                if len > 0 {
+                    core::ptr::copy_nonoverlapping(
+                        p.add(len - STRIPE_LEN),
+                        self.tail.as_mut_ptr(),
+                        STRIPE_LEN,
+                    );
+                }
+            }
+            BLAKE3 => {
+                let mut pos = 0;
+                while pos < len {
+                    let chunk = (self.len + pos as u64) / CHUNK_LEN as u64;
+                    let cv = chunk_output(p.add(pos), CHUNK_LEN, chunk).chaining_value();
+                    self.push_cv(cv, chunk + 1);
+                    pos += CHUNK_LEN;
+                }
+            }
+            _ => {}
+        }
+        self.len += len as u64;
+    }
+
+    // The last bytes, any number, and the digest of everything
+    pub unsafe fn finish(mut self, p: *const u8, len: usize) -> Digest {
+        // all but the last piece, which XXH3 and BLAKE3 treat differently
+        let whole = if len == 0 {
+            0
+        } else {
+            (len - 1) / PIECE_LEN * PIECE_LEN
+        };
+        self.update(p, whole);
+        let (p, len) = (p.add(whole), len - whole);
+        let total = self.len + len as u64;
+
+        let mut d = Digest {
+            len: 0,
+            bytes: [0; MAX_DIGEST_LEN],
+        };
+        let out = d.bytes.as_mut_ptr();
+        match self.algo {
+            CRC32C => {
+                (out as *mut u32).write_unaligned(crc32c(self.crc, p, len).to_le());
+                d.len = 4;
+            }
+            XXH3 => {
+                let h = if total <= 240 {
+                    xxh3_short(p, len)
+                } else {
+                    self.xxh3_long_end(p, len, total)
+                };
+                (out as *mut u64).write_unaligned(h.to_le());
+                d.len = 8;
+            }
+            BLAKE3 => {
+                let chunk = self.len / CHUNK_LEN as u64;
+                let mut output = chunk_output(p, len, chunk);
+                // merge everything on the stack into the root
+                let stack = self.cvs.as_ptr();
+                while self.cvs_len > 0 {
+                    self.cvs_len -= 1;
+                    output = parent_output(&*stack.add(self.cvs_len), &output.chaining_value());
+                }
+                let words = compress(
+                    &output.cv,
+                    &output.block,
+                    0,
+                    output.block_len,
+                    output.flags | ROOT,
+                );
+                let mut i = 0;
+                while i < 8 {
+                    (out.add(4 * i) as *mut u32).write_unaligned(words[i].to_le());
+                    i += 1;
+                }
+                d.len = 32;
+            }
+            _ => {}
+        }
+        d
+    }
+
+    // The last, partial, block of more than 240 bytes. len is 1 to BLOCK_LEN.
+    unsafe fn xxh3_long_end(&mut self, p: *const u8, len: usize, total: u64) -> u64 {
+        let s = SECRET.as_ptr();
+        xxh3_stripes(&mut self.acc, p, (len - 1) / STRIPE_LEN);
+        // the last stripe, which can overlap the one before, even the block before
+        let mut last = [0u8; STRIPE_LEN];
+        if len >= STRIPE_LEN {
+            core::ptr::copy_nonoverlapping(p.add(len - STRIPE_LEN), last.as_mut_ptr(), STRIPE_LEN);
+        } else {
+            let before = STRIPE_LEN - len;
+            core::ptr::copy_nonoverlapping(self.tail.as_ptr().add(len), last.as_mut_ptr(), before);
+            core::ptr::copy_nonoverlapping(p, last.as_mut_ptr().add(before), len);
+        }
+        accumulate(
+            &mut self.acc,
+            last.as_ptr(),
+            s.add(SECRET_LEN - STRIPE_LEN - 7),
+        );
+
+        let mut result = total.wrapping_mul(PRIME64_1);
+        let mut i = 0;
+        while i < 4 {
+            let s = s.add(11 + 16 * i);
+            result = result.wrapping_add(mul128_fold64(
+                self.acc[2 * i] ^ read64(s),
+                self.acc[2 * i + 1] ^ read64(s.add(8)),
+            ));
+            i += 1;
+        }
+        xxh3_avalanche(result)
+    }
+
+    // Merge the completed subtrees, one per trailing zero bit of the number
+    // of chunks so far, and push what's left
+    unsafe fn push_cv(&mut self, mut cv: [u32; 8], mut chunks: u64) {
+        let stack = self.cvs.as_mut_ptr();
+        while chunks & 1 == 0 {
+            self.cvs_len -= 1;
+            cv = parent_output(&*stack.add(self.cvs_len), &cv).chaining_value();
+            chunks >>= 1;
+        }
+        *stack.add(self.cvs_len) = cv;
+        self.cvs_len += 1;
+    }
+}
+
+// 0 not checked yet, 1 no, 2 yes
+#[cfg(target_arch = "x86_64")]
+static SSE42: AtomicU8 = AtomicU8::new(0);
+
+// Does the CPU have SSE4.2, for crc32 and pcmpistri. Asks cpuid once.
+#[cfg(target_arch = "x86_64")]
+pub fn has_sse42() -> bool {
+    let mut state = SSE42.load(Ordering::Relaxed);
+    if state == 0 {
+        let ecx: u32;
+        unsafe {
+            // cpuid writes rbx, which LLVM keeps for itself, so put it back
+            asm!(
+                "mov {saved:r}, rbx",
+                "cpuid",
+                "mov rbx, {saved:r}",
+                saved = out(reg) _,
+                inout("eax") 1 => _,
+                out("ecx") ecx,
+                out("edx") _,
+                options(nostack, nomem, preserves_flags)
+            );
+        }
+        state = if ecx & (1 << 20) != 0 { 2 } else { 1 };
+        SSE42.store(state, Ordering::Relaxed);
+    }
+    state == 2
+}
+
+// There's no cpuid on aarch64, the kernel tells the helper in its auxiliary
+// vector (AT_HWCAP) and it passes that on. Until then, and in de, it's no.
+#[cfg(target_arch = "aarch64")]
+static CRC32: AtomicBool = AtomicBool::new(false);
+
+#[cfg(target_arch = "aarch64")]
+pub fn set_has_crc32(is_crc32: bool) {
+    CRC32.store(is_crc32, Ordering::Relaxed);
+}
+
+#[cfg(target_arch = "aarch64")]
+pub fn has_crc32() -> bool {
+    CRC32.load(Ordering::Relaxed)
+}
+
+#[inline(always)]
+unsafe fn read32(p: *const u8) -> u32 {
+    u32::from_le((p as *const u32).read_unaligned())
+}
+
+#[inline(always)]
+unsafe fn read64(p: *const u8) -> u64 {
+    u64::from_le((p as *const u64).read_unaligned())
+}
+
+//
+// CRC32C
+//
+
+// crc is the CRC of the bytes before these, 0 to start
+
+#[cfg(target_arch = "x86_64")]
+unsafe fn crc32c(crc: u32, ptr: *const u8, len: usize) -> u32 {
+    if has_sse42() {
+        crc32c_hw(crc, ptr, len)
+    } else {
+        crc32c_sw(crc, ptr, len)
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+unsafe fn crc32c(crc: u32, ptr: *const u8, len: usize) -> u32 {
+    if has_crc32() {
+        crc32c_hw(crc, ptr, len)
+    } else {
+        crc32c_sw(crc, ptr, len)
+    }
+}
+
+// riscv64 has no CRC instruction
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+unsafe fn crc32c(crc: u32, ptr: *const u8, len: usize) -> u32 {
+    crc32c_sw(crc, ptr, len)
+}
+
+// Only call this if has_sse42()
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse4.2")]
+pub unsafe fn crc32c_hw(crc: u32, ptr: *const u8, len: usize) -> u32 {
+    let mut crc64 = !crc as u64;
+    let mut pos = 0;
+    while pos + 8 <= len {
+        crc64 = _mm_crc32_u64(crc64, read64(ptr.add(pos)));
+        pos += 8;
+    }
+    let mut crc = crc64 as u32;
+    while pos < len {
+        crc = _mm_crc32_u8(crc, *ptr.add(pos));
+        pos += 1;
+    }
+    !crc
+}
+
+// Only call this if has_crc32()
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "crc")]
+pub unsafe fn crc32c_hw(crc: u32, ptr: *const u8, len: usize) -> u32 {
+    let mut crc = !crc;
+    let mut pos = 0;
+    while pos + 8 <= len {
+        crc = __crc32cd(crc, read64(ptr.add(pos)));
+        pos += 8;
+    }
+    while pos < len {
+        crc = __crc32cb(crc, *ptr.add(pos));
+        pos += 1;
+    }
+    !crc
+}
+
+// Half a byte at a time. A table for whole bytes is faster, but at 1 KiB it
+// would double the size of the helper.
+pub unsafe fn crc32c_sw(crc: u32, ptr: *const u8, len: usize) -> u32 {
+    let mut crc = !crc;
+    let mut pos = 0;
+    while pos < len {
+        crc ^= *ptr.add(pos) as u32;
+        crc = (crc >> 4) ^ CRC32C_TABLE[(crc & 0xF) as usize];
+        crc = (crc >> 4) ^ CRC32C_TABLE[(crc & 0xF) as usize];
+        pos += 1;
+    }
+    !crc
+}
+
+// The CRC of each 4 bit value, reflected polynomial 0x82F63B78
+const CRC32C_TABLE: [u32; 16] = crc32c_table();
+
+const fn crc32c_table() -> [u32; 16] {
+    let mut table = [0u32; 16];
+    let mut i = 0;
+    while i < 16 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 4 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82F63B78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+//
+// XXH3, https://github.com/Cyan4973/xxHash/blob/dev/doc/xxhash_spec.md
+//
+
+const PRIME32_1: u64 = 0x9E3779B1;
+const PRIME32_2: u64 = 0x85EBCA77;
+const PRIME32_3: u64 = 0xC2B2AE3D;
+const PRIME64_1: u64 = 0x9E3779B185EBCA87;
+const PRIME64_2: u64 = 0xC2B2AE3D27D4EB4F;
+const PRIME64_3: u64 = 0x165667B19E3779F9;
+const PRIME64_4: u64 = 0x85EBCA77C2B2AE63;
+const PRIME64_5: u64 = 0x27D4EB2F165667C5;
+
+const SECRET_LEN: usize = 192;
+const STRIPE_LEN: usize = 64;
+const STRIPES_PER_BLOCK: usize = (SECRET_LEN - STRIPE_LEN) / 8;
+const BLOCK_LEN: usize = STRIPE_LEN * STRIPES_PER_BLOCK;
+
+static SECRET: [u8; SECRET_LEN] = [
+    0xb8, 0xfe, 0x6c, 0x39, 0x23, 0xa4, 0x4b, 0xbe, 0x7c, 0x01, 0x81, 0x2c, 0xf7, 0x21, 0xad, 0x1c,
+    0xde, 0xd4, 0x6d, 0xe9, 0x83, 0x90, 0x97, 0xdb, 0x72, 0x40, 0xa4, 0xa4, 0xb7, 0xb3, 0x67, 0x1f,
+    0xcb, 0x79, 0xe6, 0x4e, 0xcc, 0xc0, 0xe5, 0x78, 0x82, 0x5a, 0xd0, 0x7d, 0xcc, 0xff, 0x72, 0x21,
+    0xb8, 0x08, 0x46, 0x74, 0xf7, 0x43, 0x24, 0x8e, 0xe0, 0x35, 0x90, 0xe6, 0x81, 0x3a, 0x26, 0x4c,
+    0x3c, 0x28, 0x52, 0xbb, 0x91, 0xc3, 0x00, 0xcb, 0x88, 0xd0, 0x65, 0x8b, 0x1b, 0x53, 0x2e, 0xa3,
+    0x71, 0x64, 0x48, 0x97, 0xa2, 0x0d, 0xf9, 0x4e, 0x38, 0x19, 0xef, 0x46, 0xa9, 0xde, 0xac, 0xd8,
+    0xa8, 0xfa, 0x76, 0x3f, 0xe3, 0x9c, 0x34, 0x3f, 0xf9, 0xdc, 0xbb, 0xc7, 0xc7, 0x0b, 0x4f, 0x1d,
+    0x8a, 0x51, 0xe0, 0x4b, 0xcd, 0xb4, 0x59, 0x31, 0xc8, 0x9f, 0x7e, 0xc9, 0xd9, 0x78, 0x73, 0x64,
+    0xea, 0xc5, 0xac, 0x83, 0x34, 0xd3, 0xeb, 0xc3, 0xc5, 0x81, 0xa0, 0xff, 0xfa, 0x13, 0x63, 0xeb,
+    0x17, 0x0d, 0xdd, 0x51, 0xb7, 0xf0, 0xda, 0x49, 0xd3, 0x16, 0x55, 0x26, 0x29, 0xd4, 0x68, 0x9e,
+    0x2b, 0x16, 0xbe, 0x58, 0x7d, 0x47, 0xa1, 0xfc, 0x8f, 0xf8, 0xb8, 0xd1, 0x7a, 0xd0, 0x31, 0xce,
+    0x45, 0xcb, 0x3a, 0x8f, 0x95, 0x16, 0x04, 0x28, 0xaf, 0xd7, 0xfb, 0xca, 0xbb, 0x4b, 0x40, 0x7e,
+];
+
+// Up to 240 bytes, longer input goes through Hasher
+unsafe fn xxh3_short(p: *const u8, len: usize) -> u64 {
+    let s = SECRET.as_ptr();
+    if len <= 16 {
+        xxh3_0to16(p, len, s)
+    } else if len <= 128 {
+        xxh3_17to128(p, len, s)
+    } else {
+        xxh3_129to240(p, len, s)
+    }
+}
+
+unsafe fn xxh3_0to16(p: *const u8, len: usize, s: *const u8) -> u64 {
+    if len > 8 {
+        let lo = read64(p) ^ read64(s.add(24)) ^ read64(s.add(32));
+        let hi = read64(p.add(len - 8)) ^ read64(s.add(40)) ^ read64(s.add(48));
+        let acc = (len as u64)
+            .wrapping_add(lo.swap_bytes())
+            .wrapping_add(hi)
+            .wrapping_add(mul128_fold64(lo, hi));
+        xxh3_avalanche(acc)
+    } else if len >= 4 {
+        let input = (read32(p.add(len - 4)) as u64).wrapping_add((read32(p) as u64) << 32);
+        let flip = read64(s.add(8)) ^ read64(s.add(16));
+        rrmxmx(input ^ flip, len as u64)
+    } else if len > 0 {
+        let combo = (*p as u32) << 16
+            | (*p.add(len >> 1) as u32) << 24
+            | *p.add(len - 1) as u32
+            | (len as u32) << 8;
+        let flip = (read32(s) ^ read32(s.add(4))) as u64;
+        xxh64_avalanche(combo as u64 ^ flip)
+    } else {
+        xxh64_avalanche(read64(s.add(56)) ^ read64(s.add(64)))
+    }
+}
+
+unsafe fn xxh3_17to128(p: *const u8, len: usize, s: *const u8) -> u64 {
+    let mut acc = (len as u64).wrapping_mul(PRIME64_1);
+    if len > 32 {
+        if len > 64 {
+            if len > 96 {
+                acc = acc.wrapping_add(mix16(p.add(48), s.add(96)));
+                acc = acc.wrapping_add(mix16(p.add(len - 64), s.add(112)));
+            }
+            acc = acc.wrapping_add(mix16(p.add(32), s.add(64)));
+            acc = acc.wrapping_add(mix16(p.add(len - 48), s.add(80)));
+        }
+        acc = acc.wrapping_add(mix16(p.add(16), s.add(32)));
+        acc = acc.wrapping_add(mix16(p.add(len - 32), s.add(48)));
+    }
+    acc = acc.wrapping_add(mix16(p, s));
+    acc = acc.wrapping_add(mix16(p.add(len - 16), s.add(16)));
+    xxh3_avalanche(acc)
+}
+
+unsafe fn xxh3_129to240(p: *const u8, len: usize, s: *const u8) -> u64 {
+    let mut acc = (len as u64).wrapping_mul(PRIME64_1);
+    let num_rounds = len / 16;
+    let mut i = 0;
+    while i < 8 {
+        acc = acc.wrapping_add(mix16(p.add(16 * i), s.add(16 * i)));
+        i += 1;
+    }
+    acc = xxh3_avalanche(acc);
+    while i < num_rounds {
+        acc = acc.wrapping_add(mix16(p.add(16 * i), s.add(16 * (i - 8) + 3)));
+        i += 1;
+    }
+    acc = acc.wrapping_add(mix16(p.add(len - 16), s.add(136 - 17)));
+    xxh3_avalanche(acc)
+}
+
+// num stripes from p, with the secret for each stripe of a block
+unsafe fn xxh3_stripes(acc: &mut [u64; 8], p: *const u8, num: usize) {
+    let mut stripe = 0;
+    while stripe < num {
+        accumulate(
+            acc,
+            p.add(stripe * STRIPE_LEN),
+            SECRET.as_ptr().add(stripe * 8),
+        );
+        stripe += 1;
+    }
+}
+
+#[inline(always)]
+unsafe fn accumulate(acc: &mut [u64; 8], p: *const u8, s: *const u8) {
+    let acc = acc.as_mut_ptr();
+    let mut i = 0;
+    while i < 8 {
+        let value = read64(p.add(8 * i));
+        let key = value ^ read64(s.add(8 * i));
+        *acc.add(i ^ 1) = (*acc.add(i ^ 1)).wrapping_add(value);
+        *acc.add(i) = (*acc.add(i)).wrapping_add((key & 0xFFFF_FFFF).wrapping_mul(key >> 32));
+        i += 1;
+    }
+}
+
+unsafe fn scramble(acc: &mut [u64; 8], s: *const u8) {
+    let mut i = 0;
+    while i < 8 {
+        let a = acc[i] ^ (acc[i] >> 47) ^ read64(s.add(8 * i));
+        acc[i] = a.wrapping_mul(PRIME32_1);
+        i += 1;
+    }
+}
+
+#[inline(always)]
+unsafe fn mix16(p: *const u8, s: *const u8) -> u64 {
+    mul128_fold64(read64(p) ^ read64(s), read64(p.add(8)) ^ read64(s.add(8)))
+}
+
+#[inline(always)]
+fn mul128_fold64(a: u64, b: u64) -> u64 {
+    let product = a as u128 * b as u128;
+    product as u64 ^ (product >> 64) as u64
+}
+
+fn xxh64_avalanche(mut h: u64) -> u64 {
+    h ^= h >> 33;
+    h = h.wrapping_mul(PRIME64_2);
+    h ^= h >> 29;
+    h = h.wrapping_mul(PRIME64_3);
+    h ^ (h >> 32)
+}
+
+fn xxh3_avalanche(mut h: u64) -> u64 {
+    h ^= h >> 37;
+    h = h.wrapping_mul(0x165667919E3779F9);
+    h ^ (h >> 32)
+}
+
+fn rrmxmx(mut h: u64, len: u64) -> u64 {
+    h ^= h.rotate_left(49) ^ h.rotate_left(24);
+    h = h.wrapping_mul(0x9FB21C651E98DF25);
+    h ^= (h >> 35).wrapping_add(len);
+    h = h.wrapping_mul(0x9FB21C651E98DF25);
+    h ^ (h >> 28)
+}
+
+//
+// BLAKE3, https://github.com/BLAKE3-team/BLAKE3-specs
+// Follows the reference implementation, one chunk at a time.
+//
+
+const IV: [u32; 8] = [
+    0x6A09E667, 0xBB67AE85, 0x3C6EF372, 0xA54FF53A, 0x510E527F, 0x9B05688C, 0x1F83D9AB, 0x5BE0CD19,
+];
+const CHUNK_LEN: usize = 1024;
+const BLAKE3_BLOCK_LEN: usize = 64;
+const CHUNK_START: u32 = 1;
+const CHUNK_END: u32 = 2;
+const PARENT: u32 = 4;
+const ROOT: u32 = 8;
+// enough for 2^54 chunks, 2^64 bytes
+const MAX_DEPTH: usize = 54;
+
+// A compression that hasn't been done yet, because it might be the root
+struct Output {
+    cv: [u32; 8],
+    block: [u32; 16],
+    counter: u64,
+    block_len: u32,
+    flags: u32,
+}
+
+impl Output {
+    fn chaining_value(&self) -> [u32; 8] {
+        first_8(&compress(
+            &self.cv,
+            &self.block,
+            self.counter,
+            self.block_len,
+            self.flags,
+        ))
+    }
+}
+
+// Compress all but the last block of a chunk. len can be 0 for empty input.
+unsafe fn chunk_output(p: *const u8, len: usize, counter: u64) -> Output {
+    let mut cv = IV;
+    let mut flags = CHUNK_START;
+    let mut pos = 0;
+    while len - pos > BLAKE3_BLOCK_LEN {
+        let block = block_words(p.add(pos), BLAKE3_BLOCK_LEN);
+        cv = first_8(&compress(
+            &cv,
+            &block,
+            counter,
+            BLAKE3_BLOCK_LEN as u32,
+            flags,
+        ));
+        flags = 0;
+        pos += BLAKE3_BLOCK_LEN;
+    }
+    Output {
+        cv,
+        block: block_words(p.add(pos), len - pos),
+        counter,
+        block_len: (len - pos) as u32,
+        flags: flags | CHUNK_END,
+    }
+}
+
+fn parent_output(left: &[u32; 8], right: &[u32; 8]) -> Output {
+    let mut block = [0u32; 16];
+    let mut i = 0;
+    while i < 8 {
+        block[i] = left[i];
+        block[i + 8] = right[i];
+        i += 1;
+    }
+    Output {
+        cv: IV,
+        block,
+        counter: 0,
+        block_len: BLAKE3_BLOCK_LEN as u32,
+        flags: PARENT,
+    }
+}
+
+// Up to 64 bytes as 16 little endian words, zero padded
+unsafe fn block_words(p: *const u8, len: usize) -> [u32; 16] {
+    let mut bytes = [0u8; BLAKE3_BLOCK_LEN];
+    core::ptr::copy_nonoverlapping(p, bytes.as_mut_ptr(), len);
+    let mut words = [0u32; 16];
+    let mut i = 0;
+    while i < 16 {
+        words[i] = read32(bytes.as_ptr().add(4 * i));
+        i += 1;
+    }
+    words
+}
+
+fn first_8(words: &[u32; 16]) -> [u32; 8] {
+    let mut out = [0u32; 8];
+    let mut i = 0;
+    while i < 8 {
+        out[i] = words[i];
+        i += 1;
+    }
+    out
+}
+
+fn compress(
+    cv: &[u32; 8],
+    block: &[u32; 16],
+    counter: u64,
+    block_len: u32,
+    flags: u32,
+) -> [u32; 16] {
+    let mut state = [
+        cv[0],
+        cv[1],
+        cv[2],
+        cv[3],
+        cv[4],
+        cv[5],
+        cv[6],
+        cv[7],
+        IV[0],
+        IV[1],
+        IV[2],
+        IV[3],
+        counter as u32,
+        (counter >> 32) as u32,
+        block_len,
+        flags,
+    ];
+    let mut m = *block;
+    let mut r = 0;
+    while r < 7 {
+        round(&mut state, &m);
+        if r < 6 {
+            m = permute(&m);
+        }
+        r += 1;
+    }
+    let mut i = 0;
+    while i < 8 {
+        state[i] ^= state[i + 8];
+        state[i + 8] ^= cv[i];
+        i += 1;
+    }
+    state
+}
+
+fn round(s: &mut [u32; 16], m: &[u32; 16]) {
+    // columns
+    g(s, 0, 4, 8, 12, m[0], m[1]);
+    g(s, 1, 5, 9, 13, m[2], m[3]);
+    g(s, 2, 6, 10, 14, m[4], m[5]);
+    g(s, 3, 7, 11, 15, m[6], m[7]);
+    // diagonals
+    g(s, 0, 5, 10, 15, m[8], m[9]);
+    g(s, 1, 6, 11, 12, m[10], m[11]);
+    g(s, 2, 7, 8, 13, m[12], m[13]);
+    g(s, 3, 4, 9, 14, m[14], m[15]);
+}
+
+#[inline(always)]
+fn g(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize, mx: u32, my: u32) {
+    s[a] = s[a].wrapping_add(s[b]).wrapping_add(mx);
+    s[d] = (s[d] ^ s[a]).rotate_right(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_right(12);
+    s[a] = s[a].wrapping_add(s[b]).wrapping_add(my);
+    s[d] = (s[d] ^ s[a]).rotate_right(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_right(7);
+}
+
+fn permute(m: &[u32; 16]) -> [u32; 16] {
+    [
+        m[2], m[6], m[3], m[10], m[7], m[0], m[4], m[13], m[1], m[11], m[12], m[5], m[9], m[14],
+        m[15], m[8],
+    ]
+}
@@ -1,8 +1,9 @@
 //
 // Part of Demeter Deploy.
 //
-// Remote helper. This gets uploaded to remote server to calc CRC32 values of remote
-// files. We want it as small as possible, and also fast.
+// Remote helper. This gets uploaded to remote server to hash the remote
+// files, see hash.rs. We want it as small as possible, and also fast.
+// It writes a binary listing to stdout, see "Output protocol" below and README.md.
 // It's no_std so we do all our own syscalls
 // Source build_env.sh to be able to build it. Do not source that for testing.
 //
@@ -16,58 +17,79 @@
 //
 // BUILD
 //
-// export RUSTFLAGS="-Ctarget-cpu=core-avx2 -Clink-args=-nostartfiles -Crelocation-model=static -Clink-args=-Wl,-n,-N,--no-dynamic-linker,--no-pie,--build-id=none,--no-eh-frame-hdr"
-// cargo build --release
-// objcopy -R .eh_frame -R .got.plt target/release/seed target/release/seed-final
+// source env_build.sh x86_64-unknown-linux-gnu
+// cargo +nightly build --release -Zbuild-std=core --target x86_64-unknown-linux-gnu
+// llvm-objcopy -R .eh_frame -R .got.plt target/x86_64-unknown-linux-gnu/release/seed target/x86_64-unknown-linux-gnu/release/seed-final
 //
-// Adjust target-cpu to match you server. Find it like this:
-// `gcc -march=native -Q --help=target | grep march`
+// Or ../build.sh for every architecture. Anything that differs between them,
+// the entry point, syscalls and struct stat, is in arch/.
+//
+// target-cpu is the baseline so that it runs anywhere. The crc32 and pcmpistri
+// instructions are only used if the CPU has them, see hash.rs.
 //
 
 #![cfg_attr(not(test), no_std)]
 #![cfg_attr(not(test), no_main)]
-#![feature(portable_simd)]
+#![cfg_attr(target_arch = "x86_64", feature(portable_simd))]
 #![feature(maybe_uninit_slice)]
 #![feature(maybe_uninit_uninit_array)]
 #![feature(maybe_uninit_array_assume_init)]
 
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+)))]
+compile_error!("seed only builds for x86_64, aarch64 and riscv64");
+
+#[cfg_attr(target_arch = "x86_64", path = "arch/x86_64.rs")]
+#[cfg_attr(target_arch = "aarch64", path = "arch/aarch64.rs")]
+#[cfg_attr(target_arch = "riscv64", path = "arch/riscv64.rs")]
+mod arch;
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+#[path = "arch/generic.rs"]
+mod generic;
+mod hash;
 #[cfg(test)]
 mod test;
 
-use core::arch::asm;
-#[cfg(not(test))]
-use core::arch::global_asm;
-use core::arch::x86_64::{
-    _mm_cmpistri, _mm_crc32_u64, _mm_loadu_si128, _SIDD_CMP_RANGES, _SIDD_NEGATIVE_POLARITY,
-};
+use arch::{syscall1, syscall2, syscall3, syscall4, syscall6, Stat};
 use core::ffi::c_char;
 use core::mem::{transmute, zeroed, MaybeUninit};
 use core::ptr::copy_nonoverlapping;
-use core::simd::u64x2;
 
-const USAGE: *const c_char = "Usage: seed <dir>\n\0".as_ptr() as *const c_char;
+const USAGE: *const c_char = "Usage: seed <dir> <c|x|b>[m]\n\0".as_ptr() as *const c_char;
 const CR: *const c_char = "\n\0".as_ptr() as *const c_char;
-const COLON: *const c_char = ":\0".as_ptr() as *const c_char;
 const BUF_SIZE: u32 = 32768; // read 32k of directory entries at a time
+const DT_UNKNOWN: u8 = 0; // filesystem doesn't say, have to stat it
 const DT_DIR: u8 = 4; // directory
 const DT_REG: u8 = 8; // regular file
+const DT_LNK: u8 = 10; // symbolic link
+
+// st_mode file types
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+const MAX_LINK_LEN: usize = 4096;
 
 const O_RDONLY: i32 = 0;
 const O_CLOEXEC: i32 = 0o2000000;
-const O_DIRECTORY: i32 = 0o200000;
+const AT_FDCWD: i64 = -100; // path is relative to the current directory
+const AT_SYMLINK_NOFOLLOW: usize = 0x100;
 const PROT_READ: i32 = 1; // mmap a file as read only
 const MAP_SHARED: i32 = 1; // for mmap
 
 const EACCES: i32 = -13; // Permission denied
 
-const CRC32: u64 = 0xFFFFFFFF;
-
 // error messages
-const EM_AVX2: *const c_char = "Need AVX2\n\0".as_ptr() as *const c_char;
 const EM_MISSING_SLASH: *const c_char = "Path must end in a single /\n\0".as_ptr() as *const c_char;
-const EM_OPEN_FILE: *const c_char = "file open err for CRCing: \0".as_ptr() as *const c_char;
+const EM_OPEN_FILE: *const c_char = "file open err for hashing: \0".as_ptr() as *const c_char;
 const EM_OPEN_DIR: *const c_char = "dir open err for listing: \0".as_ptr() as *const c_char;
 const EM_FSTAT: *const c_char = "fstat err: \0".as_ptr() as *const c_char;
+const EM_STAT: *const c_char = "stat err: \0".as_ptr() as *const c_char;
+const EM_READLINK: *const c_char = "readlink err: \0".as_ptr() as *const c_char;
 const EM_GETDENTS64: *const c_char = "getdents64 err: \0".as_ptr() as *const c_char;
 const EM_MMAP: *const c_char = "mmap err: \0".as_ptr() as *const c_char;
 const EM_MUNMAP: *const c_char = "munmap err: \0".as_ptr() as *const c_char;
@@ -78,17 +100,6 @@ const EM_CLOSE: *const c_char = "close err: \0".as_ptr() as *const c_char;
 const STDOUT: u32 = 1;
 const STDERR: u32 = 2;
 
-// syscalls
-const SYS_WRITE: u32 = 1;
-const SYS_OPEN: u32 = 2;
-const SYS_CLOSE: u32 = 3;
-const SYS_FSTAT: u32 = 5;
-const SYS_MMAP: u64 = 9;
-const SYS_MUNMAP: u32 = 11;
-const SYS_EXIT: i32 = 60;
-const SYS_CHDIR: u32 = 80;
-const SYS_GETDENTS64: u32 = 217;
-
 // err codes
 // Padding them and using array instead of slice saves about 200 bytes.
 const ERRS: [&[u8; 8]; 36] = [
@@ -137,56 +148,68 @@ struct Dirent64 {
     d_off: u64,
     d_reclen: u16,
     d_type: u8,
-    d_name: [i8; 256],
+    d_name: [c_char; 256],
 }
 
-// /usr/include/bits/struct_stat.h
-// 144 bytes
-#[repr(C)]
-struct Stat {
-    st_dev: u64,     /* Device.  */
-    st_ino: u64,     /* file serial number.	*/
-    st_nlink: u64,   /* Link count.  */
-    st_mode: u32,    /* File mode.  */
-    st_uid: u32,     /* User ID of the file's owner.  */
-    st_gid: u32,     /* Group ID of the file's group.  */
-    _pad0: u32,      /* switch back to u64 padding */
-    st_rdev: u64,    /* Device number, if device.  */
-    st_size: u64,    /* Size of file, in bytes.  */
-    st_blksize: u64, /* Optimal block size for I/O.  */
-    st_blocks: u64,  /* Number 512-byte blocks allocated. */
-    _pad1: [u8; 72], /* struct st_atim and __glibc_reserved that we don't use */
-}
+// Output protocol. Must match de/src/protocol.rs. All numbers are little endian.
+//
+// Header: magic "DMTR", u16 version, u8 flags, u8 hash
+// Record: u8 kind, u8 digest length, u16 name length, u32 mode, u64 size,
+//         u64 mtime, digest, name
+//  - 'F' file: mode is the permission bits. No digest in mtime mode.
+//  - 'L' symlink: mode and mtime 0, size and digest of where it points
+//  - 'E' end: the last record, size is the number of records before it
+//
+// hash is one of the hash::* algorithms, the one we were asked for.
+// Names are relative to the directory and not \0 terminated.
+const MAGIC: u32 = 0x52544D44; // "DMTR" read as a little endian u32
+pub(crate) const PROTOCOL_VERSION: u16 = 2;
+const FLAG_MTIME: u8 = 1;
+pub(crate) const HEADER_LEN: usize = 8;
+pub(crate) const RECORD_LEN: usize = 24; // without the digest and name
+pub(crate) const KIND_FILE: u8 = b'F';
+const KIND_LINK: u8 = b'L';
+const KIND_END: u8 = b'E';
+
+// records written so far, for the end record
+static mut NUM_RECORDS: u64 = 0;
+
+// which hash to use, from the command line
+static mut HASH: u8 = hash::CRC32C;
+
+// for records that don't carry one
+const NO_DIGEST: hash::Digest = hash::Digest {
+    len: 0,
+    bytes: [0; hash::MAX_DIGEST_LEN],
+};
 
-const MAX_PATH_LEN: usize = 256;
+// max u64 is 20 digits, plus the \0
+const NUM_STR_LEN: usize = 24;
 
-// Rust asm! macro messes with the stack so start here
-#[cfg(not(test))]
-global_asm!(
-    ".global _start",
-    "_start:",
-    "  pop rdi",        // argc
-    "  add rsp, 8",     // skip param 0, program name
-    "  mov rsi, [rsp]", // addr of param 0
-    "  call enter",
-    "  ud2",
-);
+const MAX_PATH_LEN: usize = 256;
 
+// Second param is the hash, 'c' CRC32C, 'x' XXH3 or 'b' BLAKE3. Followed by
+// "m" to not hash files and send only their mtime, for --compare=mtime-size
+// envp is the environment, which arch::init looks past for what the CPU has.
 #[no_mangle]
-unsafe fn enter(argc: u32, dir_name: *const c_char) -> ! {
-    if !has_avx2() {
-        print_avx2_missing();
-        exit(2);
-    }
-    if argc != 2 {
+unsafe extern "C" fn enter(
+    argc: u32,
+    dir_name: *const c_char,
+    mode: *const c_char,
+    envp: *const *const c_char,
+) -> ! {
+    arch::init(envp);
+    if argc != 3 || !is_hash(*mode as u8) {
         print_err(USAGE);
-        exit(0);
+        exit(1);
     }
+    HASH = *mode as u8;
+    let is_mtime = *mode.add(1) == b'm' as c_char;
 
     // check we have a slash at end of dir
     let dir_name_len = strlen_local(dir_name);
     let last_char = dir_name.add(dir_name_len - 1);
-    if *last_char != b'/' as i8 {
+    if *last_char != b'/' as c_char {
         print_err(EM_MISSING_SLASH);
         exit(1);
     }
@@ -194,17 +217,37 @@ unsafe fn enter(argc: u32, dir_name: *const c_char) -> ! {
     // chdir so that our paths can be relative, hence shorter
     chdir(dir_name);
 
+    let mut header = [0u8; HEADER_LEN];
+    write_out(
+        header.as_ptr(),
+        encode_header(header.as_mut_ptr(), is_mtime),
+    );
+
     // start in current directory
-    handle_dir(b".\0".as_ptr() as *const c_char);
+    handle_dir(b".\0".as_ptr() as *const c_char, is_mtime);
+
+    // so that de can tell the listing wasn't cut short
+    let mut end = [0u8; RECORD_LEN];
+    let len = encode_record(
+        end.as_mut_ptr(),
+        KIND_END,
+        0,
+        NUM_RECORDS,
+        0,
+        &NO_DIGEST,
+        CR,
+        0,
+    );
+    write_out(end.as_ptr(), len);
 
     exit(0);
 }
 
-// handle_dir: crc32 all the files in a directory
+// handle_dir: hash all the files in a directory
 // calling itself on sub directories.
-// Expects [active_dir] to contain the bytes of the directory name to crc,
+// Expects [active_dir] to contain the bytes of the directory name to hash,
 //  relative to dir passed on cmd line.
-unsafe fn handle_dir(dir: *const c_char) {
+unsafe fn handle_dir(dir: *const c_char, is_mtime: bool) {
     let dir_fd = match open_dir(dir) {
         None => {
             return;
@@ -215,7 +258,12 @@ unsafe fn handle_dir(dir: *const c_char) {
     let mut buf: [MaybeUninit<u8>; BUF_SIZE as usize] = MaybeUninit::uninit_array();
     let mut bytes_read = get_dir_entries(dir_fd, &mut buf);
     while bytes_read != 0 {
-        process_chunk(dir, &MaybeUninit::array_assume_init(buf), bytes_read);
+        process_chunk(
+            dir,
+            &MaybeUninit::array_assume_init(buf),
+            bytes_read,
+            is_mtime,
+        );
         bytes_read = get_dir_entries(dir_fd, &mut buf);
     }
     close(dir_fd);
@@ -224,7 +272,7 @@ unsafe fn handle_dir(dir: *const c_char) {
 // sub function of handle_dir
 // pass size to avoid using slices which can panic and
 //  pull in enormous amounts of format machinery.
-unsafe fn process_chunk(dir: *const c_char, buf: &[u8], bytes_read: i32) {
+unsafe fn process_chunk(dir: *const c_char, buf: &[u8], bytes_read: i32, is_mtime: bool) {
     let mut bytes_processed = 0;
     let dir_len = strlen_local(dir);
     while bytes_processed < bytes_read {
@@ -234,12 +282,12 @@ unsafe fn process_chunk(dir: *const c_char, buf: &[u8], bytes_read: i32) {
 
         // build full path
         #[allow(unused_assignments)] // read via pointer
-        let mut full_path = [0i8; MAX_PATH_LEN];
+        let mut full_path = [0 as c_char; MAX_PATH_LEN];
         full_path = zeroed();
         let mut path_ptr = full_path.as_mut_ptr();
         copy_nonoverlapping(dir, path_ptr, dir_len);
         path_ptr = path_ptr.add(dir_len);
-        *path_ptr = '/' as i8;
+        *path_ptr = '/' as c_char;
         // struct Stat is 19 bytes long plus a variable null-terminated filename
         copy_nonoverlapping(
             (*dirent).d_name.as_ptr(),
@@ -247,10 +295,21 @@ unsafe fn process_chunk(dir: *const c_char, buf: &[u8], bytes_read: i32) {
             (reclen - 19) as usize,
         );
 
-        match (*dirent).d_type {
+        let mut d_type = (*dirent).d_type;
+        if d_type == DT_UNKNOWN {
+            d_type = fstatat_type(full_path.as_ptr());
+        }
+        match d_type {
+            // it's a symlink, never followed
+            DT_LNK => {
+                link_print(full_path.as_ptr());
+            }
             // it's a regular file
+            DT_REG if is_mtime => {
+                mtime_print(full_path.as_ptr());
+            }
             DT_REG => {
-                crc_print(full_path.as_ptr());
+                hash_print(full_path.as_ptr());
             }
             // it's a directory
             DT_DIR => {
@@ -258,32 +317,30 @@ unsafe fn process_chunk(dir: *const c_char, buf: &[u8], bytes_read: i32) {
                 let d_name = (*dirent).d_name.as_ptr();
                 if !is_ignore_dir(d_name) {
                     // it's a dir we want to handle, recurse
-                    handle_dir(full_path.as_ptr());
+                    handle_dir(full_path.as_ptr(), is_mtime);
                 }
             }
-            _ => {} // it's not a file or directory, ignore it
+            _ => {} // it's not a file, directory or link, ignore it
         }
         bytes_processed += reclen as i32;
     }
 }
 
 // is_ignore_dir: Should we ignore this directory ('.' and '..')
-pub(crate) unsafe fn is_ignore_dir(dir: *const i8) -> bool {
+pub(crate) unsafe fn is_ignore_dir(dir: *const c_char) -> bool {
     let dir = dir as *const u16;
     let is_dot = *dir == 0x002E; // '.\0'
     let is_dot_dot = *dir == 0x2E2E; // '..'
     is_dot || is_dot_dot
 }
 
-// crc32's the file and outputs: "filename: crc32\n"
-unsafe fn crc_print(filename: *const c_char) {
-    print(filename.add(2)); // skip the "./" path prefix
-
-    // print a character to separate filename and CRC
-    // we use a colon for human readiness. a null byte would be more correct.
-    print(COLON);
+// is this one of the hash::* algorithms
+unsafe fn is_hash(c: u8) -> bool {
+    c == hash::CRC32C || c == hash::XXH3 || c == hash::BLAKE3
+}
 
-    // next calculate crc32, we print it at end of function
+// hashes the file and outputs its record
+unsafe fn hash_print(filename: *const c_char) {
     let fd = match open_file(filename) {
         Some(fd) => fd,
         None => {
@@ -293,44 +350,160 @@ unsafe fn crc_print(filename: *const c_char) {
     let mut sb: MaybeUninit<Stat> = MaybeUninit::uninit();
     fstat(fd, &mut sb);
     let sb = sb.assume_init();
-    let mut crc = 0; // if the file is empty the crc will be 0
-    if sb.st_size != 0 {
-        crc = calc_crc(fd, sb.st_size);
-    }
-
-    let mut crc_str: [c_char; 8] = [0; 8];
-    itoa(crc, crc_str.as_mut_ptr());
-    print(crc_str.as_ptr());
-    print(CR);
+    let digest = calc_hash(fd, sb.st_size);
+
+    print_record(
+        KIND_FILE,
+        sb.st_mode & 0o7777,
+        sb.st_size,
+        sb.st_mtime,
+        &digest,
+        filename,
+    );
 
     // close file so we don't run out of descriptors in large folders
     close(fd);
 }
 
-unsafe fn calc_crc(fd: i32, size: u64) -> u32 {
+// outputs the file's record with its mtime, without opening the file
+unsafe fn mtime_print(filename: *const c_char) {
+    let mut sb: MaybeUninit<Stat> = MaybeUninit::uninit();
+    stat(filename, &mut sb);
+    let sb = sb.assume_init();
+    print_record(
+        KIND_FILE,
+        sb.st_mode & 0o7777,
+        sb.st_size,
+        sb.st_mtime,
+        &NO_DIGEST,
+        filename,
+    );
+}
+
+unsafe fn calc_hash(fd: i32, size: u64) -> hash::Digest {
+    if size == 0 {
+        // can't mmap nothing
+        return hash::digest(HASH, [0u8; 0].as_ptr(), 0);
+    }
     let mmap_ptr = mmap(fd, size);
-    let mut checksum = CRC32;
-    let mut pos = 0;
-    while pos < size {
-        // read fd 8 bytes at a time, treating those 8 bytes as a u64
-        checksum = _mm_crc32_u64(checksum, *(mmap_ptr.add(pos as usize) as *const u64));
-        pos += 8;
+    let digest = hash::digest(HASH, mmap_ptr, size as usize);
+    munmap(mmap_ptr, size);
+    digest
+}
+
+// outputs the link's record, with the hash of where it points
+unsafe fn link_print(filename: *const c_char) {
+    let mut target = [0u8; MAX_LINK_LEN];
+    let len = readlink(filename, target.as_mut_ptr());
+    let digest = hash::digest(HASH, target.as_ptr(), len as usize);
+    print_record(KIND_LINK, 0, len as u64, 0, &digest, filename);
+}
+
+// Write one record to stdout. filename starts with "./", which is left off.
+unsafe fn print_record(
+    kind: u8,
+    mode: u32,
+    size: u64,
+    mtime: u64,
+    digest: &hash::Digest,
+    filename: *const c_char,
+) {
+    let mut buf = [0u8; RECORD_LEN + hash::MAX_DIGEST_LEN + MAX_PATH_LEN];
+    let name = filename.add(2);
+    let len = encode_record(
+        buf.as_mut_ptr(),
+        kind,
+        mode,
+        size,
+        mtime,
+        digest,
+        name,
+        strlen_local(name),
+    );
+    write_out(buf.as_ptr(), len);
+    NUM_RECORDS += 1;
+}
+
+// Returns the number of bytes written, HEADER_LEN
+pub(crate) unsafe fn encode_header(buf: *mut u8, is_mtime: bool) -> usize {
+    (buf as *mut u32).write_unaligned(MAGIC.to_le());
+    (buf.add(4) as *mut u16).write_unaligned(PROTOCOL_VERSION.to_le());
+    *buf.add(6) = if is_mtime { FLAG_MTIME } else { 0 };
+    *buf.add(7) = HASH;
+    HEADER_LEN
+}
+
+// buf needs room for RECORD_LEN + digest.len + name_len bytes.
+// Returns the number of bytes written.
+#[allow(clippy::too_many_arguments)]
+pub(crate) unsafe fn encode_record(
+    buf: *mut u8,
+    kind: u8,
+    mode: u32,
+    size: u64,
+    mtime: u64,
+    digest: &hash::Digest,
+    name: *const c_char,
+    name_len: usize,
+) -> usize {
+    let digest_len = digest.len as usize;
+    *buf = kind;
+    *buf.add(1) = digest.len;
+    (buf.add(2) as *mut u16).write_unaligned((name_len as u16).to_le());
+    (buf.add(4) as *mut u32).write_unaligned(mode.to_le());
+    (buf.add(8) as *mut u64).write_unaligned(size.to_le());
+    (buf.add(16) as *mut u64).write_unaligned(mtime.to_le());
+    copy_nonoverlapping(digest.bytes.as_ptr(), buf.add(RECORD_LEN), digest_len);
+    copy_nonoverlapping(
+        name as *const u8,
+        buf.add(RECORD_LEN + digest_len),
+        name_len,
+    );
+    RECORD_LEN + digest_len + name_len
+}
+
+// The DT_* type of a path, for filesystems where getdents64 doesn't say
+unsafe fn fstatat_type(path: *const c_char) -> u8 {
+    let mut sb: MaybeUninit<Stat> = MaybeUninit::uninit();
+    let ret = syscall4(
+        arch::SYS_NEWFSTATAT,
+        AT_FDCWD as usize,
+        path as usize,
+        sb.as_mut_ptr() as usize,
+        AT_SYMLINK_NOFOLLOW,
+    );
+    error(ret as i32, EM_STAT);
+    match sb.assume_init().st_mode & S_IFMT {
+        S_IFREG => DT_REG,
+        S_IFDIR => DT_DIR,
+        S_IFLNK => DT_LNK,
+        _ => DT_UNKNOWN,
     }
+}
 
-    munmap(mmap_ptr, size);
-    (checksum & CRC32) as u32
+// Where a symlink points. Writes at most MAX_LINK_LEN bytes, not \0 terminated.
+// Returns the length.
+unsafe fn readlink(path: *const c_char, buf: *mut u8) -> i32 {
+    let ret = syscall4(
+        arch::SYS_READLINKAT,
+        AT_FDCWD as usize,
+        path as usize,
+        buf as usize,
+        MAX_LINK_LEN,
+    ) as i32;
+    error(ret, EM_READLINK);
+    ret
 }
 
 unsafe fn mmap(fd: i32, size: u64) -> *const u8 {
-    let mut ret: i64;
-    asm!("syscall",
-        inout("rax") SYS_MMAP => ret,
-        in("rdi") 0, // let kernel choose starting address, page aligned
-        in("rsi") size,
-        in("rdx") PROT_READ,
-        in("r10") MAP_SHARED,
-        in("r8") fd,
-        in("r9") 0, // offset in the file to start mapping
+    let ret = syscall6(
+        arch::SYS_MMAP,
+        0, // let kernel choose starting address, page aligned
+        size as usize,
+        PROT_READ as usize,
+        MAP_SHARED as usize,
+        fd as usize,
+        0, // offset in the file to start mapping
     );
     if ret <= 0 {
         error(ret as i32, EM_MMAP);
@@ -339,47 +512,41 @@ unsafe fn mmap(fd: i32, size: u64) -> *const u8 {
 }
 
 unsafe fn munmap(ptr: *const u8, size: u64) {
-    let ret: i32;
-    asm!("syscall",
-         inout("eax") SYS_MUNMAP => ret,
-         in("rdi") ptr,
-         in("rsi") size,
-    );
-    error(ret, EM_MUNMAP);
+    let ret = syscall2(arch::SYS_MUNMAP, ptr as usize, size as usize);
+    error(ret as i32, EM_MUNMAP);
 }
 
 unsafe fn fstat(fd: i32, sb: &mut MaybeUninit<Stat>) {
-    let mut ret: i32;
-    asm!("syscall",
-         inout("eax") SYS_FSTAT => ret,
-         in("edi") fd,
-         in("rsi") sb as *mut MaybeUninit<Stat>,
+    let ret = syscall2(arch::SYS_FSTAT, fd as usize, sb.as_mut_ptr() as usize);
+    error(ret as i32, EM_FSTAT);
+}
+
+unsafe fn stat(path: *const c_char, sb: &mut MaybeUninit<Stat>) {
+    let ret = syscall4(
+        arch::SYS_NEWFSTATAT,
+        AT_FDCWD as usize,
+        path as usize,
+        sb.as_mut_ptr() as usize,
+        0,
     );
-    error(ret, EM_FSTAT);
+    error(ret as i32, EM_STAT);
 }
 
 unsafe fn close(fd: i32) {
-    let mut ret: i32;
-    asm!("syscall",
-         inout("eax") SYS_CLOSE => ret,
-         in("edi") fd,
-         options(nostack, nomem),
-    );
-    error(ret, EM_CLOSE);
+    let ret = syscall1(arch::SYS_CLOSE, fd as usize);
+    error(ret as i32, EM_CLOSE);
 }
 
 // List directory entries.
 // Returns the number of bytes read or 0 if no more directory entries
 unsafe fn get_dir_entries(dir_fd: i32, buf: &mut [MaybeUninit<u8>]) -> i32 {
-    let mut ret: i32;
-    asm!("syscall",
-        inout("eax") SYS_GETDENTS64 => ret,
-        in("edi") dir_fd,
+    let ret = syscall3(
+        arch::SYS_GETDENTS64,
+        dir_fd as usize,
         // address of space for linux_dirent64 structures
-        in("rsi") buf.as_mut_ptr(),
-        in("edx") BUF_SIZE,
-        options(nostack, nomem),
-    );
+        buf.as_mut_ptr() as usize,
+        BUF_SIZE as usize,
+    ) as i32;
     error(ret, EM_GETDENTS64);
     ret
 }
@@ -389,17 +556,16 @@ unsafe fn open_file(filename: *const c_char) -> Option<i32> {
 }
 
 unsafe fn open_dir(active_dir: *const c_char) -> Option<i32> {
-    open(active_dir, O_RDONLY | O_DIRECTORY, EM_OPEN_DIR)
+    open(active_dir, O_RDONLY | arch::O_DIRECTORY, EM_OPEN_DIR)
 }
 
 unsafe fn open(path: *const c_char, flags: i32, err_msg: *const c_char) -> Option<i32> {
-    let mut result: i32;
-    asm!("syscall",
-        inout("eax") SYS_OPEN => result,
-        in("rdi") path,
-        in("esi") flags,
-        options(nostack, nomem)
-    );
+    let result = syscall3(
+        arch::SYS_OPENAT,
+        AT_FDCWD as usize,
+        path as usize,
+        flags as usize,
+    ) as i32;
     if result == EACCES {
         // EACCES Permission denied, we won't be able to rcp over it
         return None;
@@ -413,16 +579,11 @@ unsafe fn open(path: *const c_char, flags: i32, err_msg: *const c_char) -> Optio
 }
 
 unsafe fn chdir(dir: *const c_char) {
-    let mut err_code;
-    asm!("syscall",
-     in("rdi") dir,
-     inout("eax") SYS_CHDIR => err_code,
-     options(nostack, nomem)
-    );
-    error(err_code, EM_CHDIR);
+    let err_code = syscall1(arch::SYS_CHDIR, dir as usize);
+    error(err_code as i32, EM_CHDIR);
 }
 
-pub(crate) unsafe fn itoa(num: u32, dest: *mut c_char) {
+pub(crate) unsafe fn itoa(num: u64, dest: *mut c_char) {
     if num == 0 {
         *dest = '0' as c_char;
         *dest.add(1) = 0;
@@ -430,11 +591,11 @@ pub(crate) unsafe fn itoa(num: u32, dest: *mut c_char) {
     }
     let mut dest_idx = 0;
     let mut remain = num;
-    let mut next_c: i8;
+    let mut next_c: c_char;
     // ASCIIify
     while remain > 0 {
-        (next_c, remain) = ((remain % 10) as i8, remain / 10);
-        *(dest.add(dest_idx)) = next_c + '0' as i8; // convert to ASCII
+        (next_c, remain) = ((remain % 10) as c_char, remain / 10);
+        *(dest.add(dest_idx)) = next_c + '0' as c_char; // convert to ASCII
         dest_idx += 1;
     }
     // Reverse
@@ -452,65 +613,22 @@ pub(crate) unsafe fn itoa(num: u32, dest: *mut c_char) {
     *(dest.add(dest_idx)) = 0;
 }
 
-// Print missing AVX2 message and exit, that means very old CPU on the server
-// don't use strlen for printing because that needs sse4.2
-unsafe fn print_avx2_missing() {
-    asm!("
-	mov edx, 10
-	syscall", // 10 is length of AVX2
-    in("rsi") EM_AVX2,
-    in("rdi") STDERR,
-    in("eax") SYS_WRITE,
-    options(nostack, nomem)
-    );
-}
-
-// Modern instructions we need: pcmpistri (SSE4_2), vmovdqa (AVX), vpxor (AVX2)
-// cpuid: check for AVX2 (which implies AVX and SSE4.2)
-// Note we can't use `cfg!` because that's compile time.
-unsafe fn has_avx2() -> bool {
-    let mut answer: u8;
-    asm!("
-	mov eax, 7
-	mov ecx, 0
-	cpuid
-	shr ebx, 5
-	and ebx, 1
-    mov eax, ebx
-    ", // output eax instead of ebx because LLVM uses ebx internally apparently
-    out("al") answer,
-    options(nostack, nomem)
-    );
-    answer != 0
+unsafe fn exit(exit_code: i32) -> ! {
+    arch::exit(exit_code)
 }
 
-unsafe fn exit(exit_code: i32) -> ! {
-    asm!("syscall",
-        in("eax") SYS_EXIT,
-        in("edi") exit_code,
-        options(nostack, nomem, noreturn)
-    )
-}
-
-// print to stdout
-unsafe fn print(s: *const c_char) {
-    asm!("syscall",
-        in("eax") SYS_WRITE,
-        in("edi") STDOUT,
-        in("rsi") s,
-        in("edx") strlen_local(s) as u32,
-        options(nostack, nomem),
-    );
+// write len bytes to stdout
+unsafe fn write_out(buf: *const u8, len: usize) {
+    syscall3(arch::SYS_WRITE, STDOUT as usize, buf as usize, len);
 }
 
 // print to STDERR
 unsafe fn print_err(s: *const c_char) {
-    asm!("syscall",
-        in("eax") SYS_WRITE,
-        in("edi") STDERR,
-        in("rsi") s,
-        in("edx") strlen_local(s) as u32,
-        options(nostack, nomem),
+    syscall3(
+        arch::SYS_WRITE,
+        STDERR as usize,
+        s as usize,
+        strlen_local(s),
     );
 }
 
@@ -525,9 +643,9 @@ unsafe fn error(err_code: i32, msg: *const c_char) {
         print_err(ERRS.get_unchecked(err_code as usize).as_ptr() as *const c_char);
     } else {
         // numeric err
-        let mut code_str: [c_char; 8] = [0; 8];
-        itoa(err_code, code_str.as_mut_ptr());
-        print(code_str.as_ptr());
+        let mut code_str: [c_char; NUM_STR_LEN] = [0; NUM_STR_LEN];
+        itoa(err_code as u64, code_str.as_mut_ptr());
+        print_err(code_str.as_ptr());
     }
     print_err(CR);
     exit(err_code as i32);
@@ -538,47 +656,32 @@ unsafe fn error(err_code: i32, msg: *const c_char) {
 // They are in crate compiler-builtins which stdlib imports.
 //
 
-// s must be 16 byte aligned
-unsafe fn strlen_local(s: *const c_char) -> usize {
-    let mut haystack = s as u64;
-    let mut idx = 16; // pcmpistri instruction uses 16 for 'not found'
-    let mut offset: i32 = -16;
-    while idx == 16 {
-        offset += 16;
-        idx = _mm_cmpistri(
-            u64x2::from_array([0xFF01, 0]).into(),
-            _mm_loadu_si128(haystack as *const _),
-            _SIDD_CMP_RANGES | _SIDD_NEGATIVE_POLARITY,
-        );
-        haystack += 16;
+#[cfg(target_arch = "x86_64")]
+pub(crate) unsafe fn strlen_local(s: *const c_char) -> usize {
+    if hash::has_sse42() {
+        arch::strlen_sse42(s)
+    } else {
+        strlen_bytes(s)
     }
-    (offset + idx) as usize
 }
 
-#[no_mangle]
-unsafe extern "C" fn memcpy(dest: *mut u8, src: *const u8, n: usize) -> *mut u8 {
-    asm!(
-        "rep movsb",
-        inout("rcx") n => _,
-        inout("rdi") dest => _,
-        inout("rsi") src => _,
-        options(nostack, preserves_flags)
-    );
-    dest
+#[cfg(not(target_arch = "x86_64"))]
+pub(crate) unsafe fn strlen_local(s: *const c_char) -> usize {
+    strlen_bytes(s)
 }
 
-#[no_mangle]
-unsafe extern "C" fn memset(dest: *mut u8, c: i32, count: usize) -> *mut u8 {
-    asm!(
-        "rep stosb",
-        inout("rcx") count => _,
-        inout("rdi") dest => _,
-        inout("al") c as u8 => _,
-        options(nostack, preserves_flags)
-    );
-    dest
+// For CPUs without SSE4.2, and other architectures.
+// Volatile or LLVM spots that this is strlen, and calls it. There isn't one.
+pub(crate) unsafe fn strlen_bytes(s: *const c_char) -> usize {
+    let mut len = 0;
+    while s.add(len).read_volatile() != 0 {
+        len += 1;
+    }
+    len
 }
 
+// memcpy and memset are in arch/
+
 //
 // machinery
 //
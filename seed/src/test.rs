@@ -1,6 +1,10 @@
 use core::{ffi::c_char, mem::zeroed};
 
-use crate::{is_ignore_dir, itoa};
+use crate::arch::strlen_sse42;
+use crate::{
+    encode_header, encode_record, hash, is_ignore_dir, itoa, strlen_bytes, strlen_local,
+    HEADER_LEN, KIND_FILE, PROTOCOL_VERSION, RECORD_LEN,
+};
 
 #[test]
 fn test_itoa() {
@@ -15,13 +19,103 @@ fn test_itoa() {
     buf = unsafe { zeroed() };
     unsafe { itoa(123, buf.as_mut_ptr() as *mut c_char) };
     assert_eq!("123\0".as_bytes(), &buf[0..4]);
+
+    let mut buf = [0u8; 24];
+    unsafe { itoa(u64::MAX, buf.as_mut_ptr() as *mut c_char) };
+    assert_eq!("18446744073709551615\0".as_bytes(), &buf[0..21]);
 }
 
 #[test]
 fn test_is_ignore_dir() {
     unsafe {
-        assert!(is_ignore_dir(".\0".as_ptr() as *const i8));
-        assert!(is_ignore_dir("..\0".as_ptr() as *const i8));
-        assert!(!is_ignore_dir("bin\0".as_ptr() as *const i8));
+        assert!(is_ignore_dir(".\0".as_ptr() as *const c_char));
+        assert!(is_ignore_dir("..\0".as_ptr() as *const c_char));
+        assert!(!is_ignore_dir("bin\0".as_ptr() as *const c_char));
+    }
+}
+
+#[test]
+fn test_encode() {
+    let mut buf = [0xFFu8; RECORD_LEN + 16];
+    assert_eq!(unsafe { encode_header(buf.as_mut_ptr(), true) }, HEADER_LEN);
+    assert_eq!(&buf[..4], b"DMTR");
+    assert_eq!(&buf[4..6], &PROTOCOL_VERSION.to_le_bytes());
+    assert_eq!(&buf[6..8], &[1, hash::CRC32C]);
+
+    let content = b"x\n";
+    let digest = unsafe { hash::digest(hash::XXH3, content.as_ptr(), content.len()) };
+    let name = "a/x\0";
+    let len = unsafe {
+        encode_record(
+            buf.as_mut_ptr(),
+            KIND_FILE,
+            0o644,
+            2,
+            1760000000,
+            &digest,
+            name.as_ptr() as *const c_char,
+            3,
+        )
+    };
+    assert_eq!(len, RECORD_LEN + 8 + 3);
+    assert_eq!(&buf[..4], &[b'F', 8, 3, 0]);
+    assert_eq!(&buf[4..8], &0o644u32.to_le_bytes());
+    assert_eq!(&buf[8..16], &2u64.to_le_bytes());
+    assert_eq!(&buf[16..24], &1760000000u64.to_le_bytes());
+    assert_eq!(&buf[24..32], &digest.bytes[..8]);
+    assert_eq!(&buf[32..35], b"a/x");
+}
+
+// Sizes that aren't a multiple of 8 used to hash bytes past the end
+#[test]
+fn test_hash_tail() {
+    let data = b"123456789";
+    unsafe {
+        let crc = hash::digest(hash::CRC32C, data.as_ptr(), data.len());
+        assert_eq!(&crc.bytes[..4], &0xE3069283u32.to_le_bytes());
+        let empty = hash::digest(hash::CRC32C, data.as_ptr(), 0);
+        assert_eq!((empty.len, &empty.bytes[..4]), (4, &[0u8; 4][..]));
+        // a different byte after the end doesn't change it
+        let short = hash::digest(hash::XXH3, b"12345678X".as_ptr(), 7);
+        assert_eq!(short, hash::digest(hash::XXH3, data.as_ptr(), 7));
+    }
+}
+
+#[test]
+fn test_strlen() {
+    // 16 byte aligned, so the SSE4.2 version never reads off the end
+    #[repr(align(16))]
+    struct Buf([u8; 48]);
+    let mut buf = Buf([b'a'; 48]);
+    for len in [0, 1, 15, 16, 17, 40] {
+        buf.0[len] = 0;
+        let s = buf.0.as_ptr() as *const c_char;
+        unsafe {
+            assert_eq!(strlen_bytes(s), len);
+            assert_eq!(strlen_local(s), len);
+            if hash::has_sse42() {
+                assert_eq!(strlen_sse42(s), len);
+            }
+        }
+        buf.0[len] = b'a';
+    }
+}
+
+// The table version, for CPUs without SSE4.2, must match the instruction
+#[test]
+fn test_crc32c_software() {
+    let data: [u8; 100] = core::array::from_fn(|i| (i * 7) as u8);
+    unsafe {
+        assert_eq!(hash::crc32c_sw(0, b"123456789".as_ptr(), 9), 0xE3069283);
+        assert_eq!(hash::crc32c_sw(0, data.as_ptr(), 0), 0);
+        if hash::has_sse42() {
+            for len in 0..data.len() {
+                assert_eq!(
+                    hash::crc32c_sw(0, data.as_ptr(), len),
+                    hash::crc32c_hw(0, data.as_ptr(), len),
+                    "{len}"
+                );
+            }
+        }
     }
 }